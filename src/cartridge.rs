@@ -0,0 +1,336 @@
+use crate::error::EmuError;
+use crate::savestate::{StateError, StateReader, StateWriter};
+
+// The ROM and RAM on the cartridge and the memory controller (MBC) that
+// switches their banks in. $0000-$3FFF is ROM bank 0, $4000-$7FFF the
+// switchable ROM bank and $A000-$BFFF the RAM bank. Writes to the ROM set
+// the controller registers.
+// We have no controller, MBC1, MBC3 and MBC5. The MBC3 clock registers can
+// be read and written but the clock stands still.
+
+// $0147 - cartridge type
+const CARTRIDGE_TYPE_ADDR: usize = 0x0147;
+// $0149 - RAM size
+const RAM_SIZE_ADDR: usize = 0x0149;
+const ROM_BANK_SIZE: usize = 0x4000;
+const RAM_BANK_SIZE: usize = 0x2000;
+// MBC3 maps the clock registers instead of RAM for these RAM banks
+const RTC_FIRST_REGISTER: usize = 0x08;
+const RTC_REGISTERS: usize = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Mbc {
+    None,
+    Mbc1,
+    Mbc3,
+    Mbc5,
+}
+
+pub struct Cartridge {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    mbc: Mbc,
+    ram_enabled: bool,
+    // what was written to the registers, MBC1 takes bank 0 as 1 right away
+    rom_bank: usize,
+    ram_bank: usize,
+    // MBC1: the RAM bank register also picks the RAM bank and moves ROM
+    // bank 0, otherwise it only holds the upper bits of the ROM bank
+    advanced_banking: bool,
+    // MBC3: seconds, minutes, hours, day low, day high
+    rtc: [u8; RTC_REGISTERS],
+}
+
+impl Cartridge {
+    // An empty slot, 32KB of zeros and 8KB of RAM
+    pub fn new() -> Cartridge {
+        Cartridge::with_mbc(vec![0; ROM_BANK_SIZE * 2], Mbc::None, RAM_BANK_SIZE)
+    }
+
+    pub fn from_rom(rom_file: &[u8]) -> Result<Cartridge, EmuError> {
+        let cartridge_type = if rom_file.len() > CARTRIDGE_TYPE_ADDR {
+            rom_file[CARTRIDGE_TYPE_ADDR]
+        } else {
+            0x00
+        };
+        let mbc = match cartridge_type {
+            // ROM only, ROM+RAM and ROM+RAM+BATTERY
+            0x00 | 0x08 | 0x09 => Mbc::None,
+            0x01..=0x03 => Mbc::Mbc1,
+            0x0F..=0x13 => Mbc::Mbc3,
+            0x19..=0x1E => Mbc::Mbc5,
+            _ => return Err(EmuError::UnsupportedCartridge { cartridge_type }),
+        };
+        let ram_size = match rom_file.get(RAM_SIZE_ADDR) {
+            Some(0x01) => 0x800,
+            Some(0x02) => 0x2000,
+            Some(0x03) => 0x8000,
+            Some(0x04) => 0x20000,
+            Some(0x05) => 0x10000,
+            _ => 0,
+        };
+        // without a controller nothing can turn it off, and plenty of
+        // homebrew uses it without saying so in the header
+        let ram_size = if mbc == Mbc::None {
+            ram_size.max(RAM_BANK_SIZE)
+        } else {
+            ram_size
+        };
+        let mut rom = rom_file.to_vec();
+        let rom_banks = rom.len().div_ceil(ROM_BANK_SIZE).max(2);
+        rom.resize(rom_banks * ROM_BANK_SIZE, 0);
+        Ok(Cartridge::with_mbc(rom, mbc, ram_size))
+    }
+
+    fn with_mbc(rom: Vec<u8>, mbc: Mbc, ram_size: usize) -> Cartridge {
+        Cartridge {
+            rom,
+            ram: vec![0; ram_size],
+            mbc,
+            ram_enabled: mbc == Mbc::None,
+            rom_bank: 1,
+            ram_bank: 0,
+            advanced_banking: false,
+            rtc: [0; RTC_REGISTERS],
+        }
+    }
+
+    fn rom_banks(&self) -> usize {
+        self.rom.len() / ROM_BANK_SIZE
+    }
+
+    pub fn read_rom(&self, address: u16) -> u8 {
+        let bank = match (address, self.mbc) {
+            (0x0000..=0x3FFF, Mbc::Mbc1) if self.advanced_banking => self.ram_bank << 5,
+            (0x0000..=0x3FFF, _) => 0,
+            (_, Mbc::None) => 1,
+            (_, Mbc::Mbc1) => (self.ram_bank << 5) | self.rom_bank,
+            _ => self.rom_bank,
+        };
+        // games with fewer banks don't wire the upper bits
+        let bank = bank % self.rom_banks();
+        self.rom[bank * ROM_BANK_SIZE + (address as usize & 0x3FFF)]
+    }
+
+    pub fn write_rom(&mut self, address: u16, value: u8) {
+        let value = value as usize;
+        match (address, self.mbc) {
+            (_, Mbc::None) => {}
+            (0x0000..=0x1FFF, _) => self.ram_enabled = (value & 0x0F) == 0x0A,
+            (0x2000..=0x3FFF, Mbc::Mbc1) => self.rom_bank = (value & 0b0001_1111).max(1),
+            (0x2000..=0x3FFF, Mbc::Mbc3) => self.rom_bank = (value & 0b0111_1111).max(1),
+            (0x2000..=0x2FFF, Mbc::Mbc5) => self.rom_bank = (self.rom_bank & 0x100) | value,
+            (0x3000..=0x3FFF, Mbc::Mbc5) => {
+                self.rom_bank = (self.rom_bank & 0xFF) | ((value & 0b1) << 8)
+            }
+            (0x4000..=0x5FFF, Mbc::Mbc1) => self.ram_bank = value & 0b11,
+            (0x4000..=0x5FFF, _) => self.ram_bank = value & 0b1111,
+            (0x6000..=0x7FFF, Mbc::Mbc1) => self.advanced_banking = (value & 0b1) != 0,
+            // MBC3 latches the clock here, it doesn't run so there is
+            // nothing to latch
+            _ => {}
+        }
+    }
+
+    fn ram_address(&self, address: u16) -> Option<usize> {
+        if !self.ram_enabled || self.ram.is_empty() {
+            return None;
+        }
+        let bank = match self.mbc {
+            Mbc::None => 0,
+            Mbc::Mbc1 if !self.advanced_banking => 0,
+            Mbc::Mbc3 if self.ram_bank >= RTC_FIRST_REGISTER => return None,
+            _ => self.ram_bank,
+        };
+        // 2KB chips and smaller ones than the bank asked for repeat
+        Some((bank * RAM_BANK_SIZE + (address as usize - 0xA000)) % self.ram.len())
+    }
+
+    fn rtc_register(&self) -> Option<usize> {
+        if self.mbc != Mbc::Mbc3 || !self.ram_enabled || self.ram_bank < RTC_FIRST_REGISTER {
+            return None;
+        }
+        Some(self.ram_bank - RTC_FIRST_REGISTER).filter(|&i| i < RTC_REGISTERS)
+    }
+
+    // Disabled or missing RAM reads as 0xFF
+    pub fn read_ram(&self, address: u16) -> u8 {
+        match (self.rtc_register(), self.ram_address(address)) {
+            (Some(i), _) => self.rtc[i],
+            (None, Some(i)) => self.ram[i],
+            (None, None) => 0xFF,
+        }
+    }
+
+    pub fn write_ram(&mut self, address: u16, value: u8) {
+        match (self.rtc_register(), self.ram_address(address)) {
+            (Some(i), _) => self.rtc[i] = value,
+            (None, Some(i)) => self.ram[i] = value,
+            (None, None) => {}
+        }
+    }
+
+    // Puts a save file back into the RAM
+    pub fn load_ram(&mut self, data: &[u8]) {
+        let size = data.len().min(self.ram.len());
+        self.ram[..size].copy_from_slice(&data[..size]);
+    }
+
+    pub fn get_ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    // The ROM isn't saved, a state only loads into the game it came from
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_vec(&self.ram);
+        state.write_bool(self.ram_enabled);
+        state.write_u16(self.rom_bank as u16);
+        state.write_u8(self.ram_bank as u8);
+        state.write_bool(self.advanced_banking);
+        state.write_bytes(&self.rtc);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        let ram = state.read_vec()?;
+        if ram.len() != self.ram.len() {
+            return Err(StateError::Corrupt);
+        }
+        self.ram = ram;
+        self.ram_enabled = state.read_bool()?;
+        self.rom_bank = state.read_u16()? as usize;
+        self.ram_bank = state.read_u8()? as usize;
+        self.advanced_banking = state.read_bool()?;
+        state.read_bytes(&mut self.rtc)?;
+        Ok(())
+    }
+}
+
+impl Default for Cartridge {
+    fn default() -> Cartridge {
+        Cartridge::new()
+    }
+}
+
+// $0147 - cartridge type, the ones we run that keep their RAM with a battery
+pub fn has_battery(rom_file: &[u8]) -> bool {
+    rom_file.len() > CARTRIDGE_TYPE_ADDR
+        && matches!(
+            rom_file[CARTRIDGE_TYPE_ADDR],
+            0x03 | 0x09 | 0x0F | 0x10 | 0x13 | 0x1B | 0x1E
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gameboy::GameBoy;
+    use crate::mmu::Model;
+
+    // Every ROM bank starts with its own number
+    fn rom(cartridge_type: u8, banks: usize, ram_size: u8) -> Vec<u8> {
+        let mut rom = vec![0; banks * ROM_BANK_SIZE];
+        for bank in 0..banks {
+            rom[bank * ROM_BANK_SIZE] = bank as u8;
+            rom[bank * ROM_BANK_SIZE + 1] = (bank >> 8) as u8;
+        }
+        rom[CARTRIDGE_TYPE_ADDR] = cartridge_type;
+        rom[RAM_SIZE_ADDR] = ram_size;
+        rom
+    }
+
+    fn rom_bank(cartridge: &Cartridge) -> usize {
+        cartridge.read_rom(0x4000) as usize | (cartridge.read_rom(0x4001) as usize) << 8
+    }
+
+    #[test]
+    fn unsupported_controllers_are_rejected() {
+        // MBC2
+        let rom = rom(0x06, 2, 0);
+        assert_eq!(
+            Cartridge::from_rom(&rom).err(),
+            Some(EmuError::UnsupportedCartridge {
+                cartridge_type: 0x06
+            })
+        );
+    }
+
+    #[test]
+    fn mbc1_switches_rom_and_ram_banks() {
+        let mut cartridge = Cartridge::from_rom(&rom(0x03, 128, 0x03)).unwrap();
+        assert_eq!(rom_bank(&cartridge), 1);
+        // bank 0 can't be picked, the upper bits come from $4000
+        cartridge.write_rom(0x2000, 0x00);
+        assert_eq!(rom_bank(&cartridge), 1);
+        cartridge.write_rom(0x2000, 0x05);
+        cartridge.write_rom(0x4000, 0x02);
+        assert_eq!(rom_bank(&cartridge), 0x45);
+        assert_eq!(cartridge.read_rom(0x0000), 0);
+
+        // RAM is off until enabled, then the mode picks the bank
+        cartridge.write_ram(0xA000, 0x12);
+        assert_eq!(cartridge.read_ram(0xA000), 0xFF);
+        cartridge.write_rom(0x0000, 0x0A);
+        cartridge.write_ram(0xA000, 0x12);
+        cartridge.write_rom(0x6000, 0x01);
+        assert_eq!(cartridge.read_rom(0x0000), 0x40);
+        assert_eq!(cartridge.read_ram(0xA000), 0x00);
+        cartridge.write_ram(0xA000, 0x34);
+        assert_eq!(cartridge.get_ram()[0], 0x12);
+        assert_eq!(cartridge.get_ram()[2 * RAM_BANK_SIZE], 0x34);
+    }
+
+    #[test]
+    fn mbc3_switches_banks_and_keeps_the_clock_registers() {
+        let mut cartridge = Cartridge::from_rom(&rom(0x10, 128, 0x03)).unwrap();
+        cartridge.write_rom(0x2000, 0x7F);
+        assert_eq!(rom_bank(&cartridge), 0x7F);
+        cartridge.write_rom(0x0000, 0x0A);
+        cartridge.write_rom(0x4000, 0x03);
+        cartridge.write_ram(0xA001, 0x56);
+        assert_eq!(cartridge.get_ram()[3 * RAM_BANK_SIZE + 1], 0x56);
+
+        cartridge.write_rom(0x4000, 0x09);
+        cartridge.write_ram(0xA000, 42);
+        assert_eq!(cartridge.read_ram(0xA000), 42);
+        cartridge.write_rom(0x4000, 0x03);
+        assert_eq!(cartridge.read_ram(0xA001), 0x56);
+    }
+
+    #[test]
+    fn mbc5_reaches_bank_0_and_the_9th_bit() {
+        let mut cartridge = Cartridge::from_rom(&rom(0x1B, 512, 0x04)).unwrap();
+        cartridge.write_rom(0x2000, 0x00);
+        assert_eq!(rom_bank(&cartridge), 0);
+        cartridge.write_rom(0x2000, 0x23);
+        cartridge.write_rom(0x3000, 0x01);
+        assert_eq!(rom_bank(&cartridge), 0x123);
+        cartridge.write_rom(0x0000, 0x0A);
+        cartridge.write_rom(0x4000, 0x0F);
+        cartridge.write_ram(0xBFFF, 0x78);
+        assert_eq!(cartridge.get_ram()[16 * RAM_BANK_SIZE - 1], 0x78);
+    }
+
+    #[test]
+    fn banks_past_the_end_of_the_rom_wrap_around() {
+        let mut cartridge = Cartridge::from_rom(&rom(0x19, 4, 0)).unwrap();
+        cartridge.write_rom(0x2000, 0x06);
+        assert_eq!(rom_bank(&cartridge), 2);
+        assert_eq!(cartridge.read_ram(0xA000), 0xFF);
+    }
+
+    #[test]
+    fn the_banks_go_in_save_states() {
+        let mut gameboy = GameBoy::new(Model::Dmg, &rom(0x1B, 8, 0x03), None, None).unwrap();
+        gameboy.get_mmu_mut().write_byte(0x0000, 0x0A);
+        gameboy.get_mmu_mut().write_byte(0x2000, 0x05);
+        gameboy.get_mmu_mut().write_byte(0xA000, 0x99);
+        let state = gameboy.save_state();
+
+        gameboy.get_mmu_mut().write_byte(0xA000, 0x00);
+        gameboy.get_mmu_mut().write_byte(0x2000, 0x01);
+        gameboy.load_state(&state).unwrap();
+        assert_eq!(gameboy.get_mmu().read_byte(0x4000), 5);
+        assert_eq!(gameboy.get_mmu().read_byte(0xA000), 0x99);
+        assert_eq!(gameboy.get_save_data()[0], 0x99);
+    }
+}
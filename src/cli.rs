@@ -0,0 +1,429 @@
+use crate::compat::ManualPalette;
+use crate::headless::Stop;
+use crate::mmu::Model;
+use crate::movie;
+use crate::ppu::{ColorCorrection, GRAY_PALETTE, GREEN_PALETTE};
+use crate::rewind;
+
+use std::path::{Path, PathBuf};
+
+pub const USAGE: &str = "\
+usage: gbrustemu <rom, .zip or .gz> [options]
+       gbrustemu test-roms [dir]
+
+options:
+  --entry <name>          the ROM to run out of a zip (default the first .gb or .gbc)
+  --boot-rom <file>       run this boot ROM first
+  --skip-boot             start the game right away, without a boot ROM
+  --model <model>         dmg, mgb, cgb or sgb (default dmg, color games pick cgb)
+  --scale <n>             window scale: 1, 2, 4, 8, 16 or 32 (default 2)
+  --palette <palette>     green, gray or four RRGGBB colors, lightest first:
+                          e0f8d0,88c070,346856,081820
+  --color-correction <c>  off or lcd, lcd makes CGB colors look like on the CGB screen
+                          (default off)
+  --compat-palette <keys> colorise a monochrome game on --model cgb like holding
+                          up, left, down or right, alone or +a or +b, at the boot logo
+  --save-dir <dir>        where the .sav and save state files go (default next to the ROM)
+  --load-state <file>     start from a save state, F1-F4 load slots in the window
+                          and Shift+F1-F4 save them
+  --rewind-interval <n>   frames between rewind snapshots, hold R to rewind (default 2)
+  --rewind-budget <MB>    memory the rewind snapshots can take, 0 turns it off (default 32)
+  --link-listen <addr>    plug a link cable into another emulator, wait for it to
+                          connect on host:port
+  --link-connect <addr>   plug a link cable into the emulator listening on host:port
+  --printer <dir>         plug in a Game Boy Printer, printouts go to dir as PNGs
+  --record <file>         record the buttons of every frame as a movie, from power-on
+                          or from --load-state
+  --play <file>           play a movie back, checking the screen comes out the same
+  --hash-interval <n>     frames between screen hashes in recorded movies, 0 for none
+                          (default 60)
+  --headless              no window, stops after --frames, --cycles or --breakpoint
+  --frames <n>            headless: run n frames (default 60)
+  --cycles <n>            headless: run n clocks
+  --breakpoint <n>        headless: run until LD B,B, n frames at most
+  --input <file>          headless: button script, lines of `<frame> <button> press|release`
+  --screen <file.png>     headless: save the screen when done
+  --memory <file.bin>     headless: save the 64KB address space when done
+  --debugger              no window, step through the game from a command prompt,
+                          type help there for the commands
+  --debug                 print the registers when the emulator stops
+  --trace                 print every instruction as it runs
+  --help                  show this";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    Run(Box<Options>),
+    TestRoms(PathBuf),
+    Help,
+}
+
+// The other end of the link cable
+#[derive(Debug, Clone, PartialEq)]
+pub enum LinkCable {
+    Listen(String),
+    Connect(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Options {
+    pub rom_path: PathBuf,
+    // which file of a zip, None takes the first ROM in there
+    pub rom_entry: Option<String>,
+    pub boot_rom_path: Option<PathBuf>,
+    pub skip_boot_rom: bool,
+    pub model: Model,
+    pub scale: usize,
+    // None keeps the one of the model
+    pub palette: Option<[u32; 4]>,
+    pub color_correction: ColorCorrection,
+    // None lets the header pick
+    pub compat_palette: Option<ManualPalette>,
+    pub save_dir: Option<PathBuf>,
+    pub state_path: Option<PathBuf>,
+    pub rewind_interval: usize,
+    // in bytes
+    pub rewind_budget: usize,
+    pub link: Option<LinkCable>,
+    pub printer_dir: Option<PathBuf>,
+    pub record_path: Option<PathBuf>,
+    pub play_path: Option<PathBuf>,
+    pub hash_interval: usize,
+    pub headless: bool,
+    pub stop: Stop,
+    pub input_path: Option<PathBuf>,
+    pub screen_path: Option<PathBuf>,
+    pub memory_path: Option<PathBuf>,
+    pub debugger: bool,
+    pub debug: bool,
+    pub trace: bool,
+}
+
+impl Options {
+    pub fn new(rom_path: PathBuf) -> Options {
+        Options {
+            rom_path,
+            rom_entry: None,
+            boot_rom_path: None,
+            skip_boot_rom: false,
+            model: Model::Dmg,
+            scale: 2,
+            palette: None,
+            color_correction: ColorCorrection::Off,
+            compat_palette: None,
+            save_dir: None,
+            state_path: None,
+            rewind_interval: rewind::DEFAULT_INTERVAL,
+            rewind_budget: rewind::DEFAULT_BUDGET,
+            link: None,
+            printer_dir: None,
+            record_path: None,
+            play_path: None,
+            hash_interval: movie::DEFAULT_HASH_INTERVAL,
+            headless: false,
+            stop: Stop::Frames(60),
+            input_path: None,
+            screen_path: None,
+            memory_path: None,
+            debugger: false,
+            debug: false,
+            trace: false,
+        }
+    }
+
+    // <save dir or the ROM's folder>/<ROM name>.sav, `rom_name` is the ROM
+    // inside the archive when it came in one
+    pub fn get_save_path(&self, rom_name: &str) -> PathBuf {
+        self.get_save_dir_path(rom_name, "sav")
+    }
+
+    // Save state slots go next to the save: <ROM name>.ss1, .ss2...
+    pub fn get_state_path(&self, rom_name: &str, slot: usize) -> PathBuf {
+        self.get_save_dir_path(rom_name, &format!("ss{}", slot))
+    }
+
+    fn get_save_dir_path(&self, rom_name: &str, extension: &str) -> PathBuf {
+        let dir = match &self.save_dir {
+            Some(dir) => dir.as_path(),
+            None => self.rom_path.parent().unwrap_or_else(|| Path::new("")),
+        };
+        let name = Path::new(rom_name).file_stem().unwrap_or_default();
+        dir.join(name).with_extension(extension)
+    }
+
+    // The palette asked for, or gray for the Pocket and green for the rest
+    pub fn get_palette(&self) -> [u32; 4] {
+        match self.palette {
+            Some(palette) => palette,
+            None if self.model == Model::Mgb => GRAY_PALETTE,
+            None => GREEN_PALETTE,
+        }
+    }
+}
+
+fn parse_model(name: &str) -> Result<Model, String> {
+    match name.to_lowercase().as_str() {
+        "dmg" => Ok(Model::Dmg),
+        "mgb" => Ok(Model::Mgb),
+        "cgb" => Ok(Model::Cgb),
+        "sgb" => Ok(Model::Sgb),
+        _ => Err(format!(
+            "unknown model {}, expected dmg, mgb, cgb or sgb",
+            name
+        )),
+    }
+}
+
+fn parse_palette(text: &str) -> Result<[u32; 4], String> {
+    match text.to_lowercase().as_str() {
+        "green" => return Ok(GREEN_PALETTE),
+        "gray" | "grey" => return Ok(GRAY_PALETTE),
+        _ => {}
+    }
+    let colors: Vec<&str> = text.split(',').collect();
+    let error = || {
+        format!(
+            "bad palette {}, expected green, gray or four RRGGBB colors",
+            text
+        )
+    };
+    if colors.len() != 4 {
+        return Err(error());
+    }
+    let mut palette = [0; 4];
+    for (shade, color) in colors.iter().enumerate() {
+        let color = color.trim().trim_start_matches('#');
+        if color.len() != 6 {
+            return Err(error());
+        }
+        palette[shade] = 0xFF00_0000 | u32::from_str_radix(color, 16).map_err(|_| error())?;
+    }
+    Ok(palette)
+}
+
+fn parse_color_correction(name: &str) -> Result<ColorCorrection, String> {
+    match name.to_lowercase().as_str() {
+        "off" => Ok(ColorCorrection::Off),
+        "lcd" => Ok(ColorCorrection::CgbLcd),
+        _ => Err(format!(
+            "unknown color correction {}, expected off or lcd",
+            name
+        )),
+    }
+}
+
+fn parse_number(flag: &str, value: &str) -> Result<usize, String> {
+    value
+        .parse()
+        .map_err(|_| format!("{} needs a number, got {}", flag, value))
+}
+
+// `args` without the program name
+pub fn parse_args(args: &[String]) -> Result<Command, String> {
+    match args.first().map(|arg| arg.as_str()) {
+        None | Some("--help") | Some("-h") => return Ok(Command::Help),
+        Some("test-roms") => {
+            let dir = args.get(1).map_or("tests/roms", |dir| dir.as_str());
+            return Ok(Command::TestRoms(PathBuf::from(dir)));
+        }
+        _ => {}
+    }
+
+    let mut rom_path = None;
+    let mut options = Options::new(PathBuf::new());
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let flag = arg.as_str();
+        // the flags that take a value
+        let mut value = || {
+            args.next()
+                .map(|value| value.as_str())
+                .ok_or_else(|| format!("{} needs a value", flag))
+        };
+        match flag {
+            "--help" | "-h" => return Ok(Command::Help),
+            "--entry" => options.rom_entry = Some(value()?.to_string()),
+            "--boot-rom" => options.boot_rom_path = Some(PathBuf::from(value()?)),
+            "--skip-boot" => options.skip_boot_rom = true,
+            "--model" => options.model = parse_model(value()?)?,
+            "--scale" => {
+                options.scale = parse_number(flag, value()?)?;
+                if ![1, 2, 4, 8, 16, 32].contains(&options.scale) {
+                    return Err(format!(
+                        "--scale can be 1, 2, 4, 8, 16 or 32, not {}",
+                        options.scale
+                    ));
+                }
+            }
+            "--palette" => options.palette = Some(parse_palette(value()?)?),
+            "--color-correction" => options.color_correction = parse_color_correction(value()?)?,
+            "--compat-palette" => {
+                let name = value()?;
+                let manual = ManualPalette::from_name(name).ok_or_else(|| {
+                    format!("unknown button combo {}, expected e.g. up or left+b", name)
+                })?;
+                options.compat_palette = Some(manual);
+            }
+            "--save-dir" => options.save_dir = Some(PathBuf::from(value()?)),
+            "--load-state" => options.state_path = Some(PathBuf::from(value()?)),
+            "--rewind-interval" => {
+                options.rewind_interval = parse_number(flag, value()?)?;
+                if options.rewind_interval == 0 {
+                    return Err("--rewind-interval must be at least 1".to_string());
+                }
+            }
+            "--rewind-budget" => {
+                let megabytes = parse_number(flag, value()?)?;
+                options.rewind_budget = megabytes
+                    .checked_mul(1024 * 1024)
+                    .ok_or_else(|| format!("--rewind-budget {}MB is too big", megabytes))?;
+            }
+            "--link-listen" => options.link = Some(LinkCable::Listen(value()?.to_string())),
+            "--link-connect" => options.link = Some(LinkCable::Connect(value()?.to_string())),
+            "--printer" => options.printer_dir = Some(PathBuf::from(value()?)),
+            "--record" => options.record_path = Some(PathBuf::from(value()?)),
+            "--play" => options.play_path = Some(PathBuf::from(value()?)),
+            "--hash-interval" => options.hash_interval = parse_number(flag, value()?)?,
+            "--headless" => options.headless = true,
+            "--frames" => options.stop = Stop::Frames(parse_number(flag, value()?)?),
+            "--cycles" => options.stop = Stop::Cycles(parse_number(flag, value()?)?),
+            "--breakpoint" => options.stop = Stop::Breakpoint(parse_number(flag, value()?)?),
+            "--input" => options.input_path = Some(PathBuf::from(value()?)),
+            "--screen" => options.screen_path = Some(PathBuf::from(value()?)),
+            "--memory" => options.memory_path = Some(PathBuf::from(value()?)),
+            "--debugger" => options.debugger = true,
+            "--debug" => options.debug = true,
+            "--trace" => options.trace = true,
+            _ if flag.starts_with("--") => return Err(format!("unknown option {}", flag)),
+            _ if rom_path.is_none() => rom_path = Some(PathBuf::from(flag)),
+            _ => return Err(format!("unexpected argument {}", flag)),
+        }
+    }
+    options.rom_path = rom_path.ok_or_else(|| "no ROM given".to_string())?;
+    // there is only one link port
+    if options.link.is_some() && options.printer_dir.is_some() {
+        return Err("--printer can't be used with a link cable".to_string());
+    }
+    if options.record_path.is_some() && options.play_path.is_some() {
+        return Err("--record and --play can't be used together".to_string());
+    }
+    if options.debugger
+        && (options.headless || options.record_path.is_some() || options.play_path.is_some())
+    {
+        return Err("--debugger can't be used with --headless, --record or --play".to_string());
+    }
+    // movies go frame by frame
+    if options.headless && options.record_path.is_some() {
+        if let Stop::Cycles(_) | Stop::Breakpoint(_) = options.stop {
+            return Err("--record only works with --frames when headless".to_string());
+        }
+    }
+    Ok(Command::Run(Box::new(options)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn parses_a_rom_and_options() {
+        let command = parse_args(&args(
+            "game.gb --model mgb --scale 4 --headless --frames 10 --trace",
+        ));
+        let options = match command {
+            Ok(Command::Run(options)) => options,
+            other => panic!("{:?}", other),
+        };
+        assert_eq!(options.rom_path, PathBuf::from("game.gb"));
+        assert_eq!(options.model, Model::Mgb);
+        assert_eq!(options.scale, 4);
+        assert!(options.headless);
+        assert_eq!(options.stop, Stop::Frames(10));
+        assert!(options.trace);
+        assert_eq!(options.compat_palette, None);
+        assert_eq!(options.color_correction, ColorCorrection::Off);
+        assert_eq!(options.link, None);
+        assert_eq!(options.printer_dir, None);
+        match parse_args(&args("game.gb --link-connect 10.0.0.2:8765")) {
+            Ok(Command::Run(options)) => assert_eq!(
+                options.link,
+                Some(LinkCable::Connect("10.0.0.2:8765".to_string()))
+            ),
+            other => panic!("{:?}", other),
+        }
+        match parse_args(&args("game.gb --color-correction lcd")) {
+            Ok(Command::Run(options)) => {
+                assert_eq!(options.color_correction, ColorCorrection::CgbLcd)
+            }
+            other => panic!("{:?}", other),
+        }
+        match parse_args(&args("game.gb --compat-palette down+a")) {
+            Ok(Command::Run(options)) => {
+                assert_eq!(options.compat_palette, Some(ManualPalette::DownA))
+            }
+            other => panic!("{:?}", other),
+        }
+        assert_eq!(options.get_palette(), GRAY_PALETTE);
+    }
+
+    #[test]
+    fn save_goes_next_to_the_rom_or_in_the_save_dir() {
+        let mut options = Options::new(PathBuf::from("roms/tetris.gb"));
+        assert_eq!(
+            options.get_save_path("tetris.gb"),
+            PathBuf::from("roms/tetris.sav")
+        );
+        options.save_dir = Some(PathBuf::from("saves"));
+        assert_eq!(
+            options.get_save_path("tetris.gb"),
+            PathBuf::from("saves/tetris.sav")
+        );
+
+        // an archive saves under the name of the ROM in it
+        let options = Options::new(PathBuf::from("roms/games.zip"));
+        assert_eq!(
+            options.get_save_path("zelda.gbc"),
+            PathBuf::from("roms/zelda.sav")
+        );
+        assert_eq!(
+            options.get_state_path("zelda.gbc", 2),
+            PathBuf::from("roms/zelda.ss2")
+        );
+    }
+
+    #[test]
+    fn parses_palettes() {
+        assert_eq!(parse_palette("gray"), Ok(GRAY_PALETTE));
+        assert_eq!(
+            parse_palette("e0f8d0,88c070,#346856,081820"),
+            Ok([0xFFE0F8D0, 0xFF88C070, 0xFF346856, 0xFF081820])
+        );
+        assert!(parse_palette("e0f8d0,88c070").is_err());
+    }
+
+    #[test]
+    fn reports_bad_arguments() {
+        assert!(parse_args(&args("--model dmg")).is_err());
+        assert!(parse_args(&args("game.gb --model gba")).is_err());
+        assert!(parse_args(&args("game.gb --scale 3")).is_err());
+        assert!(parse_args(&args("game.gb --compat-palette up+select")).is_err());
+        assert!(parse_args(&args("game.gb --color-correction vivid")).is_err());
+        assert!(parse_args(&args("game.gb --rewind-interval 0")).is_err());
+        let huge = format!("game.gb --rewind-budget {}", usize::MAX / 1024);
+        assert!(parse_args(&args(&huge)).is_err());
+        assert!(parse_args(&args("game.gb --record a.movie --play b.movie")).is_err());
+        assert!(parse_args(&args("game.gb --printer prints --link-listen :8765")).is_err());
+        assert!(parse_args(&args("game.gb --headless --cycles 100 --record a.movie")).is_err());
+        assert!(parse_args(&args("game.gb --debugger --headless")).is_err());
+        assert!(parse_args(&args("game.gb --frames")).is_err());
+        assert!(parse_args(&args("game.gb --fast")).is_err());
+        assert_eq!(parse_args(&args("")), Ok(Command::Help));
+        assert_eq!(
+            parse_args(&args("test-roms roms")),
+            Ok(Command::TestRoms(PathBuf::from("roms")))
+        );
+    }
+}
@@ -0,0 +1,289 @@
+// DMG compatibility palettes: when a CGB boots a monochrome cartridge its
+// boot ROM colorises it, picking the palettes from a hash of the title or
+// from a button combo held while the logo shows. These are the boot ROM's own
+// tables, so we get the same colors without needing the boot ROM.
+
+// $0134-$0143 - Title
+const TITLE_START: usize = 0x0134;
+const TITLE_END: usize = 0x0143;
+// $0144-$0145 - New Licensee Code
+const NEW_LICENSEE_ADDR: usize = 0x0144;
+// $014B - Old Licensee Code
+const OLD_LICENSEE_ADDR: usize = 0x014B;
+
+// entries from this one on (the 0xB3 after 0x6B) share their checksum with
+// another game and need the 4th letter of the title to tell them apart
+const FIRST_CHECKSUM_WITH_DUPLICATE: usize = 65;
+
+const TITLE_CHECKSUMS: [u8; 94] = [
+    0x00, 0x88, 0x16, 0x36, 0xD1, 0xDB, 0xF2, 0x3C, 0x8C, 0x92, 0x3D, 0x5C, 0x58, 0xC9, 0x3E, 0x70,
+    0x1D, 0x59, 0x69, 0x19, 0x35, 0xA8, 0x14, 0xAA, 0x75, 0x95, 0x99, 0x34, 0x6F, 0x15, 0xFF, 0x97,
+    0x4B, 0x90, 0x17, 0x10, 0x39, 0xF7, 0xF6, 0xA2, 0x49, 0x4E, 0x43, 0x68, 0xE0, 0x8B, 0xF0, 0xCE,
+    0x0C, 0x29, 0xE8, 0xB7, 0x86, 0x9A, 0x52, 0x01, 0x9D, 0x71, 0x9C, 0xBD, 0x5D, 0x6D, 0x67, 0x3F,
+    0x6B, // 64, the last one that needs no 4th letter
+    0xB3, 0x46, 0x28, 0xA5, 0xC6, 0xD3, 0x27, 0x61, 0x18, 0x66, 0x6A, 0xBF, 0x0D, 0xF4, 0xB3, 0x46,
+    0x28, 0xA5, 0xC6, 0xD3, 0x27, 0x61, 0x18, 0x66, 0x6A, 0xBF, 0x0D, 0xF4, 0xB3,
+];
+
+const DUPLICATE_4TH_LETTERS: &[u8; 29] = b"BEFAARBEKEK R-URAR INAILICE R";
+
+// palette combination used by each entry of TITLE_CHECKSUMS
+const COMBINATION_PER_CHECKSUM: [u8; 94] = [
+    0, 4, 5, 35, 34, 3, 31, 15, 10, 5, 19, 36, 7, 37, 30, 44, 21, 32, 31, 20, 5, 33, 13, 14, 5, 29,
+    5, 18, 9, 3, 2, 26, 25, 25, 41, 42, 26, 45, 42, 45, 36, 38, 26, 42, 30, 41, 34, 34, 5, 42, 6,
+    5, 33, 25, 42, 42, 40, 2, 16, 25, 42, 42, 5, 0, 39, 36, 22, 25, 6, 32, 12, 36, 11, 39, 18, 39,
+    24, 31, 50, 17, 46, 6, 27, 0, 47, 41, 41, 0, 0, 19, 34, 23, 18, 29,
+];
+
+// 30 palettes of 4 colors, 15 bit RGB like the CGB palette RAM
+const COLORS: [u16; 120] = [
+    0x7FFF, 0x32BF, 0x00D0, 0x0000, // 0
+    0x639F, 0x4279, 0x15B0, 0x04CB, // 1
+    0x7FFF, 0x6E31, 0x454A, 0x0000, // 2
+    0x7FFF, 0x1BEF, 0x0200, 0x0000, // 3
+    0x7FFF, 0x421F, 0x1CF2, 0x0000, // 4
+    0x7FFF, 0x5294, 0x294A, 0x0000, // 5
+    0x7FFF, 0x03FF, 0x012F, 0x0000, // 6
+    0x7FFF, 0x03EF, 0x01D6, 0x0000, // 7
+    0x7FFF, 0x42B5, 0x3DC8, 0x0000, // 8
+    0x7E74, 0x03FF, 0x0180, 0x0000, // 9
+    0x67FF, 0x77AC, 0x1A13, 0x2D6B, // 10
+    0x7ED6, 0x4BFF, 0x2175, 0x0000, // 11
+    0x53FF, 0x4A5F, 0x7E52, 0x0000, // 12
+    0x4FFF, 0x7ED2, 0x3A4C, 0x1CE0, // 13
+    0x03ED, 0x7FFF, 0x255F, 0x0000, // 14
+    0x036A, 0x021F, 0x03FF, 0x7FFF, // 15
+    0x7FFF, 0x01DF, 0x0112, 0x0000, // 16
+    0x231F, 0x035F, 0x00F2, 0x0009, // 17
+    0x7FFF, 0x03EA, 0x011F, 0x0000, // 18
+    0x299F, 0x001A, 0x000C, 0x0000, // 19
+    0x7FFF, 0x027F, 0x001F, 0x0000, // 20
+    0x7FFF, 0x03E0, 0x0206, 0x0120, // 21
+    0x7FFF, 0x7EEB, 0x001F, 0x7C00, // 22
+    0x7FFF, 0x3FFF, 0x7E00, 0x001F, // 23
+    0x7FFF, 0x03FF, 0x001F, 0x0000, // 24
+    0x03FF, 0x001F, 0x000C, 0x0000, // 25
+    0x7FFF, 0x033F, 0x0193, 0x0000, // 26
+    0x0000, 0x4200, 0x037F, 0x7FFF, // 27
+    0x7FFF, 0x7E8C, 0x7C00, 0x0000, // 28
+    0x7FFF, 0x1BEF, 0x6180, 0x0000, // 29
+];
+
+// OBJ0, OBJ1 and BG offsets into COLORS. A few combinations start in the
+// middle of a palette, the boot ROM reuses the overlap to save space.
+const COMBINATIONS: [(u8, u8, u8); 51] = [
+    (4 * 4, 4 * 4, 29 * 4),         // 0, Right + A (default)
+    (18 * 4, 18 * 4, 18 * 4),       // 1, Right
+    (20 * 4, 20 * 4, 20 * 4),       // 2
+    (24 * 4, 24 * 4, 24 * 4),       // 3, Down + A
+    (9 * 4, 9 * 4, 9 * 4),          // 4
+    (0, 0, 0),                      // 5, Up
+    (27 * 4, 27 * 4, 27 * 4),       // 6, Right + B
+    (5 * 4, 5 * 4, 5 * 4),          // 7, Left + B
+    (12 * 4, 12 * 4, 12 * 4),       // 8, Down
+    (26 * 4, 26 * 4, 26 * 4),       // 9
+    (16 * 4, 8 * 4, 8 * 4),         // 10
+    (4 * 4, 28 * 4, 28 * 4),        // 11
+    (4 * 4, 2 * 4, 2 * 4),          // 12
+    (3 * 4, 4 * 4, 4 * 4),          // 13
+    (4 * 4, 29 * 4, 29 * 4),        // 14
+    (28 * 4, 4 * 4, 28 * 4),        // 15
+    (2 * 4, 17 * 4, 2 * 4),         // 16
+    (16 * 4, 16 * 4, 8 * 4),        // 17
+    (4 * 4, 4 * 4, 7 * 4),          // 18
+    (4 * 4, 4 * 4, 18 * 4),         // 19
+    (4 * 4, 4 * 4, 20 * 4),         // 20
+    (19 * 4, 19 * 4, 9 * 4),        // 21
+    (4 * 4 - 1, 4 * 4 - 1, 11 * 4), // 22
+    (17 * 4, 17 * 4, 2 * 4),        // 23
+    (4 * 4, 4 * 4, 2 * 4),          // 24
+    (4 * 4, 4 * 4, 3 * 4),          // 25
+    (28 * 4, 28 * 4, 0),            // 26
+    (3 * 4, 3 * 4, 0),              // 27
+    (0, 0, 4),                      // 28, Up + B
+    (18 * 4, 22 * 4, 18 * 4),       // 29
+    (20 * 4, 22 * 4, 20 * 4),       // 30
+    (24 * 4, 22 * 4, 24 * 4),       // 31
+    (16 * 4, 22 * 4, 8 * 4),        // 32
+    (17 * 4, 4 * 4, 13 * 4),        // 33
+    (28 * 4 - 1, 0, 14 * 4),        // 34
+    (28 * 4 - 1, 4 * 4, 15 * 4),    // 35
+    (19 * 4, 22 * 4, 9 * 4),        // 36
+    (16 * 4, 28 * 4, 10 * 4),       // 37
+    (4 * 4, 23 * 4, 28 * 4),        // 38
+    (17 * 4, 22 * 4, 2 * 4),        // 39
+    (4 * 4, 0, 2 * 4),              // 40, Left + A
+    (4 * 4, 28 * 4, 3 * 4),         // 41
+    (28 * 4, 3 * 4, 0),             // 42
+    (3 * 4, 28 * 4, 4 * 4),         // 43, Up + A
+    (21 * 4, 28 * 4, 4 * 4),        // 44
+    (3 * 4, 28 * 4, 0),             // 45
+    (25 * 4, 3 * 4, 28 * 4),        // 46
+    (0, 28 * 4, 8 * 4),             // 47
+    (4 * 4, 3 * 4, 28 * 4),         // 48, Left
+    (28 * 4, 3 * 4, 6 * 4),         // 49, Down + B
+    (4 * 4, 28 * 4, 29 * 4),        // 50
+];
+
+// What the player can hold during the boot logo to choose the colors
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ManualPalette {
+    Up,
+    UpA,
+    UpB,
+    Left,
+    LeftA,
+    LeftB,
+    Down,
+    DownA,
+    DownB,
+    Right,
+    RightA,
+    RightB,
+}
+
+impl ManualPalette {
+    // Names like the CLI takes them: up, up+a, up+b, left, ... right+b
+    pub fn from_name(name: &str) -> Option<ManualPalette> {
+        match name.to_lowercase().as_str() {
+            "up" => Some(ManualPalette::Up),
+            "up+a" => Some(ManualPalette::UpA),
+            "up+b" => Some(ManualPalette::UpB),
+            "left" => Some(ManualPalette::Left),
+            "left+a" => Some(ManualPalette::LeftA),
+            "left+b" => Some(ManualPalette::LeftB),
+            "down" => Some(ManualPalette::Down),
+            "down+a" => Some(ManualPalette::DownA),
+            "down+b" => Some(ManualPalette::DownB),
+            "right" => Some(ManualPalette::Right),
+            "right+a" => Some(ManualPalette::RightA),
+            "right+b" => Some(ManualPalette::RightB),
+            _ => None,
+        }
+    }
+
+    fn combination(self) -> usize {
+        match self {
+            ManualPalette::Up => 5,
+            ManualPalette::UpA => 43,
+            ManualPalette::UpB => 28,
+            ManualPalette::Left => 48,
+            ManualPalette::LeftA => 40,
+            ManualPalette::LeftB => 7,
+            ManualPalette::Down => 8,
+            ManualPalette::DownA => 3,
+            ManualPalette::DownB => 49,
+            ManualPalette::Right => 1,
+            ManualPalette::RightA => 0,
+            ManualPalette::RightB => 6,
+        }
+    }
+
+    pub fn palette(self) -> CompatPalette {
+        CompatPalette::from_combination(self.combination())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CompatPalette {
+    pub bg: [u16; 4],
+    pub obj0: [u16; 4],
+    pub obj1: [u16; 4],
+}
+
+impl CompatPalette {
+    fn from_combination(combination: usize) -> CompatPalette {
+        let (obj0, obj1, bg) = COMBINATIONS[combination];
+        CompatPalette {
+            bg: CompatPalette::colors_at(bg),
+            obj0: CompatPalette::colors_at(obj0),
+            obj1: CompatPalette::colors_at(obj1),
+        }
+    }
+
+    fn colors_at(offset: u8) -> [u16; 4] {
+        let offset = offset as usize;
+        [
+            COLORS[offset],
+            COLORS[offset + 1],
+            COLORS[offset + 2],
+            COLORS[offset + 3],
+        ]
+    }
+
+    // The palettes the boot ROM would pick for this cartridge on its own
+    pub fn from_header(rom: &[u8]) -> CompatPalette {
+        CompatPalette::from_combination(combination_for_header(rom))
+    }
+}
+
+fn is_nintendo_licensee(rom: &[u8]) -> bool {
+    match rom[OLD_LICENSEE_ADDR] {
+        0x01 => true,
+        0x33 => &rom[NEW_LICENSEE_ADDR..=NEW_LICENSEE_ADDR + 1] == b"01",
+        _ => false,
+    }
+}
+
+fn combination_for_header(rom: &[u8]) -> usize {
+    // only Nintendo games get a palette of their own
+    if rom.len() <= OLD_LICENSEE_ADDR || !is_nintendo_licensee(rom) {
+        return 0;
+    }
+    let checksum = rom[TITLE_START..=TITLE_END]
+        .iter()
+        .fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+    let fourth_letter = rom[TITLE_START + 3];
+
+    for (i, title_checksum) in TITLE_CHECKSUMS.iter().enumerate() {
+        if *title_checksum != checksum {
+            continue;
+        }
+        if i < FIRST_CHECKSUM_WITH_DUPLICATE
+            || DUPLICATE_4TH_LETTERS[i - FIRST_CHECKSUM_WITH_DUPLICATE] == fourth_letter
+        {
+            return COMBINATION_PER_CHECKSUM[i] as usize;
+        }
+    }
+    0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gameboy::GameBoy;
+    use crate::mmu::Model;
+
+    // a monochrome game by Nintendo, titled "TETRIS"
+    fn rom() -> Vec<u8> {
+        let mut rom = vec![0; 0x8000];
+        rom[TITLE_START..TITLE_START + 6].copy_from_slice(b"TETRIS");
+        rom[OLD_LICENSEE_ADDR] = 0x01;
+        rom
+    }
+
+    fn bg_palette(gameboy: &GameBoy) -> [u16; 4] {
+        let mmu = gameboy.get_mmu();
+        [0, 1, 2, 3].map(|color| mmu.get_bg_palette_color(0, color))
+    }
+
+    #[test]
+    fn the_header_picks_the_palette() {
+        // TETRIS sums to 0xDB, which is entry 5, combination 3
+        let gameboy = GameBoy::new(Model::Cgb, &rom(), None, None).unwrap();
+        assert_eq!(combination_for_header(&rom()), 3);
+        assert_eq!(bg_palette(&gameboy), CompatPalette::from_combination(3).bg);
+    }
+
+    #[test]
+    fn a_button_combo_overrides_the_header() {
+        let mut gameboy = GameBoy::new(Model::Cgb, &rom(), None, None).unwrap();
+        gameboy.set_manual_palette(ManualPalette::from_name("Up").unwrap());
+        assert_eq!(bg_palette(&gameboy), [0x7FFF, 0x32BF, 0x00D0, 0x0000]);
+        assert_eq!(
+            ManualPalette::from_name("left+b"),
+            Some(ManualPalette::LeftB)
+        );
+        assert_eq!(ManualPalette::from_name("a+b"), None);
+    }
+}
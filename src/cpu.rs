@@ -1,9 +1,64 @@
-use crate::instruction::Instruction;
-use crate::mmu::MMU;
+use crate::error::EmuError;
+use crate::instruction::{Address, AluOp, Condition, Instruction, Operand, RotOp};
+use crate::mmu::{Model, MMU};
+use crate::opcodes::{CB_OPCODES, OPCODES};
 use crate::ppu::PPU;
+use crate::savestate::{StateError, StateReader, StateWriter};
 
 use std::fmt;
 
+const Z_FLAG: u8 = 0b1000_0000;
+const N_FLAG: u8 = 0b0100_0000;
+const H_FLAG: u8 = 0b0010_0000;
+const C_FLAG: u8 = 0b0001_0000;
+
+// $FFFF - IE, $FF0F - IF: VBlank, STAT, Timer, Serial, Joypad
+const IE_ADDR: u16 = 0xFFFF;
+const IF_ADDR: u16 = 0xFF0F;
+const INTERRUPT_BITS: u8 = 0b0001_1111;
+// pushing PC and jumping to the handler takes 5 M-cycles
+const INTERRUPT_CLOCKS: usize = 20;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Reg8 {
+    A,
+    F,
+    B,
+    C,
+    D,
+    E,
+    H,
+    L,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Reg16 {
+    Af,
+    Bc,
+    De,
+    Hl,
+    Sp,
+    Pc,
+}
+
+// How the rest of the console keeps up with the CPU
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Timing {
+    // every instruction runs in one go, then the PPU, timer and serial port
+    // catch up with all of its clocks at once
+    Instruction,
+    // each memory access moves everything else by one M-cycle first, so
+    // they see reads and writes at the right point inside the instruction
+    MCycle,
+}
+
+// A read or write the CPU put on the bus, with the address and the byte
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BusAccess {
+    Read(u16, u8),
+    Write(u16, u8),
+}
+
 pub struct CPU {
     a: u8,
     b: u8,
@@ -21,6 +76,22 @@ pub struct CPU {
     last_t: usize,
     last_m: usize,
     debug: bool,
+    // EI only takes effect after the instruction that follows it
+    ime_scheduled: bool,
+    halted: bool,
+    // HALT with IME off and an interrupt already pending doesn't halt, but
+    // the next opcode byte is read twice
+    halt_bug: bool,
+    // an illegal opcode hangs the CPU until the console is turned off
+    locked: bool,
+    timing: Timing,
+    // clocks of the current instruction the rest of the console already ran
+    system_clocks: usize,
+    // only kept after record_bus_accesses, for the tests and the debugger
+    bus_accesses: Option<Vec<BusAccess>>,
+    // what the last run_instruction ran, None when it was an interrupt, HALT
+    // or the CPU being locked
+    last_opcode: Option<u8>,
 }
 
 impl fmt::Debug for CPU {
@@ -65,10 +136,113 @@ impl CPU {
             last_t: 0,
             last_m: 0,
             debug: false,
+            ime_scheduled: false,
+            halted: false,
+            halt_bug: false,
+            locked: false,
+            timing: Timing::Instruction,
+            system_clocks: 0,
+            bus_accesses: None,
+            last_opcode: None,
         };
         cpu
     }
 
+    // Registers as the boot ROM leaves them, for when we don't run it
+    pub fn skip_boot_rom(&mut self, mmu: &mut MMU) {
+        mmu.skip_boot_rom();
+        if mmu.is_cgb_mode() {
+            self.a = 0x11;
+            self.f = 0x80;
+            self.b = 0x00;
+            self.c = 0x00;
+            self.d = 0xFF;
+            self.e = 0x56;
+            self.h = 0x00;
+            self.l = 0x0D;
+        } else if mmu.get_model() == Model::Sgb {
+            self.a = 0x01;
+            self.f = 0x00;
+            self.b = 0x00;
+            self.c = 0x14;
+            self.d = 0x00;
+            self.e = 0x00;
+            self.h = 0xC0;
+            self.l = 0x60;
+        } else if mmu.get_model() == Model::Cgb {
+            // the CGB boot ROM in compatibility mode
+            self.a = 0x11;
+            self.f = 0x80;
+            self.b = 0x00;
+            self.c = 0x00;
+            self.d = 0x00;
+            self.e = 0x08;
+            self.h = 0x00;
+            self.l = 0x7C;
+        } else {
+            self.a = if mmu.get_model() == Model::Mgb {
+                0xFF
+            } else {
+                0x01
+            };
+            self.f = 0xB0;
+            self.b = 0x00;
+            self.c = 0x13;
+            self.d = 0x00;
+            self.e = 0xD8;
+            self.h = 0x01;
+            self.l = 0x4D;
+        }
+        self.sp = 0xFFFE;
+        self.pc = 0x0100;
+    }
+
+    // Timing, debug output and bus logging are settings, not state
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_bytes(&[
+            self.a, self.b, self.c, self.d, self.e, self.f, self.h, self.l,
+        ]);
+        state.write_u16(self.pc);
+        state.write_u16(self.sp);
+        state.write_usize(self.t);
+        state.write_usize(self.m);
+        state.write_usize(self.last_t);
+        state.write_usize(self.last_m);
+        state.write_bool(self.ime);
+        state.write_bool(self.ime_scheduled);
+        state.write_bool(self.halted);
+        state.write_bool(self.halt_bug);
+        state.write_bool(self.locked);
+        state.write_usize(self.system_clocks);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        let mut registers = [0; 8];
+        state.read_bytes(&mut registers)?;
+        let [a, b, c, d, e, f, h, l] = registers;
+        self.a = a;
+        self.b = b;
+        self.c = c;
+        self.d = d;
+        self.e = e;
+        self.f = f;
+        self.h = h;
+        self.l = l;
+        self.pc = state.read_u16()?;
+        self.sp = state.read_u16()?;
+        self.t = state.read_usize()?;
+        self.m = state.read_usize()?;
+        self.last_t = state.read_usize()?;
+        self.last_m = state.read_usize()?;
+        self.ime = state.read_bool()?;
+        self.ime_scheduled = state.read_bool()?;
+        self.halted = state.read_bool()?;
+        self.halt_bug = state.read_bool()?;
+        self.locked = state.read_bool()?;
+        self.system_clocks = state.read_usize()?;
+        Ok(())
+    }
+
     pub fn set_debug_flag(&mut self) {
         self.debug = true;
     }
@@ -80,1282 +254,1151 @@ impl CPU {
         (self.f & bit_mask) != 0
     }
     fn get_z_flag(&self) -> bool {
-        self.get_flag(0b1000_0000)
+        self.get_flag(Z_FLAG)
     }
     fn get_n_flag(&self) -> bool {
-        self.get_flag(0b0100_0000)
+        self.get_flag(N_FLAG)
     }
     fn get_h_flag(&self) -> bool {
-        self.get_flag(0b0010_0000)
+        self.get_flag(H_FLAG)
     }
     fn get_c_flag(&self) -> bool {
-        self.get_flag(0b0001_0000)
+        self.get_flag(C_FLAG)
     }
 
-    fn set_z_flag(&mut self) {
-        self.f = self.f | 0b1000_0000;
+    fn set_flag(&mut self, bit_mask: u8, value: bool) {
+        if value {
+            self.f |= bit_mask;
+        } else {
+            self.f &= !bit_mask;
+        }
     }
 
-    fn reset_z_flag(&mut self) {
-        self.f = self.f & 0b0111_1111;
-    }
-    fn set_n_flag(&mut self) {
-        self.f = self.f | 0b0100_0000;
-    }
-    fn reset_n_flag(&mut self) {
-        self.f = self.f & 0b1011_1111;
+    fn set_flags(&mut self, z: bool, n: bool, h: bool, c: bool) {
+        self.set_flag(Z_FLAG, z);
+        self.set_flag(N_FLAG, n);
+        self.set_flag(H_FLAG, h);
+        self.set_flag(C_FLAG, c);
     }
-    fn set_h_flag(&mut self) {
-        self.f = self.f | 0b0010_0000;
-    }
-    fn reset_h_flag(&mut self) {
-        self.f = self.f & 0b1101_1111;
-    }
-    fn set_c_flag(&mut self) {
-        self.f = self.f | 0b0001_0000;
-    }
-    fn reset_c_flag(&mut self) {
-        self.f = self.f & 0b1110_1111;
+
+    pub fn get_register(&self, register: Reg8) -> u8 {
+        match register {
+            Reg8::A => self.a,
+            Reg8::F => self.f,
+            Reg8::B => self.b,
+            Reg8::C => self.c,
+            Reg8::D => self.d,
+            Reg8::E => self.e,
+            Reg8::H => self.h,
+            Reg8::L => self.l,
+        }
     }
 
-    pub fn push_to_stack(&mut self, mmu: &mut MMU, addr: u16) {
-        // write the address being pushed to the current stack pointer
-        let addr_0: u8 = ((addr & 0xFF00) >> 8) as u8;
-        let addr_1: u8 = (addr & 0x00FF) as u8;
-        mmu.write_byte(self.sp, addr_1);
-        self.sp -= 1;
-        mmu.write_byte(self.sp, addr_0);
-        self.sp -= 1;
+    pub fn set_register(&mut self, register: Reg8, value: u8) {
+        match register {
+            Reg8::A => self.a = value,
+            // the low nibble of F doesn't exist, it always reads 0
+            Reg8::F => self.f = value & 0xF0,
+            Reg8::B => self.b = value,
+            Reg8::C => self.c = value,
+            Reg8::D => self.d = value,
+            Reg8::E => self.e = value,
+            Reg8::H => self.h = value,
+            Reg8::L => self.l = value,
+        }
     }
 
-    pub fn pop_from_stack(&mut self, mmu: &MMU) -> u16 {
-        self.sp += 1;
-        let addr_0 = mmu.read_byte(self.sp);
-        self.sp += 1;
-        let addr_1 = mmu.read_byte(self.sp);
-        let addr_016 = (addr_0 as u16) << 8;
-        let addr: u16 = addr_016 | (addr_1 as u16);
-        addr
+    pub fn get_register_pair(&self, register: Reg16) -> u16 {
+        let (high, low) = match register {
+            Reg16::Af => (self.a, self.f),
+            Reg16::Bc => (self.b, self.c),
+            Reg16::De => (self.d, self.e),
+            Reg16::Hl => (self.h, self.l),
+            Reg16::Sp => return self.sp,
+            Reg16::Pc => return self.pc,
+        };
+        ((high as u16) << 8) | (low as u16)
     }
 
-    fn do_bit_opcode(&mut self, bit: bool) {
-        if bit {
-            self.set_z_flag();
-        } else {
-            self.reset_z_flag();
+    pub fn set_register_pair(&mut self, register: Reg16, value: u16) {
+        let high = (value >> 8) as u8;
+        let low = (value & 0x00FF) as u8;
+        match register {
+            Reg16::Af => {
+                self.set_register(Reg8::A, high);
+                self.set_register(Reg8::F, low);
+            }
+            Reg16::Bc => {
+                self.b = high;
+                self.c = low;
+            }
+            Reg16::De => {
+                self.d = high;
+                self.e = low;
+            }
+            Reg16::Hl => {
+                self.h = high;
+                self.l = low;
+            }
+            Reg16::Sp => self.sp = value,
+            Reg16::Pc => self.pc = value,
         }
-        self.reset_n_flag();
-        self.set_h_flag();
-        self.pc += 2;
-    }
-    fn calc_half_carry_on_u16_sum(&self, value_a: u16, value_b: u16) -> bool {
-        ((value_a & 0xFFF) + (value_b & 0xFFF)) & 0x1000 == 0x1000
     }
 
-    fn calc_half_carry_on_u16_sub(&self, value_a: u16, value_b: u16) -> bool {
-        (value_a & 0xFFF) < (value_b & 0xFFF)
+    pub fn push_to_stack(&mut self, mmu: &mut MMU, ppu: &mut PPU, value: u16) {
+        // the high byte goes first, so it ends up above the low one
+        self.sp = self.sp.wrapping_sub(1);
+        self.write(mmu, ppu, self.sp, (value >> 8) as u8);
+        self.sp = self.sp.wrapping_sub(1);
+        self.write(mmu, ppu, self.sp, (value & 0x00FF) as u8);
     }
 
-    fn calc_half_carry_on_u8_sum(&self, value_a: u8, value_b: u8) -> bool {
-        ((value_a & 0xF) + (value_b & 0xF)) & 0x10 == 0x10
+    pub fn pop_from_stack(&mut self, mmu: &mut MMU, ppu: &mut PPU) -> u16 {
+        let low = self.read(mmu, ppu, self.sp);
+        self.sp = self.sp.wrapping_add(1);
+        let high = self.read(mmu, ppu, self.sp);
+        self.sp = self.sp.wrapping_add(1);
+        ((high as u16) << 8) | (low as u16)
     }
 
-    fn calc_half_carry_on_u8_sub(&self, value_a: u8, value_b: u8) -> bool {
-        (value_a & 0xF) < (value_b & 0xF)
+    fn tick(&mut self, clocks: usize) {
+        self.t += clocks;
+        self.m += clocks / 4;
     }
 
-    fn do_inc_d16(&mut self, register_value: u16) -> u16 {
-        // Checking the Half Carry bit
-        if self.calc_half_carry_on_u16_sum(register_value, 1) {
-            self.set_h_flag();
-        } else {
-            self.reset_h_flag();
+    // Runs the PPU, timer and serial port for `clocks` CPU clocks
+    fn tick_system(&mut self, mmu: &mut MMU, ppu: &mut PPU, clocks: usize) {
+        self.system_clocks += clocks;
+        if mmu.is_flat() {
+            return;
         }
-
-        let new_register_value = register_value.wrapping_add(1);
-
-        self.pc += 1;
-        self.t += 4;
-        self.m += 1;
-        // set the flags
-        if new_register_value == 0 {
-            self.set_z_flag();
+        // the timer and serial port run off the CPU clock, so they speed up
+        // in double speed
+        mmu.step_timer(clocks);
+        mmu.step_serial(clocks);
+        // while the PPU keeps its pace
+        let ppu_clocks = if mmu.is_double_speed() {
+            clocks / 2
         } else {
-            self.reset_z_flag();
-        }
-        self.reset_n_flag();
-        new_register_value
+            clocks
+        };
+        ppu.step(ppu_clocks, mmu);
     }
-    fn do_add(&mut self, register_value_a: u8, register_value_b: u8) -> u8 {
-        // Checking the Half Carry bit
-        if self.calc_half_carry_on_u8_sum(register_value_a, register_value_b) {
-            self.set_h_flag();
-        } else {
-            self.reset_h_flag();
-        }
 
-        let new_register_value_a = register_value_a.wrapping_add(register_value_b);
-
-        self.pc += 1;
-        self.t += 4;
-        self.m += 1;
-        // set the flags
-        if new_register_value_a == 0 {
-            self.set_z_flag();
-        } else {
-            self.reset_z_flag();
-        }
-        self.reset_n_flag();
-        new_register_value_a
-    }
-    fn do_inc_n(&mut self, register_value: u8) -> u8 {
-        self.do_add(register_value, 1)
-    }
-    fn do_sub(&mut self, register_value_a: u8, register_value_b: u8) -> u8 {
-        // Checking the Half Carry bit
-        if self.calc_half_carry_on_u8_sub(register_value_a, register_value_b) {
-            self.reset_h_flag();
-        } else {
-            self.set_h_flag();
+    // Every read and write the CPU does goes through these two, each one is
+    // a M-cycle
+    fn read(&mut self, mmu: &mut MMU, ppu: &mut PPU, address: u16) -> u8 {
+        if self.timing == Timing::MCycle {
+            self.tick_system(mmu, ppu, 4);
         }
-
-        let new_register_value_a = register_value_a.wrapping_sub(register_value_b);
-
-        self.pc += 1;
-        self.t += 4;
-        self.m += 1;
-        // set the flags
-        if new_register_value_a == 0 {
-            self.set_z_flag();
-        } else {
-            self.reset_z_flag();
+        let value = mmu.read_byte(address);
+        if let Some(bus_accesses) = &mut self.bus_accesses {
+            bus_accesses.push(BusAccess::Read(address, value));
         }
-        self.reset_n_flag();
-        new_register_value_a
-    }
-    fn do_dec_n(&mut self, register_value: u8) -> u8 {
-        self.do_sub(register_value, 1)
+        value
     }
 
-    fn do_rl_n(&mut self, register_value: u8) -> u8 {
-        let old_c_flag = self.get_c_flag();
-        let c_flag: bool = (0b1000_0000 & register_value) != 0;
-        if c_flag {
-            self.set_c_flag();
-        } else {
-            self.reset_c_flag();
+    fn write(&mut self, mmu: &mut MMU, ppu: &mut PPU, address: u16, value: u8) {
+        if self.timing == Timing::MCycle {
+            self.tick_system(mmu, ppu, 4);
         }
-        // actually rotating
-        let mut new_register_value = register_value << 1;
-        new_register_value = new_register_value & 0b1111_1110;
-        if old_c_flag {
-            new_register_value += 1;
+        if let Some(bus_accesses) = &mut self.bus_accesses {
+            bus_accesses.push(BusAccess::Write(address, value));
         }
-        //handling flags
-        if new_register_value == 0 {
-            self.set_z_flag();
-        } else {
-            self.reset_z_flag();
-        }
-        self.reset_n_flag();
-        self.reset_h_flag();
-
-        self.pc += 2;
-        self.t += 8;
-        self.t += 2;
-        new_register_value
+        mmu.write_byte(address, value);
     }
 
-    fn decode(&mut self, byte: u8, mmu: &MMU) -> Instruction {
-        if self.debug {
-            println!("Decoding PC: {:#X}", self.pc);
-        }
-        // prepare some special variables
-        // the immediate 16 bit
-        let n1 = mmu.read_byte(self.pc + 1) as u16;
-        let n2 = mmu.read_byte(self.pc + 2) as u16;
-        // inverting position because it is BIG ENDIAN with bitwise operation
-        let d16: u16 = (n2 << 8) | n1;
-
-        // in case of prefix CB n1 is OPCODE
-        let cb_opcode = n1;
-        // in case of prefix CB n2 is n1
-        let cb_n1 = n2;
-        let cb_n2 = mmu.read_byte(self.pc + 3) as u16;
-        // inverting position because it is BIG ENDIAN with bitwise operation
-        let cb_d16: u16 = (cb_n2 << 8) | cb_n1;
-
-        match byte {
-            0x00 => Instruction::Nop,
-            0xF3 => Instruction::Di,
-            0xFB => Instruction::Ei,
-            0x01 => Instruction::LdBc(d16),
-            0x3E => Instruction::LdA(n1 as u8),
-            0x06 => Instruction::LdB(n1 as u8),
-            0x0E => Instruction::LdC(n1 as u8),
-            0x16 => Instruction::LdD(n1 as u8),
-            0x1E => Instruction::LdE(n1 as u8),
-            0x26 => Instruction::LdH(n1 as u8),
-            0x2E => Instruction::LdL(n1 as u8),
-            0x7F => Instruction::LdAa,
-            0x47 => Instruction::LdBa,
-            0x4F => Instruction::LdCa,
-            0x57 => Instruction::LdDa,
-            0x5F => Instruction::LdEa,
-            0x67 => Instruction::LdHa,
-            0x6F => Instruction::LdLa,
-            0x77 => Instruction::LdHlA,
-            0x36 => Instruction::LdHln(n1 as u8),
-            0xEA => Instruction::LdXxA(d16),
-            0x1A => Instruction::LdADe,
-            0x0A => Instruction::LdABc,
-            0x78 => Instruction::LdAb,
-            0x79 => Instruction::LdAc,
-            0x7A => Instruction::LdAd,
-            0x7B => Instruction::LdAe,
-            0x7C => Instruction::LdAh,
-            0x7D => Instruction::LdAl,
-            0x11 => Instruction::LdDe(d16),
-            0x21 => Instruction::LdHl(d16),
-            0x31 => Instruction::LdSp(d16),
-            0xE0 => Instruction::LdFf00U8a(n1 as u8),
-            0xF0 => Instruction::LdAFf00U8(n1 as u8),
-            0xE2 => Instruction::LdFf00Ca,
-            0x18 => Instruction::Jr(n1 as i8),
-            0x20 => Instruction::JrNz(n1 as i8),
-            0x28 => Instruction::JrZ(n1 as i8),
-            0x30 => Instruction::JrNc(n1 as i8),
-            0xC3 => Instruction::Jp(d16),
-            0x32 => Instruction::LddHlA,
-            0x22 => Instruction::LdiHlA,
-            0x2A => Instruction::LdiAHl,
-            0x38 => Instruction::JrC(n1 as i8),
-            0xAF => Instruction::XorA,
-            0xA8 => Instruction::XorB,
-            0xA9 => Instruction::XorC,
-            0xAA => Instruction::XorD,
-            0xAB => Instruction::XorE,
-            0xAC => Instruction::XorH,
-            0xAD => Instruction::XorL,
-            0xAE => Instruction::XorHl,
-            0x3C => Instruction::IncA,
-            0x04 => Instruction::IncB,
-            0x0C => Instruction::IncC,
-            0x14 => Instruction::IncD,
-            0x1C => Instruction::IncE,
-            0x24 => Instruction::IncH,
-            0x2C => Instruction::IncL,
-            0x23 => Instruction::IncHlNoflags,
-            0x34 => Instruction::IncHl,
-            0x13 => Instruction::IncDe,
-            0x03 => Instruction::IncBc,
-            0x3D => Instruction::DecA,
-            0x05 => Instruction::DecB,
-            0x0D => Instruction::DecC,
-            0x15 => Instruction::DecD,
-            0x1D => Instruction::DecE,
-            0x25 => Instruction::DecH,
-            0x2D => Instruction::DecL,
-            0x35 => Instruction::DecHl,
-            0x97 => Instruction::SubA,
-            0x90 => Instruction::SubB,
-            0x91 => Instruction::SubC,
-            0x92 => Instruction::SubD,
-            0x93 => Instruction::SubE,
-            0x94 => Instruction::SubH,
-            0x95 => Instruction::SubL,
-            0x96 => Instruction::SubHl,
-            0xD6 => Instruction::Sub(n1 as u8),
-            0x87 => Instruction::AddAa,
-            0x80 => Instruction::AddAb,
-            0x81 => Instruction::AddAc,
-            0x81 => Instruction::AddAc,
-            0x82 => Instruction::AddAd,
-            0x83 => Instruction::AddAe,
-            0x84 => Instruction::AddAh,
-            0x85 => Instruction::AddAl,
-            0x86 => Instruction::AddAhl,
-            0xC6 => Instruction::AddA(n1 as u8),
-            0xC4 => Instruction::CallNz(d16),
-            0xD4 => Instruction::CallNc(d16),
-            0xCC => Instruction::CallZ(d16),
-            0xDC => Instruction::CallC(d16),
-            0xCD => Instruction::Call(d16),
-            0xC9 => Instruction::Ret,
-            0xF5 => Instruction::PushAf,
-            0xC5 => Instruction::PushBc,
-            0xD5 => Instruction::PushDe,
-            0xE5 => Instruction::PushHl,
-            0xF1 => Instruction::PopAf,
-            0xC1 => Instruction::PopBc,
-            0xD1 => Instruction::PopDe,
-            0xE1 => Instruction::PopHl,
-            0x17 => Instruction::RLA,
-            0xBF => Instruction::CpA,
-            0xB8 => Instruction::CpB,
-            0xB9 => Instruction::CpC,
-            0xBA => Instruction::CpD,
-            0xBB => Instruction::CpE,
-            0xBC => Instruction::CpH,
-            0xBD => Instruction::CpL,
-            0xBE => Instruction::CpHl,
-            0xFE => Instruction::Cp(n1 as u8),
-            0xCB => match cb_opcode {
-                0x40 => Instruction::BitbB(0b0000_0001),
-                0x41 => Instruction::BitbC(0b0000_0001),
-                0x42 => Instruction::BitbD(0b0000_0001),
-                0x43 => Instruction::BitbE(0b0000_0001),
-                0x44 => Instruction::BitbH(0b0000_0001),
-                0x45 => Instruction::BitbL(0b0000_0001),
-                0x46 => Instruction::BitbHL(0b0000_0001),
-                0x47 => Instruction::BitbA(0b0000_0001),
-                0x48 => Instruction::BitbB(0b0000_0010),
-                0x49 => Instruction::BitbC(0b0000_0010),
-                0x4A => Instruction::BitbD(0b0000_0010),
-                0x4B => Instruction::BitbE(0b0000_0010),
-                0x4C => Instruction::BitbH(0b0000_0010),
-                0x4D => Instruction::BitbL(0b0000_0010),
-                0x4E => Instruction::BitbHL(0b0000_0010),
-                0x4F => Instruction::BitbA(0b0000_0010),
-                0x50 => Instruction::BitbB(0b0000_0100),
-                0x51 => Instruction::BitbC(0b0000_0100),
-                0x52 => Instruction::BitbD(0b0000_0100),
-                0x53 => Instruction::BitbE(0b0000_0100),
-                0x54 => Instruction::BitbH(0b0000_0100),
-                0x55 => Instruction::BitbL(0b0000_0100),
-                0x56 => Instruction::BitbHL(0b0000_0100),
-                0x57 => Instruction::BitbA(0b0000_0100),
-                0x58 => Instruction::BitbB(0b0000_1000),
-                0x59 => Instruction::BitbC(0b0000_1000),
-                0x5A => Instruction::BitbD(0b0000_1000),
-                0x5B => Instruction::BitbE(0b0000_1000),
-                0x5C => Instruction::BitbH(0b0000_1000),
-                0x5D => Instruction::BitbL(0b0000_1000),
-                0x5E => Instruction::BitbHL(0b0000_1000),
-                0x5F => Instruction::BitbA(0b0000_1000),
-                0x7C => Instruction::BitbH(0b1000_0000),
-                0x17 => Instruction::RlA,
-                0x10 => Instruction::RlB,
-                0x11 => Instruction::RlC,
-                0x12 => Instruction::RlD,
-                0x13 => Instruction::RlE,
-                0x14 => Instruction::RlH,
-                0x15 => Instruction::RlL,
-                0x16 => Instruction::RlHl,
-                _ => panic!(
-                    "DECODING CB PREFIX: Unreconized MMU STATE: {:?}\ncb_opcode {:#X} on pc {:#X}\n CPU STATE: {:?}",
-                    mmu, cb_opcode, self.pc as u16, self
-                ),
-            },
-            0xEE => Instruction::Xor(n1 as u8),
-            _ => panic!(
-                "\nMMU STATE: {:?} \nCPU STATE: {:?}\nDECODING: Unreconized byte {:#X} on pc {:#X}",
-                mmu, self, byte, self.pc
-            ),
+    // A M-cycle where the CPU is busy without touching memory. The clocks of
+    // the instruction that are left after its accesses run at the end
+    // anyway, this is only needed when an access comes after it.
+    fn internal_cycle(&mut self, mmu: &mut MMU, ppu: &mut PPU) {
+        if self.timing == Timing::MCycle {
+            self.tick_system(mmu, ppu, 4);
         }
     }
 
-    fn set_register(&mut self, register_name: &str, register_value: u8) {
-        match register_name {
-            "a" => {
-                self.a = register_value;
-            }
-            "b" => {
-                self.b = register_value;
-            }
-            "c" => {
-                self.c = register_value;
-            }
-            "d" => {
-                self.d = register_value;
-            }
-            "e" => {
-                self.e = register_value;
-            }
-            "f" => {
-                self.f = register_value;
-            }
-            "h" => {
-                self.h = register_value;
-            }
-            "l" => {
-                self.l = register_value;
-            }
-            _ => {
-                panic!(
-                    "set_register: Unrecognized register to set {:?}",
-                    &register_name
-                );
-            }
+    fn check_condition(&self, condition: Option<Condition>) -> bool {
+        match condition {
+            None => true,
+            Some(Condition::Nz) => !self.get_z_flag(),
+            Some(Condition::Z) => self.get_z_flag(),
+            Some(Condition::Nc) => !self.get_c_flag(),
+            Some(Condition::C) => self.get_c_flag(),
         }
     }
-    fn get_register(&self, register_name: &str) -> u8 {
-        match register_name {
-            "a" => self.a,
-            "b" => self.b,
-            "c" => self.c,
-            "d" => self.d,
-            "e" => self.e,
-            "f" => self.f,
-            "h" => self.h,
-            "l" => self.l,
-            _ => {
-                panic!(
-                    "set_register: Unrecognized register to set {:?}",
-                    &register_name
-                );
-            }
+
+    fn read_operand(&mut self, mmu: &mut MMU, ppu: &mut PPU, operand: Operand) -> u8 {
+        match operand {
+            Operand::Reg(register) => self.get_register(register),
+            Operand::HlIndirect => self.read(mmu, ppu, self.get_register_pair(Reg16::Hl)),
+            Operand::Immediate(n) => n,
         }
     }
 
-    fn do_ld_reg_to_reg(&mut self, to: &str, from: &str) {
-        if self.debug {
-            println!("LD {:?} {:?}", to, from)
+    fn write_operand(&mut self, mmu: &mut MMU, ppu: &mut PPU, operand: Operand, value: u8) {
+        match operand {
+            Operand::Reg(register) => self.set_register(register, value),
+            Operand::HlIndirect => self.write(mmu, ppu, self.get_register_pair(Reg16::Hl), value),
+            Operand::Immediate(_) => unreachable!("immediates are never written to"),
         }
-        self.set_register(to, self.get_register(from));
-        self.pc += 1;
-        self.t += 4;
-        self.m += 1;
     }
 
-    fn execute(&mut self, instruction: &Instruction, mmu: &mut MMU) {
-        if self.debug {
-            println!("Executing PC: {:#X}", self.pc);
+    // (HL+) and (HL-) move HL along as a side effect
+    fn resolve_address(&mut self, address: Address) -> u16 {
+        match address {
+            Address::Bc => self.get_register_pair(Reg16::Bc),
+            Address::De => self.get_register_pair(Reg16::De),
+            Address::HlIncrement => {
+                let hl = self.get_register_pair(Reg16::Hl);
+                self.set_register_pair(Reg16::Hl, hl.wrapping_add(1));
+                hl
+            }
+            Address::HlDecrement => {
+                let hl = self.get_register_pair(Reg16::Hl);
+                self.set_register_pair(Reg16::Hl, hl.wrapping_sub(1));
+                hl
+            }
+            Address::Direct(nn) => nn,
+            Address::HighC => 0xFF00 | self.c as u16,
+            Address::High(n) => 0xFF00 | n as u16,
         }
-        match instruction {
-            Instruction::Nop => {
-                self.pc += 1;
-                self.t += 4;
-                self.m += 1;
-            },
-            Instruction::Di => {
-                self.ime = false;
-                self.pc += 1;
-                self.t += 4;
-                self.m += 1;
-            },
-            Instruction::Ei => {
-                self.ime = true;
-                self.pc += 1;
-                self.t += 4;
-                self.m += 1;
-            }
-            Instruction::LdA(n) => {
-                if self.debug {
-                    println!("LD A, n: {:#X}", n);
-                }
-                self.a = *n;
-                self.pc += 2;
-                self.t += 8;
-                self.m += 2;
-            }
-            Instruction::LdB(n) => {
-                if self.debug {
-                    println!("LD B, n: {:#X}", n);
-                }
-                self.b = *n;
-                self.pc += 2;
-                self.t += 8;
-                self.m += 2;
-            }
-            Instruction::LdC(n) => {
-                if self.debug {
-                    println!("LD C, n: {:#X}", n);
-                }
-                self.c = *n;
-                self.pc += 2;
-                self.t += 8;
-                self.m += 2;
-            }
-            Instruction::LdD(n) => {
-                if self.debug {
-                    println!("LD D, n: {:#X}", n);
-                }
-                self.d = *n;
-                self.pc += 2;
-                self.t += 8;
-                self.m += 2;
-            }
-            Instruction::LdE(n) => {
-                if self.debug {
-                    println!("LD E, n: {:#X}", n);
-                }
-                self.e = *n;
-                self.pc += 2;
-                self.t += 8;
-                self.m += 2;
-            }
-            Instruction::LdH(n) => {
-                if self.debug {
-                    println!("LD H, n: {:#X}", n);
-                }
-                self.h = *n;
-                self.pc += 2;
-                self.t += 8;
-                self.m += 2;
-            }
-            Instruction::LdL(n) => {
-                if self.debug {
-                    println!("LD L, n: {:#X}", n);
-                }
-                self.l = *n;
-                self.pc += 2;
-                self.t += 8;
-                self.m += 2;
-            }
-            Instruction::LdSp(d16) => {
-                if self.debug {
-                    println!("LD SP, d16: {:#X}", d16);
-                }
-                self.sp = *d16;
-                self.pc += 3;
-                self.t += 12;
-                self.m += 3;
-            }
-            Instruction::LdBc(d16) => {
-                if self.debug {
-                    println!("LD BC, d16: {:#X}", d16);
-                }
-                self.b = ((d16 & 0xFF00) >> 8) as u8;
-                self.c = (d16 & 0x00FF) as u8;
-                self.pc += 3;
-                self.t += 12;
-                self.m += 3;
-            }
-            Instruction::LdDe(d16) => {
-                if self.debug {
-                    println!("LD DE, d16: {:#X}", d16);
-                }
-                self.d = ((d16 & 0xFF00) >> 8) as u8;
-                self.e = (d16 & 0x00FF) as u8;
-                self.pc += 3;
-                self.t += 12;
-                self.m += 3;
-            }
-            Instruction::LdHl(d16) => {
-                if self.debug {
-                    println!(
-                        "LD HL before, d16: {:#X} H: {:#X}, L: {:#X}",
-                        d16, self.h, self.l
-                    );
-                }
-                self.h = ((d16 & 0xFF00) >> 8) as u8;
-                self.l = (d16 & 0x00FF) as u8;
-                if self.debug {
-                    println!("LD HL after, H: {:#X}, L: {:#X}", self.h, self.l);
-                }
-                self.pc += 3;
-                self.t += 12;
-                self.m += 3;
-            }
-            Instruction::LdAa => {
-                self.do_ld_reg_to_reg("a", "a");
-            }
-            Instruction::LdBa => {
-                self.do_ld_reg_to_reg("b", "a");
-            }
-            Instruction::LdCa => {
-                self.do_ld_reg_to_reg("c", "a");
-            }
-            Instruction::LdDa => {
-                self.do_ld_reg_to_reg("d", "a");
-            }
-            Instruction::LdEa => {
-                self.do_ld_reg_to_reg("e", "a");
-            }
-            Instruction::LdHa => {
-                self.do_ld_reg_to_reg("h", "a");
-            }
-            Instruction::LdLa => {
-                self.do_ld_reg_to_reg("l", "a");
-            },
-            Instruction::LdHln(n) => {
-                let h16 = (self.h as u16) << 8;
-                let hl: u16 = h16 | (self.l as u16);
-                mmu.write_byte(hl, *n);
-                self.pc += 2;
-                self.t += 12;
-                self.m += 3;
-            }
-            Instruction::LdADe => {
-                if self.debug {
-                    println!(
-                        "LD,A,(DE) before, A: {:#X} D: {:#X}, E: {:#X}",
-                        self.a, self.d, self.e
-                    );
+    }
+
+    fn alu(&mut self, op: AluOp, value: u8) {
+        let a = self.a;
+        match op {
+            AluOp::Add | AluOp::Adc => {
+                let carry = (op == AluOp::Adc && self.get_c_flag()) as u8;
+                let result = a.wrapping_add(value).wrapping_add(carry);
+                let half_carry = (a & 0xF) + (value & 0xF) + carry > 0xF;
+                let full_carry = a as u16 + value as u16 + carry as u16 > 0xFF;
+                self.set_flags(result == 0, false, half_carry, full_carry);
+                self.a = result;
+            }
+            AluOp::Sub | AluOp::Sbc | AluOp::Cp => {
+                let carry = (op == AluOp::Sbc && self.get_c_flag()) as u8;
+                let result = a.wrapping_sub(value).wrapping_sub(carry);
+                let half_borrow = (a & 0xF) < (value & 0xF) + carry;
+                let full_borrow = (a as u16) < value as u16 + carry as u16;
+                self.set_flags(result == 0, true, half_borrow, full_borrow);
+                // CP is a SUB that throws the result away
+                if op != AluOp::Cp {
+                    self.a = result;
                 }
-                let d16 = (self.d as u16) << 8;
-                let de: u16 = d16 | (self.e as u16);
-                self.a = mmu.read_byte(de);
-                self.pc += 1;
-                self.t += 8;
-                self.m += 2;
-            }
-            Instruction::LdAb => {
-                self.do_ld_reg_to_reg("a", "b");
-            }
-            Instruction::LdAc => {
-                self.do_ld_reg_to_reg("a", "c");
             }
-            Instruction::LdAd => {
-                self.do_ld_reg_to_reg("a", "d");
+            AluOp::And => {
+                self.a = a & value;
+                self.set_flags(self.a == 0, false, true, false);
             }
-            Instruction::LdAe => {
-                self.do_ld_reg_to_reg("a", "e");
+            AluOp::Xor => {
+                self.a = a ^ value;
+                self.set_flags(self.a == 0, false, false, false);
             }
-            Instruction::LdAh => {
-                self.do_ld_reg_to_reg("a", "h");
+            AluOp::Or => {
+                self.a = a | value;
+                self.set_flags(self.a == 0, false, false, false);
             }
-            Instruction::LdAl => {
-                self.do_ld_reg_to_reg("a", "l");
-            }
-            Instruction::LdHlA => {
-                if self.debug {
-                    println!(
-                        "LD (HL) A before, A: {:#X} H: {:#X}, L: {:#X}",
-                        self.a, self.h, self.l
-                    );
-                }
-                let h16 = (self.h as u16) << 8;
-                let hl: u16 = h16 | (self.l as u16);
-                mmu.write_byte(hl, self.a);
-                self.pc += 1;
-                self.t += 8;
-                self.m += 2;
+        }
+    }
+
+    fn rotate(&mut self, op: RotOp, value: u8) -> u8 {
+        let carry_in = self.get_c_flag() as u8;
+        let (result, carry_out) = match op {
+            RotOp::Rlc => (value.rotate_left(1), (value & 0b1000_0000) != 0),
+            RotOp::Rrc => (value.rotate_right(1), (value & 0b0000_0001) != 0),
+            RotOp::Rl => ((value << 1) | carry_in, (value & 0b1000_0000) != 0),
+            RotOp::Rr => ((value >> 1) | (carry_in << 7), (value & 0b0000_0001) != 0),
+            RotOp::Sla => (value << 1, (value & 0b1000_0000) != 0),
+            // bit 7 stays, it is the sign
+            RotOp::Sra => (
+                (value >> 1) | (value & 0b1000_0000),
+                (value & 0b0000_0001) != 0,
+            ),
+            RotOp::Swap => (value.rotate_left(4), false),
+            RotOp::Srl => (value >> 1, (value & 0b0000_0001) != 0),
+        };
+        self.set_flags(result == 0, false, false, carry_out);
+        result
+    }
+
+    // ADD SP,e and LD HL,SP+e: the flags come from the low byte, like an
+    // 8 bit add of the unsigned offset
+    fn add_sp_offset(&mut self, offset: i8) -> u16 {
+        let sp = self.sp;
+        let offset = offset as i16 as u16;
+        let half_carry = (sp & 0x000F) + (offset & 0x000F) > 0x000F;
+        let full_carry = (sp & 0x00FF) + (offset & 0x00FF) > 0x00FF;
+        self.set_flags(false, false, half_carry, full_carry);
+        sp.wrapping_add(offset)
+    }
+
+    fn daa(&mut self) {
+        let mut a = self.a;
+        let mut carry = self.get_c_flag();
+        if !self.get_n_flag() {
+            // after an addition
+            if carry || a > 0x99 {
+                a = a.wrapping_add(0x60);
+                carry = true;
+            }
+            if self.get_h_flag() || (a & 0x0F) > 0x09 {
+                a = a.wrapping_add(0x06);
             }
-            Instruction::LdXxA(d16) => {
-                if self.debug { println!("LD (XX),A xx: {:#X}", d16); }
-                mmu.write_byte(*d16, self.a);
-                self.pc += 3;
-                self.t += 16;
-                self.m += 4;
+        } else {
+            // after a subtraction
+            if carry {
+                a = a.wrapping_sub(0x60);
             }
-            Instruction::LddHlA => {
-                if self.debug {
-                    println!(
-                        "LD (HL-) A before, A: {:#X} H: {:#X}, L: {:#X}",
-                        self.a, self.h, self.l
-                    );
-                }
-                let h16 = (self.h as u16) << 8;
-                let mut hl: u16 = h16 | (self.l as u16);
-                mmu.write_byte(hl, self.a);
-                hl = hl.wrapping_sub(1);
-                self.h = ((hl & 0xFF00) >> 8) as u8;
-                self.l = (hl & 0x00FF) as u8;
-                self.pc += 1;
-                self.t += 8;
-                self.m += 2;
-                if self.debug {
-                    println!(
-                        "LD (HL-) A after, A: {:#X} H: {:#X}, L: {:#X}",
-                        self.a, self.h, self.l
-                    );
-                }
+            if self.get_h_flag() {
+                a = a.wrapping_sub(0x06);
             }
-            Instruction::LdiHlA => {
-                if self.debug { println!("LD (HL+)"); }
-                let h16 = (self.h as u16) << 8;
-                let mut hl: u16 = h16 | (self.l as u16);
-                mmu.write_byte(hl, self.a);
-                hl = hl.wrapping_add(1);
-                self.h = ((hl & 0xFF00) >> 8) as u8;
-                self.l = (hl & 0x00FF) as u8;
-                self.pc += 1;
-                self.t += 8;
-                self.m += 2;
-            },
-            Instruction::LdiAHl => {
-                if self.debug { println!("LD A, (HL+)"); }
-                let h16 = (self.h as u16) << 8;
-                let mut hl: u16 = h16 | (self.l as u16);
-                self.a = mmu.read_byte(hl);
-                hl = hl.wrapping_add(1);
-                self.h = ((hl & 0xFF00) >> 8) as u8;
-                self.l = (hl & 0x00FF) as u8;
-                self.pc += 1;
-                self.t += 8;
-                self.m += 2;
-            },
-            Instruction::LdFf00U8a(n) => {
-                if self.debug {
-                    println!("LD (FF00+u8) A n(u8): {:#X}", n);
+        }
+        self.a = a;
+        let n = self.get_n_flag();
+        self.set_flags(a == 0, n, false, carry);
+    }
+
+    // Runs one instruction, PC already points past it. Returns true when a
+    // conditional jump, call or return was taken.
+    fn execute(&mut self, instruction: Instruction, mmu: &mut MMU, ppu: &mut PPU) -> bool {
+        match instruction {
+            Instruction::Nop => {}
+            Instruction::Stop => {
+                // on CGB this is how the speed switch happens (KEY1)
+                let stall = mmu.switch_speed();
+                self.tick(stall);
+            }
+            Instruction::Halt => {
+                let pending = mmu.read_byte(IE_ADDR) & mmu.read_byte(IF_ADDR) & INTERRUPT_BITS;
+                if !self.ime && pending != 0 {
+                    self.halt_bug = true;
+                } else {
+                    self.halted = true;
                 }
-                let addr: u16 = 0xFF00 + *n as u16;
-                mmu.write_byte(addr, self.a);
-                self.pc += 2;
-                self.t += 12;
-                self.m += 3;
             }
-            Instruction::LdAFf00U8(n) => {
-                if self.debug {
-                    println!("LD A (FF00+u8) n(u8): {:#X}", n);
+            Instruction::Di => {
+                self.ime = false;
+                self.ime_scheduled = false;
+            }
+            Instruction::Ei => self.ime_scheduled = true,
+            Instruction::Ld(to, from) => self.set_register(to, self.get_register(from)),
+            Instruction::LdImmediate(register, n) => self.set_register(register, n),
+            Instruction::LdFromHl(register) => {
+                let value = self.read(mmu, ppu, self.get_register_pair(Reg16::Hl));
+                self.set_register(register, value);
+            }
+            Instruction::LdToHl(register) => self.write(
+                mmu,
+                ppu,
+                self.get_register_pair(Reg16::Hl),
+                self.get_register(register),
+            ),
+            Instruction::LdHlImmediate(n) => {
+                self.write(mmu, ppu, self.get_register_pair(Reg16::Hl), n)
+            }
+            Instruction::LdAFrom(address) => {
+                let address = self.resolve_address(address);
+                self.a = self.read(mmu, ppu, address);
+            }
+            Instruction::LdATo(address) => {
+                let address = self.resolve_address(address);
+                self.write(mmu, ppu, address, self.a);
+            }
+            Instruction::Ld16(register, nn) => self.set_register_pair(register, nn),
+            Instruction::LdAddressSp(nn) => {
+                self.write(mmu, ppu, nn, (self.sp & 0x00FF) as u8);
+                self.write(mmu, ppu, nn.wrapping_add(1), (self.sp >> 8) as u8);
+            }
+            Instruction::LdSpHl => self.sp = self.get_register_pair(Reg16::Hl),
+            Instruction::LdHlSpOffset(offset) => {
+                let value = self.add_sp_offset(offset);
+                self.set_register_pair(Reg16::Hl, value);
+            }
+            Instruction::Push(register) => {
+                self.internal_cycle(mmu, ppu);
+                let value = self.get_register_pair(register);
+                self.push_to_stack(mmu, ppu, value);
+            }
+            Instruction::Pop(register) => {
+                let value = self.pop_from_stack(mmu, ppu);
+                self.set_register_pair(register, value);
+            }
+            Instruction::Alu(op, operand) => {
+                let value = self.read_operand(mmu, ppu, operand);
+                self.alu(op, value);
+            }
+            Instruction::Inc(operand) => {
+                let value = self.read_operand(mmu, ppu, operand);
+                let result = value.wrapping_add(1);
+                let c = self.get_c_flag();
+                self.set_flags(result == 0, false, (value & 0x0F) == 0x0F, c);
+                self.write_operand(mmu, ppu, operand, result);
+            }
+            Instruction::Dec(operand) => {
+                let value = self.read_operand(mmu, ppu, operand);
+                let result = value.wrapping_sub(1);
+                let c = self.get_c_flag();
+                self.set_flags(result == 0, true, (value & 0x0F) == 0x00, c);
+                self.write_operand(mmu, ppu, operand, result);
+            }
+            Instruction::Inc16(register) => {
+                let value = self.get_register_pair(register).wrapping_add(1);
+                self.set_register_pair(register, value);
+            }
+            Instruction::Dec16(register) => {
+                let value = self.get_register_pair(register).wrapping_sub(1);
+                self.set_register_pair(register, value);
+            }
+            Instruction::AddHl(register) => {
+                let hl = self.get_register_pair(Reg16::Hl);
+                let value = self.get_register_pair(register);
+                let z = self.get_z_flag();
+                let half_carry = (hl & 0x0FFF) + (value & 0x0FFF) > 0x0FFF;
+                let full_carry = hl as u32 + value as u32 > 0xFFFF;
+                self.set_flags(z, false, half_carry, full_carry);
+                self.set_register_pair(Reg16::Hl, hl.wrapping_add(value));
+            }
+            Instruction::AddSp(offset) => self.sp = self.add_sp_offset(offset),
+            // the accumulator rotates always clear Z
+            Instruction::Rlca => {
+                self.a = self.rotate(RotOp::Rlc, self.a);
+                self.set_flag(Z_FLAG, false);
+            }
+            Instruction::Rrca => {
+                self.a = self.rotate(RotOp::Rrc, self.a);
+                self.set_flag(Z_FLAG, false);
+            }
+            Instruction::Rla => {
+                self.a = self.rotate(RotOp::Rl, self.a);
+                self.set_flag(Z_FLAG, false);
+            }
+            Instruction::Rra => {
+                self.a = self.rotate(RotOp::Rr, self.a);
+                self.set_flag(Z_FLAG, false);
+            }
+            Instruction::Daa => self.daa(),
+            Instruction::Cpl => {
+                self.a = !self.a;
+                self.set_flag(N_FLAG, true);
+                self.set_flag(H_FLAG, true);
+            }
+            Instruction::Scf => {
+                let z = self.get_z_flag();
+                self.set_flags(z, false, false, true);
+            }
+            Instruction::Ccf => {
+                let z = self.get_z_flag();
+                let c = self.get_c_flag();
+                self.set_flags(z, false, false, !c);
+            }
+            Instruction::Jp(condition, nn) => {
+                if !self.check_condition(condition) {
+                    return false;
                 }
-                let addr: u16 = 0xFF00 + *n as u16;
-                self.a = mmu.read_byte(addr);
-                self.pc += 2;
-                self.t += 12;
-                self.m += 3;
+                self.pc = nn;
             }
-            Instruction::LdFf00Ca => {
-                if self.debug {
-                    println!("LD (C) A");
+            Instruction::JpHl => self.pc = self.get_register_pair(Reg16::Hl),
+            Instruction::Jr(condition, offset) => {
+                if !self.check_condition(condition) {
+                    return false;
                 }
-                let addr: u16 = 0xFF00 + self.c as u16;
-                mmu.write_byte(addr, self.a);
-                self.pc += 1;
-                self.t += 8;
-                self.m += 2;
+                self.pc = self.pc.wrapping_add(offset as i16 as u16);
             }
-            Instruction::XorA => {
-                if self.debug {
-                    println!("XorA, before A: {:#X}, Z: {:?}", self.a, self.get_z_flag());
-                }
-                self.a ^= self.a;
-                if self.a == 0 {
-                    self.set_z_flag();
+            Instruction::Call(condition, nn) => {
+                if !self.check_condition(condition) {
+                    return false;
                 }
-                if self.debug {
-                    println!("XorA, after A: {:#X}, Z: {:?}", self.a, self.get_z_flag());
+                self.internal_cycle(mmu, ppu);
+                self.push_to_stack(mmu, ppu, self.pc);
+                self.pc = nn;
+            }
+            Instruction::Ret(condition) => {
+                // checking the condition takes a cycle of its own
+                if condition.is_some() {
+                    self.internal_cycle(mmu, ppu);
                 }
-                self.pc += 1;
-                self.t += 4;
-                self.m += 1;
-            }
-            Instruction::BitbA(bit_mask) => {
-                if self.debug {
-                    println!("Bit b,A b: {:b}", bit_mask);
+                if !self.check_condition(condition) {
+                    return false;
                 }
-                let bit_test: u8 = self.a & *bit_mask;
-                self.do_bit_opcode(*bit_mask != bit_test);
-                self.t += 8;
-                self.m += 2;
+                self.pc = self.pop_from_stack(mmu, ppu);
             }
-            Instruction::BitbB(bit_mask) => {
-                if self.debug {
-                    println!("Bit b,B b: {:b}", bit_mask);
-                }
-                let bit_test: u8 = self.b & *bit_mask;
-                self.do_bit_opcode(*bit_mask != bit_test);
-                self.t += 8;
-                self.m += 2;
+            Instruction::Reti => {
+                self.pc = self.pop_from_stack(mmu, ppu);
+                self.ime = true;
             }
-            Instruction::BitbC(bit_mask) => {
-                if self.debug {
-                    println!("Bit b,C b: {:b}", bit_mask);
-                }
-                let bit_test: u8 = self.c & *bit_mask;
-                self.do_bit_opcode(*bit_mask != bit_test);
-                self.t += 8;
-                self.m += 2;
+            Instruction::Rst(vector) => {
+                self.internal_cycle(mmu, ppu);
+                self.push_to_stack(mmu, ppu, self.pc);
+                self.pc = vector;
             }
-            Instruction::BitbD(bit_mask) => {
-                if self.debug {
-                    println!("Bit b,D b: {:b}", bit_mask);
-                }
-                let bit_test: u8 = self.d & *bit_mask;
-                self.do_bit_opcode(*bit_mask != bit_test);
-                self.t += 8;
-                self.m += 2;
+            Instruction::Rot(op, operand) => {
+                let value = self.read_operand(mmu, ppu, operand);
+                let result = self.rotate(op, value);
+                self.write_operand(mmu, ppu, operand, result);
             }
-            Instruction::BitbE(bit_mask) => {
-                if self.debug {
-                    println!("Bit b,E b: {:b}", bit_mask);
-                }
-                let bit_test: u8 = self.e & *bit_mask;
-                self.do_bit_opcode(*bit_mask != bit_test);
-                self.t += 8;
-                self.m += 2;
+            Instruction::Bit(bit, operand) => {
+                let value = self.read_operand(mmu, ppu, operand);
+                let c = self.get_c_flag();
+                self.set_flags((value >> bit) & 0b1 == 0, false, true, c);
             }
-            Instruction::BitbH(bit_mask) => {
-                if self.debug {
-                    println!(
-                        "BIT n,H - before, b: {:b}, H: {:#X}, Z: {:?}, N: {:?}, H(bit): {:?}, C: {:?}",
-                        bit_mask,
-                        self.h,
-                        self.get_z_flag(),
-                        self.get_n_flag(),
-                        self.get_h_flag(),
-                        self.get_c_flag()
-                    );
-                }
-                let bit_test: u8 = self.h & *bit_mask;
-                self.do_bit_opcode(*bit_mask != bit_test);
-                if self.debug {
-                    println!(
-                        "BIT n,H - after, b: {:b}, H: {:#X}, Z: {:?}, N: {:?}, H(bit): {:?}, C: {:?}",
-                        bit_mask,
-                        self.h,
-                        self.get_z_flag(),
-                        self.get_n_flag(),
-                        self.get_h_flag(),
-                        self.get_c_flag()
-                    );
-                }
-                self.t += 8;
-                self.m += 2;
+            Instruction::Res(bit, operand) => {
+                let value = self.read_operand(mmu, ppu, operand);
+                self.write_operand(mmu, ppu, operand, value & !(1 << bit));
             }
-            Instruction::BitbL(bit_mask) => {
-                if self.debug {
-                    println!("Bit b,L b: {:b}", bit_mask);
-                }
-                let bit_test: u8 = self.l & *bit_mask;
-                self.do_bit_opcode(*bit_mask != bit_test);
-                self.t += 8;
-                self.m += 2;
+            Instruction::Set(bit, operand) => {
+                let value = self.read_operand(mmu, ppu, operand);
+                self.write_operand(mmu, ppu, operand, value | (1 << bit));
             }
-            Instruction::JrNz(n) => {
-                if self.debug {
-                    println!("JR NZ n: {:#X}", n);
+            Instruction::Illegal(_) => unreachable!("illegal opcodes never get executed"),
+        }
+        true
+    }
+
+    // Jumps to the highest priority interrupt that is enabled and requested.
+    // Returns true when it did, which takes the place of an instruction.
+    fn handle_interrupts(&mut self, mmu: &mut MMU, ppu: &mut PPU) -> bool {
+        let requested = mmu.read_byte(IF_ADDR);
+        let pending = mmu.read_byte(IE_ADDR) & requested & INTERRUPT_BITS;
+        if pending == 0 {
+            return false;
+        }
+        // any pending interrupt ends HALT, even with IME off
+        self.halted = false;
+        if !self.ime {
+            return false;
+        }
+        self.ime = false;
+        let bit = pending.trailing_zeros() as u16;
+        mmu.write_byte(IF_ADDR, requested & !(1 << bit));
+        self.internal_cycle(mmu, ppu);
+        self.internal_cycle(mmu, ppu);
+        self.push_to_stack(mmu, ppu, self.pc);
+        self.pc = 0x0040 + bit * 8;
+        self.tick(INTERRUPT_CLOCKS);
+        true
+    }
+
+    // Fetches, decodes and executes the instruction at PC
+    fn step(&mut self, mmu: &mut MMU, ppu: &mut PPU) -> Result<(), EmuError> {
+        // EI before this instruction turns interrupts on after it
+        if self.ime_scheduled {
+            self.ime_scheduled = false;
+            self.ime = true;
+        }
+        let pc = self.pc;
+        let opcode = self.read(mmu, ppu, pc);
+        self.last_opcode = Some(opcode);
+        // with the HALT bug PC doesn't move past the opcode
+        let operands_at = if self.halt_bug {
+            pc
+        } else {
+            pc.wrapping_add(1)
+        };
+        self.halt_bug = false;
+        let (instruction, info) = if opcode == 0xCB {
+            let cb_opcode = self.read(mmu, ppu, operands_at);
+            (
+                Instruction::decode_cb(cb_opcode),
+                &CB_OPCODES[cb_opcode as usize],
+            )
+        } else {
+            let info = &OPCODES[opcode as usize];
+            // only read the operand bytes this instruction has. STOP skips the
+            // byte after it without reading it.
+            let low = if info.length > 1 && opcode != 0x10 {
+                self.read(mmu, ppu, operands_at)
+            } else {
+                0
+            };
+            let high = if info.length > 2 {
+                self.read(mmu, ppu, operands_at.wrapping_add(1))
+            } else {
+                0
+            };
+            (Instruction::decode(opcode, low, high), info)
+        };
+        if self.debug {
+            println!("{:#06X}: {:<12} {:?}", pc, info.mnemonic, instruction);
+        }
+        if let Instruction::Illegal(opcode) = instruction {
+            self.locked = true;
+            return Err(EmuError::IllegalOpcode { opcode, pc });
+        }
+        self.pc = operands_at.wrapping_add(info.length as u16 - 1);
+
+        let taken = self.execute(instruction, mmu, ppu);
+        let clocks = if taken {
+            info.cycles_taken
+        } else {
+            info.cycles
+        };
+        self.tick(clocks as usize);
+        Ok(())
+    }
+
+    pub fn run_instruction(&mut self, mmu: &mut MMU, ppu: &mut PPU) -> Result<usize, EmuError> {
+        self.last_m = self.m;
+        self.last_t = self.t;
+        self.system_clocks = 0;
+        self.last_opcode = None;
+
+        if self.locked {
+            // the rest of the console keeps going while the CPU is stuck
+            self.tick(4);
+        } else if self.handle_interrupts(mmu, ppu) {
+            // jumping to the handler took the place of an instruction
+        } else if self.halted {
+            self.tick(4);
+        } else {
+            self.step(mmu, ppu)?;
+        }
+        // a VRAM DMA started by this instruction (or the last HBlank) halts us
+        let dma_stall_clocks = mmu.take_dma_stall_clocks();
+        self.tick(dma_stall_clocks);
+
+        let mut current_instruction_t_clocks_passed = self.t - self.last_t;
+        // whatever the memory accesses didn't run yet
+        let clocks_left = current_instruction_t_clocks_passed - self.system_clocks;
+        self.tick_system(mmu, ppu, clocks_left);
+        // in double speed mode the PPU keeps its pace while we run twice as fast
+        if mmu.is_double_speed() {
+            current_instruction_t_clocks_passed /= 2;
+        }
+        Ok(current_instruction_t_clocks_passed)
+    }
+
+    pub fn set_timing(&mut self, timing: Timing) {
+        self.timing = timing;
+    }
+
+    pub fn get_timing(&self) -> Timing {
+        self.timing
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.locked
+    }
+
+    pub fn is_halted(&self) -> bool {
+        self.halted
+    }
+
+    // The opcode of the instruction run_instruction just ran, 0xCB for the
+    // CB prefixed ones
+    pub fn get_last_opcode(&self) -> Option<u8> {
+        self.last_opcode
+    }
+
+    pub fn get_ime(&self) -> bool {
+        self.ime
+    }
+
+    pub fn set_ime(&mut self, ime: bool) {
+        self.ime = ime;
+    }
+
+    // From now on every read and write is kept until taken
+    pub fn record_bus_accesses(&mut self) {
+        self.bus_accesses = Some(Vec::new());
+    }
+
+    pub fn take_bus_accesses(&mut self) -> Vec<BusAccess> {
+        match &mut self.bus_accesses {
+            Some(bus_accesses) => std::mem::take(bus_accesses),
+            None => Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Programs run from work RAM, with HL, BC and DE pointing at work RAM
+    // and the stack holding a return address
+    const START: u16 = 0xC000;
+    const RETURN_ADDRESS: u16 = 0xC220;
+
+    fn setup(program: &[u8]) -> (CPU, MMU, PPU) {
+        setup_in(MMU::new(), program)
+    }
+
+    // For the tests that need RAM at $0000, where the cartridge ROM is
+    fn setup_flat(program: &[u8]) -> (CPU, MMU, PPU) {
+        setup_in(MMU::new_flat(), program)
+    }
+
+    fn setup_in(mut mmu: MMU, program: &[u8]) -> (CPU, MMU, PPU) {
+        // take the boot ROM off $0000
+        mmu.write_byte(0xFF50, 0x01);
+        for (i, &byte) in program.iter().enumerate() {
+            mmu.write_byte(START.wrapping_add(i as u16), byte);
+        }
+        mmu.write_byte(0xD000, (RETURN_ADDRESS & 0x00FF) as u8);
+        mmu.write_byte(0xD001, (RETURN_ADDRESS >> 8) as u8);
+        let mut cpu = CPU::new();
+        cpu.pc = START;
+        cpu.sp = 0xD000;
+        cpu.set_register_pair(Reg16::Bc, 0xC180);
+        cpu.set_register_pair(Reg16::De, 0xC190);
+        cpu.set_register_pair(Reg16::Hl, 0xC1A0);
+        (cpu, mmu, PPU::new())
+    }
+
+    fn run(cpu: &mut CPU, mmu: &mut MMU, ppu: &mut PPU) -> usize {
+        cpu.run_instruction(mmu, ppu).unwrap()
+    }
+
+    // M-cycles of every opcode, the branches when not taken
+    #[rustfmt::skip]
+    const CYCLES: [u8; 256] = [
+        1, 3, 2, 2, 1, 1, 2, 1, 5, 2, 2, 2, 1, 1, 2, 1,
+        1, 3, 2, 2, 1, 1, 2, 1, 3, 2, 2, 2, 1, 1, 2, 1,
+        2, 3, 2, 2, 1, 1, 2, 1, 2, 2, 2, 2, 1, 1, 2, 1,
+        2, 3, 2, 2, 3, 3, 3, 1, 2, 2, 2, 2, 1, 1, 2, 1,
+        1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1,
+        1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1,
+        1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1,
+        2, 2, 2, 2, 2, 2, 1, 2, 1, 1, 1, 1, 1, 1, 2, 1,
+        1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1,
+        1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1,
+        1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1,
+        1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1,
+        2, 3, 3, 4, 3, 4, 2, 4, 2, 4, 3, 0, 3, 6, 2, 4,
+        2, 3, 3, 0, 3, 4, 2, 4, 2, 4, 3, 0, 3, 0, 2, 4,
+        3, 3, 2, 0, 0, 4, 2, 4, 4, 1, 4, 0, 0, 0, 2, 4,
+        3, 3, 2, 1, 0, 4, 2, 4, 3, 2, 4, 1, 0, 0, 2, 4,
+    ];
+
+    #[rustfmt::skip]
+    const LENGTHS: [u8; 256] = [
+        1, 3, 1, 1, 1, 1, 2, 1, 3, 1, 1, 1, 1, 1, 2, 1,
+        2, 3, 1, 1, 1, 1, 2, 1, 2, 1, 1, 1, 1, 1, 2, 1,
+        2, 3, 1, 1, 1, 1, 2, 1, 2, 1, 1, 1, 1, 1, 2, 1,
+        2, 3, 1, 1, 1, 1, 2, 1, 2, 1, 1, 1, 1, 1, 2, 1,
+        1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
+        1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
+        1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
+        1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
+        1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
+        1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
+        1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
+        1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
+        1, 1, 3, 3, 3, 1, 2, 1, 1, 1, 3, 2, 3, 3, 2, 1,
+        1, 1, 3, 0, 3, 1, 2, 1, 1, 1, 3, 0, 3, 0, 2, 1,
+        2, 1, 1, 0, 0, 1, 2, 1, 2, 1, 3, 0, 0, 0, 2, 1,
+        2, 1, 1, 1, 0, 1, 2, 1, 2, 1, 3, 1, 0, 0, 2, 1,
+    ];
+
+    // M-cycles of the conditional branches when they are taken
+    fn taken_cycles(opcode: u8) -> Option<u8> {
+        match opcode {
+            0x20 | 0x28 | 0x30 | 0x38 => Some(3),
+            0xC0 | 0xC8 | 0xD0 | 0xD8 => Some(5),
+            0xC2 | 0xCA | 0xD2 | 0xDA => Some(4),
+            0xC4 | 0xCC | 0xD4 | 0xDC => Some(6),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn every_opcode_takes_its_cycles_and_length() {
+        for &timing in [Timing::Instruction, Timing::MCycle].iter() {
+            for opcode in 0..=255u8 {
+                if LENGTHS[opcode as usize] == 0 || opcode == 0xCB {
+                    continue;
                 }
-                self.pc = self.pc + 2;
-                if self.debug {
-                    println!(
-                        "JR NZ, n - before, n: {:#X}, Z: {:?}, PC: {:#X}",
-                        n,
-                        self.get_z_flag(),
-                        self.pc
+                // with both flag settings every conditional branch is taken
+                // once and skipped once
+                for &flags in [0x00, 0xF0].iter() {
+                    // jumps land on $C210 or 16 bytes ahead, never right after
+                    let (mut cpu, mut mmu, mut ppu) = setup(&[opcode, 0x10, 0xC2]);
+                    cpu.set_timing(timing);
+                    cpu.set_register(Reg8::F, flags);
+                    let clocks = run(&mut cpu, &mut mmu, &mut ppu);
+
+                    let next = START + LENGTHS[opcode as usize] as u16;
+                    let expected = match taken_cycles(opcode) {
+                        Some(taken) if cpu.pc != next => taken,
+                        _ => CYCLES[opcode as usize],
+                    };
+                    assert_eq!(
+                        clocks,
+                        expected as usize * 4,
+                        "opcode {:#04X} flags {:#04X} {:?}",
+                        opcode,
+                        flags,
+                        timing
                     );
-                }
-                if !self.get_z_flag() {
-                    self.pc = self.pc.wrapping_add(*n as u16);
-                    self.t += 12;
-                    self.m += 3;
-                } else {
-                    self.t += 8;
-                    self.m += 2;
-                }
-                if self.debug {
-                    println!(
-                        "JR NZ, n - before, n: {:#X}, Z: {:?}, PC: {:#X}",
-                        n,
-                        self.get_z_flag(),
-                        self.pc
+                    let info = &OPCODES[opcode as usize];
+                    assert_eq!(
+                        info.length, LENGTHS[opcode as usize],
+                        "opcode {:#04X}",
+                        opcode
                     );
                 }
             }
-            Instruction::JrZ(n) => {
-                if self.debug {
-                    println!("JR Z n: {:#X}", n);
-                }
-                self.pc = self.pc + 2;
-                if self.get_z_flag() {
-                    self.pc = self.pc.wrapping_add(*n as u16);
-                    self.t += 12;
-                    self.m += 3;
-                } else {
-                    self.t += 8;
-                    self.m += 2;
-                }
-            }
-            Instruction::JrNc(n) => {
-                if self.debug {
-                    println!("JR NC n: {:#X}", n);
-                }
-                self.pc = self.pc + 2;
-                if !self.get_c_flag() {
-                    self.pc = self.pc.wrapping_add(*n as u16);
-                    self.t += 12;
-                    self.m += 3;
-                } else {
-                    self.t += 8;
-                    self.m += 2;
-                }
-            }
-            Instruction::JrC(n) => {
-                if self.debug {
-                    println!("JR C n: {:#X}", n);
-                }
-                self.pc = self.pc + 2;
-                if self.get_c_flag() {
-                    self.pc = self.pc.wrapping_add(*n as u16);
-                    self.t += 12;
-                    self.m += 3;
-                } else {
-                    self.t += 8;
-                    self.m += 2;
-                }
-            }
-            Instruction::Jr(n) => {
-                if self.debug { println!("JR n: {:#X}", n); }
-                self.pc = self.pc + 2;
-                self.pc = self.pc.wrapping_add(*n as u16);
-                self.t += 12;
-                self.m += 3;
-            }
-            Instruction::Jp(d16) => {
-                if self.debug { println!("JP nn: {:#X}", d16); }
-                self.pc = *d16;
-                self.t += 16;
-                self.m += 4;
-            }
-            Instruction::IncA => {
-                if self.debug { println!("INC A"); }
-                self.a = self.do_inc_n(self.a);
-            }
-            Instruction::IncB => {
-                if self.debug { println!("INC B"); }
-                self.b = self.do_inc_n(self.b);
-            }
-            Instruction::IncC => {
-                if self.debug { println!("INC C"); }
-                self.c = self.do_inc_n(self.c);
-            }
-            Instruction::IncD => {
-                if self.debug { println!("INC D"); }
-                self.d = self.do_inc_n(self.d);
-            }
-            Instruction::IncE => {
-                if self.debug { println!("INC E") };
-                self.e = self.do_inc_n(self.e);
-            }
-            Instruction::IncH => {
-                if self.debug { println!("INC H") };
-                self.h = self.do_inc_n(self.h);
-            }
-            Instruction::IncL => {
-                if self.debug { println!("INC L") };
-                self.l = self.do_inc_n(self.l);
-            }
-            Instruction::IncHl => {
-                if self.debug { println!("INC (HL)") };
-                let h16 = (self.h as u16) << 8;
-                let mut hl: u16 = h16 | (self.l as u16);
-                hl = self.do_inc_d16(hl);
-                self.h = ((hl & 0xFF00) >> 8) as u8;
-                self.l = (hl & 0x00FF) as u8;
-            }
-            Instruction::IncHlNoflags => {
-                if self.debug { println!("INC HL") };
-                let h16 = (self.h as u16) << 8;
-                let mut hl: u16 = h16 | (self.l as u16);
-                hl = hl.wrapping_add(1);
-                self.h = ((hl & 0xFF00) >> 8) as u8;
-                self.l = (hl & 0x00FF) as u8;
-                self.pc += 1;
-                self.t += 8;
-                self.m += 2;
-            }
-            Instruction::IncBc => {
-                if self.debug { println!("INC BC") };
-                let b16 = (self.b as u16) << 8;
-                let mut bc: u16 = b16 | (self.c as u16);
-                bc = bc.wrapping_add(1);
-                self.b = ((bc & 0xFF00) >> 8) as u8;
-                self.c = (bc & 0x00FF) as u8;
-                self.pc += 1;
-                self.t += 8;
-                self.m += 2;
-            }
-            Instruction::IncDe => {
-                if self.debug { println!("INC DE") };
-                let d16 = (self.d as u16) << 8;
-                let mut de: u16 = d16 | (self.e as u16);
-                de = de.wrapping_add(1);
-                self.d = ((de & 0xFF00) >> 8) as u8;
-                self.e = (de & 0x00FF) as u8;
-                self.pc += 1;
-                self.t += 8;
-                self.m += 2;
-            }
-            Instruction::DecA => {
-                if self.debug { println!("DEC A") };
-                self.a = self.do_dec_n(self.a);
-            }
-            Instruction::DecB => {
-                if self.debug { println!("DEC B") };
-                self.b = self.do_dec_n(self.b);
-            }
-            Instruction::DecC => {
-                if self.debug { println!("DEC C") };
-                self.c = self.do_dec_n(self.c);
-            }
-            Instruction::DecD => {
-                if self.debug {
-                    println!("DEC D")
+        }
+    }
+
+    #[test]
+    fn every_cb_opcode_takes_its_cycles() {
+        for &timing in [Timing::Instruction, Timing::MCycle].iter() {
+            for opcode in 0..=255u8 {
+                let (mut cpu, mut mmu, mut ppu) = setup(&[0xCB, opcode]);
+                cpu.set_timing(timing);
+                let clocks = run(&mut cpu, &mut mmu, &mut ppu);
+                let expected = match (opcode & 0b111, opcode >> 6) {
+                    // BIT n,(HL) only reads
+                    (6, 1) => 3,
+                    (6, _) => 4,
+                    _ => 2,
                 };
-                self.d = self.do_dec_n(self.d);
-            }
-            Instruction::DecE => {
-                if self.debug { println!("DEC E") };
-                self.e = self.do_dec_n(self.e);
-            }
-            Instruction::DecH => {
-                if self.debug { println!("DEC H") };
-                self.h = self.do_dec_n(self.h);
-            }
-            Instruction::DecL => {
-                if self.debug { println!("DEC L") };
-                self.l = self.do_dec_n(self.l);
-            }
-            Instruction::SubA => {
-                if self.debug { println!("SUB A") };
-                self.a = self.do_sub(self.a, self.a);
-            }
-            Instruction::SubB => {
-                if self.debug { println!("SUB B") };
-                self.a = self.do_sub(self.a, self.b);
-            }
-            Instruction::SubC => {
-                if self.debug { println!("SUB C") };
-                self.a = self.do_sub(self.a, self.c);
-            }
-            Instruction::SubD => {
-                if self.debug { println!("SUB D") };
-                self.a = self.do_sub(self.a, self.d);
-            }
-            Instruction::SubE => {
-                if self.debug { println!("SUB E") };
-                self.a = self.do_sub(self.a, self.e);
-            }
-            Instruction::SubH => {
-                if self.debug { println!("SUB H") };
-                self.a = self.do_sub(self.a, self.h);
-            }
-            Instruction::SubL => {
-                if self.debug { println!("SUB L") };
-                self.a = self.do_sub(self.a, self.l);
-            }
-            Instruction::AddAa => {
-                if self.debug { println!("Add A, A") };
-                self.a = self.do_add(self.a, self.a);
-            }
-            Instruction::AddAb => {
-                if self.debug { println!("Add A, B") };
-                self.a = self.do_add(self.a, self.b);
-            }
-            Instruction::AddAc => {
-                if self.debug { println!("Add A, C") };
-                self.a = self.do_add(self.a, self.c);
-            }
-            Instruction::AddAd => {
-                if self.debug { println!("Add A, D") };
-                self.a = self.do_add(self.a, self.d);
-            }
-            Instruction::AddAe => {
-                if self.debug { println!("Add A, E") };
-                self.a = self.do_add(self.a, self.e);
-            }
-            Instruction::AddAh => {
-                if self.debug { println!("Add A, H") };
-                self.a = self.do_add(self.a, self.h);
-            }
-            Instruction::AddAl => {
-                if self.debug { println!("Add A, l") };
-                self.a = self.do_add(self.a, self.l);
-            }
-            Instruction::AddAhl => {
-                if self.debug { println!("Add A, HL") };
-                let h16 = (self.h as u16) << 8;
-                let hl: u16 = h16 | (self.l as u16);
-                self.a = self.do_add(self.a, mmu.read_byte(hl));
+                assert_eq!(
+                    clocks,
+                    expected * 4,
+                    "opcode CB {:#04X} {:?}",
+                    opcode,
+                    timing
+                );
+                assert_eq!(cpu.pc, START + 2, "opcode CB {:#04X}", opcode);
             }
+        }
+    }
 
-            Instruction::Call(d16) => {
-                if self.debug { println!("Call d16: {:#X}", d16); }
-                self.pc += 3;
-                self.push_to_stack(mmu, self.pc);
-                self.pc = *d16;
-                self.t += 24;
-                self.m += 6;
-            }
-            Instruction::Ret => {
-                if self.debug { println!("RET"); }
-                self.pc = self.pop_from_stack(mmu);
-                self.t += 16;
-                self.m += 4;
-            }
-            Instruction::PushAf => {
-                if self.debug { println!("Push AF"); }
-                let a16 = (self.a as u16) << 8;
-                let af: u16 = a16 | (self.f as u16);
-                self.push_to_stack(mmu, af);
-                self.pc += 1;
-                self.t += 16;
-                self.m += 4;
-            }
-            Instruction::PushBc => {
-                if self.debug { println!("Push BC"); }
-                let b16 = (self.b as u16) << 8;
-                let bc: u16 = b16 | (self.c as u16);
-                self.push_to_stack(mmu, bc);
-                self.pc += 1;
-                self.t += 16;
-                self.m += 4;
-            }
-            Instruction::PushDe => {
-                if self.debug { println!("Push DE"); }
-                let d16 = (self.d as u16) << 8;
-                let de: u16 = d16 | (self.e as u16);
-                self.push_to_stack(mmu, de);
-                self.pc += 1;
-                self.t += 16;
-                self.m += 4;
-            }
-            Instruction::PushHl => {
-                if self.debug { println!("Push HL"); }
-                let h16 = (self.h as u16) << 8;
-                let hl: u16 = h16 | (self.l as u16);
-                self.push_to_stack(mmu, hl);
-                self.pc += 1;
-                self.t += 16;
-                self.m += 4;
-            }
-            Instruction::PopAf => {
-                if self.debug { println!("Pop AF"); }
-                let addr: u16 = self.pop_from_stack(mmu);
-                self.a = ((addr & 0xFF00) >> 8) as u8;
-                self.f = (addr & 0x00FF) as u8;
-                self.pc += 1;
-                self.t += 12;
-                self.m += 3;
-            }
-            Instruction::PopDe => {
-                if self.debug { println!("Pop DE"); }
-                let addr: u16 = self.pop_from_stack(mmu);
-                self.d = ((addr & 0xFF00) >> 8) as u8;
-                self.e = (addr & 0x00FF) as u8;
-                self.pc += 1;
-                self.t += 12;
-                self.m += 3;
-            }
-            Instruction::PopHl => {
-                if self.debug { println!("Pop HL"); }
-                let addr: u16 = self.pop_from_stack(mmu);
-                self.h = ((addr & 0xFF00) >> 8) as u8;
-                self.l = (addr & 0x00FF) as u8;
-                self.pc += 1;
-                self.t += 12;
-                self.m += 3;
-            }
-            Instruction::PopBc => {
-                if self.debug { println!("Pop BC"); }
-                let addr: u16 = self.pop_from_stack(mmu);
-                self.b = ((addr & 0xFF00) >> 8) as u8;
-                self.c = (addr & 0x00FF) as u8;
-                self.pc += 1;
-                self.t += 12;
-                self.m += 3;
-            }
-            Instruction::RlA => {
-                if self.debug { println!("RL A"); }
-                self.a = self.do_rl_n(self.a);
-            }
-            Instruction::RlB => {
-                if self.debug { println!("RL B"); }
-                self.b = self.do_rl_n(self.b);
-            }
-            Instruction::RlC => {
-                if self.debug { println!("RL C"); }
-                self.c = self.do_rl_n(self.c);
-            }
-            Instruction::RlD => {
-                if self.debug { println!("RL D"); }
-                self.d = self.do_rl_n(self.d);
-            }
-            Instruction::RlE => {
-                if self.debug { println!("RL E"); }
-                self.e = self.do_rl_n(self.e);
-            }
-            Instruction::RlH => {
-                if self.debug { println!("RL H"); }
-                self.h = self.do_rl_n(self.h);
-            }
-            Instruction::RlL => {
-                if self.debug { println!("RL L"); }
-                self.l = self.do_rl_n(self.l);
-            }
-            Instruction::RLA => {
-                if self.debug { println!("RLA"); }
-                self.a = self.do_rl_n(self.a);
-                self.pc -= 1;
-                self.t -= 4;
-                self.m -= 1;
-            }
-            Instruction::CpA => {
-                if self.debug { println!("CP A") };
-                let _ = self.do_sub(self.a, self.a);
-            }
-            Instruction::CpB => {
-                if self.debug { println!("CP B") };
-                let _ = self.do_sub(self.a, self.b);
-            }
-            Instruction::CpC => {
-                if self.debug { println!("CP C") };
-                let _ = self.do_sub(self.a, self.c);
-            }
-            Instruction::CpD => {
-                if self.debug { println!("CP D") };
-                let _ = self.do_sub(self.a, self.d);
-            }
-            Instruction::CpE => {
-                if self.debug { println!("CP E") };
-                let _ = self.do_sub(self.a, self.e);
-            }
-            Instruction::CpH => {
-                if self.debug { println!("CP H") };
-                let _ = self.do_sub(self.a, self.h);
-            }
-            Instruction::CpL => {
-                if self.debug { println!("CP L") };
-                let _ = self.do_sub(self.a, self.l);
-            }
-            Instruction::CpHl => {
-                if self.debug { println!("CP HL") };
-                let h16 = (self.h as u16) << 8;
-                let hl: u16 = h16 | (self.l as u16);
-                let _ = self.do_sub(self.a, mmu.read_byte(hl));
-                self.t += 4;
-                self.m += 1;
-            }
-            Instruction::Cp(n) => {
-                if self.debug { println!("CP n: {:#X}", n) };
-                let ly = mmu.read_byte(0xFF44);
-//                if ly > 143 {
-//                    println!("ly {:?}", ly);
-//                    println!("pc {:#X}", self.pc);
-//                    println!("a {:?}", self.a);
-//                    println!("n {:?}", *n);
-//                }
-                let _ = self.do_sub(self.a, *n);
-//                if ly > 139 {
-//                    println!("{:?}", self.get_z_flag());
-//                }
-                self.pc += 1;
-                self.t += 4;
-                self.m += 1;
-            }
-            _ => panic!(
-                "\n MEM STATE: {:?} \nCPU STATE: {:?}\nEXECUTING: Unreconized instruction {:?} on pc {:#X}",
-                mmu, self, instruction, self.pc
-            ),
+    #[test]
+    fn illegal_opcodes_lock_the_cpu() {
+        for &opcode in [
+            0xD3, 0xDB, 0xDD, 0xE3, 0xE4, 0xEB, 0xEC, 0xED, 0xF4, 0xFC, 0xFD,
+        ]
+        .iter()
+        {
+            let (mut cpu, mut mmu, mut ppu) = setup(&[opcode]);
+            let error = cpu.run_instruction(&mut mmu, &mut ppu).unwrap_err();
+            assert_eq!(error, EmuError::IllegalOpcode { opcode, pc: START });
+            assert!(cpu.is_locked());
+            // it stays put and only lets time pass
+            assert_eq!(run(&mut cpu, &mut mmu, &mut ppu), 4);
+            assert_eq!(cpu.pc, START);
         }
     }
 
-    pub fn run_instruction(&mut self, mmu: &mut MMU, ppu: &mut PPU) {
-        self.last_m = self.m;
-        self.last_t = self.t;
+    // Runs a single instruction with A and F set, returns A and F after it
+    fn alu(program: &[u8], a: u8, f: u8) -> (u8, u8) {
+        let (mut cpu, mut mmu, mut ppu) = setup(program);
+        cpu.set_register(Reg8::A, a);
+        cpu.set_register(Reg8::F, f);
+        run(&mut cpu, &mut mmu, &mut ppu);
+        (cpu.get_register(Reg8::A), cpu.get_register(Reg8::F))
+    }
+
+    #[test]
+    fn add_and_adc_flags() {
+        // ADD A,n
+        assert_eq!(alu(&[0xC6, 0x01], 0x0F, 0), (0x10, H_FLAG));
+        assert_eq!(
+            alu(&[0xC6, 0x01], 0xFF, 0),
+            (0x00, Z_FLAG | H_FLAG | C_FLAG)
+        );
+        assert_eq!(alu(&[0xC6, 0x10], 0xF0, 0), (0x00, Z_FLAG | C_FLAG));
+        // ADC A,n adds the carry in, which can make the half carry alone
+        assert_eq!(alu(&[0xCE, 0x00], 0x0F, C_FLAG), (0x10, H_FLAG));
+        assert_eq!(
+            alu(&[0xCE, 0xFF], 0x00, C_FLAG),
+            (0x00, Z_FLAG | H_FLAG | C_FLAG)
+        );
+    }
+
+    #[test]
+    fn sub_sbc_and_cp_flags() {
+        // SUB n
+        assert_eq!(alu(&[0xD6, 0x01], 0x10, 0), (0x0F, N_FLAG | H_FLAG));
+        assert_eq!(
+            alu(&[0xD6, 0x01], 0x00, 0),
+            (0xFF, N_FLAG | H_FLAG | C_FLAG)
+        );
+        assert_eq!(alu(&[0xD6, 0x42], 0x42, 0), (0x00, Z_FLAG | N_FLAG));
+        // SBC A,n takes the carry away too
+        assert_eq!(
+            alu(&[0xDE, 0x00], 0x00, C_FLAG),
+            (0xFF, N_FLAG | H_FLAG | C_FLAG)
+        );
+        assert_eq!(
+            alu(&[0xDE, 0x0F], 0x10, C_FLAG),
+            (0x00, Z_FLAG | N_FLAG | H_FLAG)
+        );
+        // CP n leaves A alone
+        assert_eq!(alu(&[0xFE, 0x42], 0x42, 0), (0x42, Z_FLAG | N_FLAG));
+        assert_eq!(
+            alu(&[0xFE, 0x43], 0x42, 0),
+            (0x42, N_FLAG | H_FLAG | C_FLAG)
+        );
+    }
+
+    #[test]
+    fn logic_flags() {
+        // AND n always sets H
+        assert_eq!(alu(&[0xE6, 0x0F], 0xF0, C_FLAG), (0x00, Z_FLAG | H_FLAG));
+        // XOR A
+        assert_eq!(alu(&[0xAF], 0x5A, 0xF0), (0x00, Z_FLAG));
+        // OR n
+        assert_eq!(alu(&[0xF6, 0x01], 0x80, 0xF0), (0x81, 0));
+    }
+
+    #[test]
+    fn inc_and_dec_keep_the_carry() {
+        // INC A
+        assert_eq!(alu(&[0x3C], 0xFF, C_FLAG), (0x00, Z_FLAG | H_FLAG | C_FLAG));
+        assert_eq!(alu(&[0x3C], 0x0E, 0), (0x0F, 0));
+        // DEC A
+        assert_eq!(alu(&[0x3D], 0x01, C_FLAG), (0x00, Z_FLAG | N_FLAG | C_FLAG));
+        assert_eq!(alu(&[0x3D], 0x00, 0), (0xFF, N_FLAG | H_FLAG));
+
+        // INC (HL)
+        let (mut cpu, mut mmu, mut ppu) = setup(&[0x34]);
+        mmu.write_byte(0xC1A0, 0x0F);
+        run(&mut cpu, &mut mmu, &mut ppu);
+        assert_eq!(mmu.read_byte(0xC1A0), 0x10);
+        assert_eq!(cpu.get_register(Reg8::F), H_FLAG);
+    }
+
+    #[test]
+    fn accumulator_rotates_clear_z() {
+        // RLCA
+        assert_eq!(alu(&[0x07], 0x80, Z_FLAG), (0x01, C_FLAG));
+        // RRCA
+        assert_eq!(alu(&[0x0F], 0x01, 0), (0x80, C_FLAG));
+        // RLA
+        assert_eq!(alu(&[0x17], 0x80, 0), (0x00, C_FLAG));
+        // RRA
+        assert_eq!(alu(&[0x1F], 0x00, C_FLAG), (0x80, 0));
+    }
+
+    #[test]
+    fn cb_rotates_and_shifts() {
+        // RLC A, RL A, SLA A, SRA A, SWAP A, SRL A
+        assert_eq!(alu(&[0xCB, 0x07], 0x00, C_FLAG), (0x00, Z_FLAG));
+        assert_eq!(alu(&[0xCB, 0x17], 0x80, 0), (0x00, Z_FLAG | C_FLAG));
+        assert_eq!(alu(&[0xCB, 0x27], 0xC0, 0), (0x80, C_FLAG));
+        assert_eq!(alu(&[0xCB, 0x2F], 0x81, 0), (0xC0, C_FLAG));
+        assert_eq!(alu(&[0xCB, 0x37], 0xF1, C_FLAG), (0x1F, 0));
+        assert_eq!(alu(&[0xCB, 0x3F], 0x01, 0), (0x00, Z_FLAG | C_FLAG));
+        // BIT 7,A keeps the carry, RES and SET don't touch the flags
+        assert_eq!(
+            alu(&[0xCB, 0x7F], 0x7F, C_FLAG),
+            (0x7F, Z_FLAG | H_FLAG | C_FLAG)
+        );
+        assert_eq!(alu(&[0xCB, 0xBF], 0xFF, 0xF0), (0x7F, 0xF0));
+        assert_eq!(alu(&[0xCB, 0xC7], 0x00, 0), (0x01, 0));
+    }
+
+    #[test]
+    fn daa_adjusts_bcd() {
+        // 0x15 + 0x27 = 0x42 in BCD
+        let (mut cpu, mut mmu, mut ppu) = setup(&[0xC6, 0x27, 0x27]);
+        cpu.set_register(Reg8::A, 0x15);
+        run(&mut cpu, &mut mmu, &mut ppu);
+        run(&mut cpu, &mut mmu, &mut ppu);
+        assert_eq!(cpu.get_register(Reg8::A), 0x42);
+        assert_eq!(cpu.get_register(Reg8::F), 0);
+
+        // 0x99 + 0x01 = 0x00, carry out
+        let (mut cpu, mut mmu, mut ppu) = setup(&[0xC6, 0x01, 0x27]);
+        cpu.set_register(Reg8::A, 0x99);
+        run(&mut cpu, &mut mmu, &mut ppu);
+        run(&mut cpu, &mut mmu, &mut ppu);
+        assert_eq!(cpu.get_register(Reg8::A), 0x00);
+        assert_eq!(cpu.get_register(Reg8::F), Z_FLAG | C_FLAG);
+
+        // 0x10 - 0x01 = 0x09
+        let (mut cpu, mut mmu, mut ppu) = setup(&[0xD6, 0x01, 0x27]);
+        cpu.set_register(Reg8::A, 0x10);
+        run(&mut cpu, &mut mmu, &mut ppu);
+        run(&mut cpu, &mut mmu, &mut ppu);
+        assert_eq!(cpu.get_register(Reg8::A), 0x09);
+        assert_eq!(cpu.get_register(Reg8::F), N_FLAG);
+    }
+
+    #[test]
+    fn cpl_scf_and_ccf() {
+        assert_eq!(alu(&[0x2F], 0x0F, Z_FLAG | C_FLAG), (0xF0, 0xF0));
+        assert_eq!(
+            alu(&[0x37], 0x00, Z_FLAG | N_FLAG | H_FLAG),
+            (0x00, Z_FLAG | C_FLAG)
+        );
+        assert_eq!(alu(&[0x3F], 0x00, N_FLAG | H_FLAG | C_FLAG), (0x00, 0));
+    }
+
+    #[test]
+    fn add_hl_keeps_z_and_carries_from_bit_11() {
+        // ADD HL,BC
+        let (mut cpu, mut mmu, mut ppu) = setup(&[0x09]);
+        cpu.set_register_pair(Reg16::Hl, 0x0FFF);
+        cpu.set_register_pair(Reg16::Bc, 0x0001);
+        cpu.set_register(Reg8::F, Z_FLAG | N_FLAG);
+        run(&mut cpu, &mut mmu, &mut ppu);
+        assert_eq!(cpu.get_register_pair(Reg16::Hl), 0x1000);
+        assert_eq!(cpu.get_register(Reg8::F), Z_FLAG | H_FLAG);
+
+        // ADD HL,HL
+        let (mut cpu, mut mmu, mut ppu) = setup(&[0x29]);
+        cpu.set_register_pair(Reg16::Hl, 0x8000);
+        run(&mut cpu, &mut mmu, &mut ppu);
+        assert_eq!(cpu.get_register_pair(Reg16::Hl), 0x0000);
+        assert_eq!(cpu.get_register(Reg8::F), C_FLAG);
+    }
+
+    #[test]
+    fn sp_offset_flags_come_from_the_low_byte() {
+        // ADD SP,-1 from $0000 wraps around, no carry out of the low byte
+        let (mut cpu, mut mmu, mut ppu) = setup(&[0xE8, 0xFF]);
+        cpu.sp = 0x0000;
+        run(&mut cpu, &mut mmu, &mut ppu);
+        assert_eq!(cpu.sp, 0xFFFF);
+        assert_eq!(cpu.get_register(Reg8::F), 0);
+
+        // LD HL,SP+1 from $00FF carries out of both nibbles
+        let (mut cpu, mut mmu, mut ppu) = setup(&[0xF8, 0x01]);
+        cpu.sp = 0x00FF;
+        cpu.set_register(Reg8::F, Z_FLAG | N_FLAG);
+        run(&mut cpu, &mut mmu, &mut ppu);
+        assert_eq!(cpu.get_register_pair(Reg16::Hl), 0x0100);
+        assert_eq!(cpu.get_register(Reg8::F), H_FLAG | C_FLAG);
+    }
+
+    #[test]
+    fn pc_wraps_around_fetching_operands() {
+        // LD A,n at $FFFF takes its operand from $0000
+        let (mut cpu, mut mmu, mut ppu) = setup_flat(&[]);
+        mmu.write_byte(0xFFFF, 0x3E);
+        mmu.write_byte(0x0000, 0x42);
+        cpu.pc = 0xFFFF;
+        run(&mut cpu, &mut mmu, &mut ppu);
+        assert_eq!(cpu.get_register(Reg8::A), 0x42);
+        assert_eq!(cpu.pc, 0x0001);
+
+        // a NOP at $FFFF lands on $0000
+        let (mut cpu, mut mmu, mut ppu) = setup(&[]);
+        mmu.write_byte(0xFFFF, 0x00);
+        cpu.pc = 0xFFFF;
+        run(&mut cpu, &mut mmu, &mut ppu);
+        assert_eq!(cpu.pc, 0x0000);
+    }
+
+    #[test]
+    fn jumps_wrap_around() {
+        // JR -3 from $0001 (PC is $0002 once it's read) lands on $FFFF
+        let (mut cpu, mut mmu, mut ppu) = setup_flat(&[]);
+        mmu.write_byte(0x0000, 0x18);
+        mmu.write_byte(0x0001, 0xFD);
+        cpu.pc = 0x0000;
+        run(&mut cpu, &mut mmu, &mut ppu);
+        assert_eq!(cpu.pc, 0xFFFF);
+
+        // RST $38 at $FFFF pushes $0000
+        let (mut cpu, mut mmu, mut ppu) = setup(&[]);
+        mmu.write_byte(0xFFFF, 0xFF);
+        cpu.pc = 0xFFFF;
+        run(&mut cpu, &mut mmu, &mut ppu);
+        assert_eq!(cpu.pc, 0x0038);
+        assert_eq!(cpu.pop_from_stack(&mut mmu, &mut ppu), 0x0000);
+    }
+
+    #[test]
+    fn stack_and_hl_wrap_around() {
+        // PUSH BC puts the high byte above the low one
+        let (mut cpu, mut mmu, mut ppu) = setup(&[0xC5]);
+        cpu.sp = 0xC001;
+        cpu.set_register_pair(Reg16::Bc, 0x1234);
+        run(&mut cpu, &mut mmu, &mut ppu);
+        assert_eq!(cpu.sp, 0xBFFF);
+        assert_eq!(mmu.read_byte(0xC000), 0x12);
+
+        // POP from $FFFF reads the high byte from $0000
+        let (mut cpu, mut mmu, mut ppu) = setup(&[0xC1]);
+        mmu.write_byte(0xFFFF, 0x34);
+        cpu.sp = 0xFFFF;
+        run(&mut cpu, &mut mmu, &mut ppu);
+        assert_eq!(cpu.sp, 0x0001);
+        assert_eq!(cpu.get_register(Reg8::C), 0x34);
+
+        // LD (HL+),A at $FFFF
+        let (mut cpu, mut mmu, mut ppu) = setup(&[0x22]);
+        cpu.set_register_pair(Reg16::Hl, 0xFFFF);
+        run(&mut cpu, &mut mmu, &mut ppu);
+        assert_eq!(cpu.get_register_pair(Reg16::Hl), 0x0000);
+
+        // LD (HL-),A at $0000
+        let (mut cpu, mut mmu, mut ppu) = setup(&[0x32]);
+        cpu.set_register_pair(Reg16::Hl, 0x0000);
+        run(&mut cpu, &mut mmu, &mut ppu);
+        assert_eq!(cpu.get_register_pair(Reg16::Hl), 0xFFFF);
+
+        // INC SP and DEC BC
+        let (mut cpu, mut mmu, mut ppu) = setup(&[0x33, 0x0B]);
+        cpu.sp = 0xFFFF;
+        cpu.set_register_pair(Reg16::Bc, 0x0000);
+        run(&mut cpu, &mut mmu, &mut ppu);
+        run(&mut cpu, &mut mmu, &mut ppu);
+        assert_eq!(cpu.sp, 0x0000);
+        assert_eq!(cpu.get_register_pair(Reg16::Bc), 0xFFFF);
+
+        // LD ($FFFF),SP writes the high byte to $0000
+        let (mut cpu, mut mmu, mut ppu) = setup_flat(&[0x08, 0xFF, 0xFF]);
+        cpu.sp = 0xABCD;
+        run(&mut cpu, &mut mmu, &mut ppu);
+        assert_eq!(mmu.read_byte(0xFFFF), 0xCD);
+        assert_eq!(mmu.read_byte(0x0000), 0xAB);
+    }
+
+    #[test]
+    fn call_and_ret() {
+        // CALL $C210, then RET
+        let (mut cpu, mut mmu, mut ppu) = setup(&[0xCD, 0x10, 0xC2]);
+        mmu.write_byte(0xC210, 0xC9);
+        run(&mut cpu, &mut mmu, &mut ppu);
+        assert_eq!(cpu.pc, 0xC210);
+        assert_eq!(cpu.sp, 0xCFFE);
+        run(&mut cpu, &mut mmu, &mut ppu);
+        assert_eq!(cpu.pc, START + 3);
+        assert_eq!(cpu.sp, 0xD000);
+    }
+
+    #[test]
+    fn f_low_nibble_is_always_zero() {
+        // POP AF
+        let (mut cpu, mut mmu, mut ppu) = setup(&[0xF1]);
+        mmu.write_byte(0xD000, 0xFF);
+        run(&mut cpu, &mut mmu, &mut ppu);
+        assert_eq!(cpu.get_register(Reg8::F), 0xF0);
+    }
+
+    #[test]
+    fn ei_takes_effect_after_the_next_instruction() {
+        // EI, NOP with a VBlank interrupt already waiting
+        let (mut cpu, mut mmu, mut ppu) = setup(&[0xFB, 0x00, 0x00]);
+        mmu.write_byte(IE_ADDR, 0b0000_0001);
+        mmu.request_interrupt(0b0000_0001);
+        run(&mut cpu, &mut mmu, &mut ppu);
+        run(&mut cpu, &mut mmu, &mut ppu);
+        assert_eq!(cpu.pc, START + 2);
+        assert_eq!(run(&mut cpu, &mut mmu, &mut ppu), INTERRUPT_CLOCKS);
+        assert_eq!(cpu.pc, 0x0040);
+        assert!(!cpu.get_ime());
+        assert_eq!(cpu.pop_from_stack(&mut mmu, &mut ppu), START + 2);
+    }
 
-        // fetch
-        let byte = mmu.read_byte(self.pc);
-        // decode
-        let instruction = self.decode(byte, mmu);
-        // execute
-        self.execute(&instruction, mmu);
-
-        let current_instruction_t_clocks_passed = self.t - self.last_t;
-        ppu.step(current_instruction_t_clocks_passed, mmu);
-        //        if self.pc == 0x00E8 {
-        //            let bg_tile_set = ppu.get_bg_tile_set(mmu);
-        //            let mut i = 0;
-        //            //            while i < bg_tile_set.len() {
-        //            let tile = ppu.get_tile(mmu, 33168);
-        //            //                println!("TILE ADDR: {:?}", (0x8000 + i) as u16);
-        //            println!("TILE: {:?}", tile);
-        //            ppu.transform_tile_to_minifb_tile(&mmu, tile);
-        //            i += 16;
-        //            //            }
-        //            panic!("BGP Palette: {:b}", ppu.get_bgp(&mmu));
-        //        }
+    #[test]
+    fn halt_bug_reads_the_next_byte_twice() {
+        // HALT with IME off and an interrupt waiting, then INC A
+        let (mut cpu, mut mmu, mut ppu) = setup(&[0x76, 0x3C, 0x00]);
+        mmu.write_byte(IE_ADDR, 0b0000_0100);
+        mmu.request_interrupt(0b0000_0100);
+        run(&mut cpu, &mut mmu, &mut ppu);
+        assert!(!cpu.is_halted());
+        run(&mut cpu, &mut mmu, &mut ppu);
+        run(&mut cpu, &mut mmu, &mut ppu);
+        assert_eq!(cpu.get_register(Reg8::A), 2);
+        assert_eq!(cpu.pc, START + 2);
     }
 }
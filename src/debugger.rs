@@ -0,0 +1,836 @@
+use crate::cpu::{BusAccess, Reg16, Reg8};
+use crate::error::EmuError;
+use crate::gameboy::GameBoy;
+use crate::headless::dump_registers;
+use crate::opcodes::{CB_OPCODES, OPCODES};
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::{self, BufRead, Write};
+
+// A debugger on the command line: stepping, breakpoints, watchpoints on the
+// bus accesses the CPU logs, and a call stack worked out from the CALLs,
+// RSTs, RETs and interrupts it sees go by.
+
+const HELP: &str = "\
+addresses and values are hex ($ or 0x in front is fine), counts are decimal
+  s, step [n]                 run n instructions (default 1)
+  n, next                     step, running a whole CALL or RST (10000000 instructions at most)
+  c, continue [n]             run until a breakpoint or watchpoint, n instructions at most (default 10000000)
+  b, break <addr> [if <cond>] stop before running the instruction at addr
+  b, break if <cond>          stop whenever it holds, <reg> ==|!=|<|>|<=|>= <value>
+  watch <addr>                stop after a write to addr
+  rwatch <addr>               stop after a read of addr
+  awatch <addr>               stop after a read or a write of addr
+  d, delete <n>               remove breakpoint or watchpoint n
+  l, list                     show the breakpoints and watchpoints
+  r, regs                     show the registers
+  set <reg> <value>           change a register: a, f, b, c, d, e, h, l, af, bc, de, hl, sp, pc
+  x, mem <addr> [len]         show len bytes of memory (default 64)
+  poke <addr> <byte>...       write bytes to memory
+  dis [addr] [n]              disassemble n instructions (default around PC)
+  bt                          show the call stack
+  h, help                     show this
+  q, quit                     stop debugging
+an empty line runs the last command again";
+
+// how many instructions that ran `dis` shows before PC
+const HISTORY_SIZE: usize = 4;
+// how far next and continue go before giving the prompt back, about half a
+// minute of game time, for loops that never hit anything
+const RUN_LIMIT: usize = 10_000_000;
+// where the interrupt handlers start
+const INTERRUPT_VECTORS: [u16; 5] = [0x0040, 0x0048, 0x0050, 0x0058, 0x0060];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Register {
+    R8(Reg8),
+    R16(Reg16),
+}
+
+fn parse_register(name: &str) -> Option<Register> {
+    match name.to_lowercase().as_str() {
+        "a" => Some(Register::R8(Reg8::A)),
+        "f" => Some(Register::R8(Reg8::F)),
+        "b" => Some(Register::R8(Reg8::B)),
+        "c" => Some(Register::R8(Reg8::C)),
+        "d" => Some(Register::R8(Reg8::D)),
+        "e" => Some(Register::R8(Reg8::E)),
+        "h" => Some(Register::R8(Reg8::H)),
+        "l" => Some(Register::R8(Reg8::L)),
+        "af" => Some(Register::R16(Reg16::Af)),
+        "bc" => Some(Register::R16(Reg16::Bc)),
+        "de" => Some(Register::R16(Reg16::De)),
+        "hl" => Some(Register::R16(Reg16::Hl)),
+        "sp" => Some(Register::R16(Reg16::Sp)),
+        "pc" => Some(Register::R16(Reg16::Pc)),
+        _ => None,
+    }
+}
+
+fn get_register(gameboy: &GameBoy, register: Register) -> u16 {
+    match register {
+        Register::R8(register) => gameboy.get_cpu().get_register(register) as u16,
+        Register::R16(register) => gameboy.get_cpu().get_register_pair(register),
+    }
+}
+
+fn set_register(gameboy: &mut GameBoy, register: Register, value: u16) {
+    match register {
+        Register::R8(register) => gameboy.get_cpu_mut().set_register(register, value as u8),
+        Register::R16(register) => gameboy.get_cpu_mut().set_register_pair(register, value),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Comparison {
+    Equal,
+    NotEqual,
+    Less,
+    Greater,
+    LessOrEqual,
+    GreaterOrEqual,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Condition {
+    pub register: Register,
+    pub comparison: Comparison,
+    pub value: u16,
+}
+
+impl Condition {
+    fn holds(&self, gameboy: &GameBoy) -> bool {
+        let register = get_register(gameboy, self.register);
+        match self.comparison {
+            Comparison::Equal => register == self.value,
+            Comparison::NotEqual => register != self.value,
+            Comparison::Less => register < self.value,
+            Comparison::Greater => register > self.value,
+            Comparison::LessOrEqual => register <= self.value,
+            Comparison::GreaterOrEqual => register >= self.value,
+        }
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let comparison = match self.comparison {
+            Comparison::Equal => "==",
+            Comparison::NotEqual => "!=",
+            Comparison::Less => "<",
+            Comparison::Greater => ">",
+            Comparison::LessOrEqual => "<=",
+            Comparison::GreaterOrEqual => ">=",
+        };
+        let register = match self.register {
+            Register::R8(register) => format!("{:?}", register),
+            Register::R16(register) => format!("{:?}", register).to_uppercase(),
+        };
+        write!(f, "{} {} {:#X}", register, comparison, self.value)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Point {
+    // before the instruction at `address` runs, or any of them without one
+    Breakpoint {
+        address: Option<u16>,
+        condition: Option<Condition>,
+    },
+    // after the instruction that touched `address`
+    Watchpoint {
+        address: u16,
+        on_read: bool,
+        on_write: bool,
+    },
+}
+
+impl fmt::Display for Point {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Point::Breakpoint { address, condition } => {
+                write!(f, "break")?;
+                if let Some(address) = address {
+                    write!(f, " {:#06X}", address)?;
+                }
+                if let Some(condition) = condition {
+                    write!(f, " if {}", condition)?;
+                }
+                Ok(())
+            }
+            Point::Watchpoint {
+                address,
+                on_read,
+                on_write,
+            } => {
+                let access = match (on_read, on_write) {
+                    (true, true) => "reads and writes",
+                    (true, false) => "reads",
+                    _ => "writes",
+                };
+                write!(f, "watch {:#06X} {}", address, access)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum StopReason {
+    Step,
+    // the numbers `list` shows
+    Breakpoint(usize),
+    Watchpoint(usize, BusAccess),
+    // the CPU ran an illegal opcode and is stuck for good
+    Locked,
+    // ran this many instructions without stopping
+    Limit(usize),
+    Error(EmuError),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CallFrame {
+    // where the CALL or RST was, or the instruction an interrupt came before
+    pub call_site: u16,
+    pub target: u16,
+    pub return_address: u16,
+    pub interrupt: bool,
+}
+
+fn is_call(opcode: u8) -> bool {
+    // CALL, CALL cc and RST
+    matches!(opcode, 0xCD | 0xC4 | 0xCC | 0xD4 | 0xDC) || (opcode & 0b1100_0111) == 0b1100_0111
+}
+
+fn is_return(opcode: u8) -> bool {
+    matches!(opcode, 0xC9 | 0xD9 | 0xC0 | 0xC8 | 0xD0 | 0xD8)
+}
+
+fn instruction_length(gameboy: &GameBoy, address: u16) -> u16 {
+    let opcode = gameboy.get_mmu().read_byte(address);
+    if opcode == 0xCB {
+        2
+    } else {
+        OPCODES[opcode as usize].length as u16
+    }
+}
+
+// The instruction at `address` with the operands filled in, and its length
+pub fn disassemble(gameboy: &GameBoy, address: u16) -> (String, u16) {
+    let mmu = gameboy.get_mmu();
+    let byte = |offset: u16| mmu.read_byte(address.wrapping_add(offset));
+    let opcode = byte(0);
+    if opcode == 0xCB {
+        return (CB_OPCODES[byte(1) as usize].mnemonic.to_string(), 2);
+    }
+    let info = &OPCODES[opcode as usize];
+    let d16 = u16::from_le_bytes([byte(1), byte(2)]);
+    let offset = byte(1) as i8;
+    let mnemonic = info.mnemonic;
+    let text = if mnemonic.starts_with("JR") {
+        // relative to the end of the JR
+        let target = address.wrapping_add(2).wrapping_add(offset as u16);
+        mnemonic.replace("r8", &format!("{:#06X}", target))
+    } else {
+        mnemonic
+            .replace("d16", &format!("{:#06X}", d16))
+            .replace("a16", &format!("{:#06X}", d16))
+            .replace("d8", &format!("{:#04X}", byte(1)))
+            .replace("a8", &format!("{:#06X}", 0xFF00 | byte(1) as u16))
+            .replace("+r8", &format!("{:+}", offset))
+            .replace("r8", &format!("{:+}", offset))
+    };
+    (text, info.length as u16)
+}
+
+fn parse_hex(text: &str) -> Result<u16, String> {
+    let digits = text
+        .trim_start_matches('$')
+        .trim_start_matches("0x")
+        .trim_start_matches("0X");
+    u16::from_str_radix(digits, 16).map_err(|_| format!("{} is not a hex number", text))
+}
+
+fn parse_count(text: Option<&&str>, default: usize) -> Result<usize, String> {
+    match text {
+        Some(text) => text
+            .parse()
+            .map_err(|_| format!("{} is not a number", text)),
+        None => Ok(default),
+    }
+}
+
+fn parse_condition(words: &[&str]) -> Result<Condition, String> {
+    let (register, comparison, value) = match words {
+        [register, comparison, value] => (register, comparison, value),
+        _ => return Err("conditions look like `a == 10`".to_string()),
+    };
+    let register =
+        parse_register(register).ok_or_else(|| format!("unknown register {}", register))?;
+    let comparison = match *comparison {
+        "==" => Comparison::Equal,
+        "!=" => Comparison::NotEqual,
+        "<" => Comparison::Less,
+        ">" => Comparison::Greater,
+        "<=" => Comparison::LessOrEqual,
+        ">=" => Comparison::GreaterOrEqual,
+        _ => return Err(format!("unknown comparison {}", comparison)),
+    };
+    let condition = Condition {
+        register,
+        comparison,
+        value: parse_hex(value)?,
+    };
+    Ok(condition)
+}
+
+pub struct Debugger {
+    // numbered from 1, numbers aren't reused after a delete
+    points: Vec<(usize, Point)>,
+    next_point: usize,
+    call_stack: Vec<CallFrame>,
+    // PCs of the last instructions that ran
+    history: VecDeque<u16>,
+    last_command: String,
+    run_limit: usize,
+}
+
+impl Debugger {
+    // Turns on the bus access log of the CPU for the watchpoints
+    pub fn new(gameboy: &mut GameBoy) -> Debugger {
+        gameboy.get_cpu_mut().record_bus_accesses();
+        Debugger {
+            points: Vec::new(),
+            next_point: 1,
+            call_stack: Vec::new(),
+            history: VecDeque::new(),
+            last_command: String::new(),
+            run_limit: RUN_LIMIT,
+        }
+    }
+
+    pub fn add_point(&mut self, point: Point) -> usize {
+        let number = self.next_point;
+        self.next_point += 1;
+        self.points.push((number, point));
+        number
+    }
+
+    pub fn delete_point(&mut self, number: usize) -> bool {
+        let count = self.points.len();
+        self.points.retain(|&(n, _)| n != number);
+        self.points.len() != count
+    }
+
+    pub fn get_points(&self) -> &[(usize, Point)] {
+        &self.points
+    }
+
+    // How many instructions next and continue run at most
+    pub fn set_run_limit(&mut self, limit: usize) {
+        self.run_limit = limit;
+    }
+
+    // Innermost last
+    pub fn get_call_stack(&self) -> &[CallFrame] {
+        &self.call_stack
+    }
+
+    // Runs one instruction, or an interrupt dispatch or a halted cycle.
+    // Returns the first watchpoint it set off.
+    pub fn step(&mut self, gameboy: &mut GameBoy) -> Result<Option<(usize, BusAccess)>, EmuError> {
+        let pc = gameboy.get_cpu().get_register_pair(Reg16::Pc);
+        let sp = gameboy.get_cpu().get_register_pair(Reg16::Sp);
+        let was_halted = gameboy.get_cpu().is_halted();
+        let opcode = gameboy.get_mmu().read_byte(pc);
+        let length = instruction_length(gameboy, pc);
+        // whatever was logged outside of the debugger
+        gameboy.get_cpu_mut().take_bus_accesses();
+        gameboy.step_instruction()?;
+
+        let new_pc = gameboy.get_cpu().get_register_pair(Reg16::Pc);
+        let new_sp = gameboy.get_cpu().get_register_pair(Reg16::Sp);
+        let pushed = new_sp == sp.wrapping_sub(2);
+        let ran_call =
+            !was_halted && is_call(opcode) && pushed && new_pc != pc.wrapping_add(length);
+        let interrupted = !ran_call && pushed && INTERRUPT_VECTORS.contains(&new_pc);
+        if ran_call || interrupted {
+            self.call_stack.push(CallFrame {
+                call_site: pc,
+                target: new_pc,
+                return_address: if interrupted {
+                    pc
+                } else {
+                    pc.wrapping_add(length)
+                },
+                interrupt: interrupted,
+            });
+        } else if !was_halted && is_return(opcode) && new_sp == sp.wrapping_add(2) {
+            // games play with the stack too, only unwind to a frame that matches
+            if let Some(frame) = self
+                .call_stack
+                .iter()
+                .rposition(|frame| frame.return_address == new_pc)
+            {
+                self.call_stack.truncate(frame);
+            }
+        }
+        if !was_halted && !interrupted {
+            self.history.push_back(pc);
+            if self.history.len() > HISTORY_SIZE {
+                self.history.pop_front();
+            }
+        }
+
+        let accesses = gameboy.get_cpu_mut().take_bus_accesses();
+        for access in accesses.iter() {
+            for &(number, point) in self.points.iter() {
+                if let Point::Watchpoint {
+                    address,
+                    on_read,
+                    on_write,
+                } = point
+                {
+                    let hit = match *access {
+                        BusAccess::Read(at, _) => on_read && at == address,
+                        BusAccess::Write(at, _) => on_write && at == address,
+                    };
+                    if hit {
+                        return Ok(Some((number, *access)));
+                    }
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    fn breakpoint_hit(&self, gameboy: &GameBoy) -> Option<usize> {
+        // halted the CPU sits on the same PC, that doesn't count again
+        if gameboy.get_cpu().is_halted() {
+            return None;
+        }
+        let pc = gameboy.get_cpu().get_register_pair(Reg16::Pc);
+        self.points.iter().find_map(|&(number, point)| match point {
+            Point::Breakpoint { address, condition }
+                if address.is_none_or(|address| address == pc)
+                    && condition.is_none_or(|condition| condition.holds(gameboy)) =>
+            {
+                Some(number)
+            }
+            _ => None,
+        })
+    }
+
+    // Runs until a breakpoint, a watchpoint, or PC reaches `until` with SP
+    // back where it was, for `limit` instructions at most
+    fn run_until(
+        &mut self,
+        gameboy: &mut GameBoy,
+        until: Option<(u16, u16)>,
+        limit: usize,
+    ) -> StopReason {
+        for _ in 0..limit {
+            if gameboy.get_cpu().is_locked() {
+                return StopReason::Locked;
+            }
+            match self.step(gameboy) {
+                Ok(None) => {}
+                Ok(Some((number, access))) => return StopReason::Watchpoint(number, access),
+                Err(error) => return StopReason::Error(error),
+            }
+            let pc = gameboy.get_cpu().get_register_pair(Reg16::Pc);
+            let sp = gameboy.get_cpu().get_register_pair(Reg16::Sp);
+            if until == Some((pc, sp)) {
+                return StopReason::Step;
+            }
+            if let Some(number) = self.breakpoint_hit(gameboy) {
+                return StopReason::Breakpoint(number);
+            }
+        }
+        StopReason::Limit(limit)
+    }
+
+    pub fn resume(&mut self, gameboy: &mut GameBoy) -> StopReason {
+        self.run_until(gameboy, None, self.run_limit)
+    }
+
+    // Like step, but a CALL or RST runs until it returns
+    pub fn next(&mut self, gameboy: &mut GameBoy) -> StopReason {
+        let pc = gameboy.get_cpu().get_register_pair(Reg16::Pc);
+        let sp = gameboy.get_cpu().get_register_pair(Reg16::Sp);
+        let opcode = gameboy.get_mmu().read_byte(pc);
+        if is_call(opcode) && !gameboy.get_cpu().is_halted() {
+            let return_address = pc.wrapping_add(instruction_length(gameboy, pc));
+            return self.run_until(gameboy, Some((return_address, sp)), self.run_limit);
+        }
+        self.step_over_points(gameboy)
+    }
+
+    fn step_over_points(&mut self, gameboy: &mut GameBoy) -> StopReason {
+        match self.step(gameboy) {
+            Ok(None) => StopReason::Step,
+            Ok(Some((number, access))) => StopReason::Watchpoint(number, access),
+            Err(error) => StopReason::Error(error),
+        }
+    }
+
+    fn format_stop(&self, gameboy: &GameBoy, reason: StopReason) -> String {
+        let pc = gameboy.get_cpu().get_register_pair(Reg16::Pc);
+        let (instruction, _) = disassemble(gameboy, pc);
+        let reason = match reason {
+            StopReason::Step => String::new(),
+            StopReason::Breakpoint(number) => format!("breakpoint {}\n", number),
+            StopReason::Watchpoint(number, BusAccess::Read(address, value)) => {
+                format!(
+                    "watchpoint {}: read {:#04X} from {:#06X}\n",
+                    number, value, address
+                )
+            }
+            StopReason::Watchpoint(number, BusAccess::Write(address, value)) => {
+                format!(
+                    "watchpoint {}: wrote {:#04X} to {:#06X}\n",
+                    number, value, address
+                )
+            }
+            StopReason::Locked => "the CPU is locked up\n".to_string(),
+            StopReason::Limit(limit) => format!("stopped after {} instructions\n", limit),
+            StopReason::Error(error) => format!("{}\n", error),
+        };
+        format!("{}{:#06X}: {}", reason, pc, instruction)
+    }
+
+    fn format_disassembly(&self, gameboy: &GameBoy, from: Option<u16>, count: usize) -> String {
+        let pc = gameboy.get_cpu().get_register_pair(Reg16::Pc);
+        let mut lines = Vec::new();
+        let mut address = match from {
+            Some(address) => address,
+            None => {
+                for &address in self.history.iter().filter(|&&address| address != pc) {
+                    lines.push(format!(
+                        "   {:#06X}: {}",
+                        address,
+                        disassemble(gameboy, address).0
+                    ));
+                }
+                pc
+            }
+        };
+        for _ in 0..count {
+            let (instruction, length) = disassemble(gameboy, address);
+            let marker = if address == pc { "=>" } else { "  " };
+            lines.push(format!("{} {:#06X}: {}", marker, address, instruction));
+            address = address.wrapping_add(length);
+        }
+        lines.join("\n")
+    }
+
+    fn format_memory(&self, gameboy: &GameBoy, from: u16, length: usize) -> String {
+        let mut lines = Vec::new();
+        for row in (0..length).step_by(16) {
+            let start = from.wrapping_add(row as u16);
+            let bytes: Vec<String> = (0..16.min(length - row))
+                .map(|i| {
+                    format!(
+                        "{:02X}",
+                        gameboy.get_mmu().read_byte(start.wrapping_add(i as u16))
+                    )
+                })
+                .collect();
+            lines.push(format!("{:#06X}: {}", start, bytes.join(" ")));
+        }
+        lines.join("\n")
+    }
+
+    fn format_call_stack(&self, gameboy: &GameBoy) -> String {
+        let mut lines = Vec::new();
+        let mut pc = gameboy.get_cpu().get_register_pair(Reg16::Pc);
+        for (depth, frame) in self.call_stack.iter().rev().enumerate() {
+            let kind = if frame.interrupt { " (interrupt)" } else { "" };
+            lines.push(format!(
+                "#{} {:#06X} in {:#06X}{}",
+                depth, pc, frame.target, kind
+            ));
+            pc = frame.call_site;
+        }
+        lines.push(format!("#{} {:#06X}", self.call_stack.len(), pc));
+        lines.join("\n")
+    }
+
+    // Runs one command line, returns what to print and whether to quit
+    pub fn execute(&mut self, gameboy: &mut GameBoy, line: &str) -> Result<(String, bool), String> {
+        let line = if line.trim().is_empty() {
+            self.last_command.clone()
+        } else {
+            line.trim().to_string()
+        };
+        self.last_command = line.clone();
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let (command, args) = match words.split_first() {
+            Some((command, args)) => (*command, args),
+            None => return Ok((String::new(), false)),
+        };
+
+        let output = match command {
+            "s" | "step" => {
+                let mut reason = StopReason::Step;
+                for _ in 0..parse_count(args.first(), 1)? {
+                    reason = self.step_over_points(gameboy);
+                    if reason != StopReason::Step {
+                        break;
+                    }
+                }
+                self.format_stop(gameboy, reason)
+            }
+            "n" | "next" => {
+                let reason = self.next(gameboy);
+                self.format_stop(gameboy, reason)
+            }
+            "c" | "continue" => {
+                let limit = parse_count(args.first(), self.run_limit)?;
+                let reason = self.run_until(gameboy, None, limit);
+                self.format_stop(gameboy, reason)
+            }
+            "b" | "break" => {
+                let point = match args {
+                    ["if", condition @ ..] => Point::Breakpoint {
+                        address: None,
+                        condition: Some(parse_condition(condition)?),
+                    },
+                    [address] => Point::Breakpoint {
+                        address: Some(parse_hex(address)?),
+                        condition: None,
+                    },
+                    [address, "if", condition @ ..] => Point::Breakpoint {
+                        address: Some(parse_hex(address)?),
+                        condition: Some(parse_condition(condition)?),
+                    },
+                    _ => return Err("break <addr> [if <cond>] or break if <cond>".to_string()),
+                };
+                let number = self.add_point(point);
+                format!("{}: {}", number, point)
+            }
+            "watch" | "rwatch" | "awatch" => {
+                let address = match args {
+                    [address] => parse_hex(address)?,
+                    _ => return Err(format!("{} <addr>", command)),
+                };
+                let point = Point::Watchpoint {
+                    address,
+                    on_read: command != "watch",
+                    on_write: command != "rwatch",
+                };
+                let number = self.add_point(point);
+                format!("{}: {}", number, point)
+            }
+            "d" | "delete" => {
+                let number = parse_count(args.first(), 0)?;
+                if !self.delete_point(number) {
+                    return Err(format!("there is no breakpoint or watchpoint {}", number));
+                }
+                format!("deleted {}", number)
+            }
+            "l" | "list" => {
+                let lines: Vec<String> = self
+                    .points
+                    .iter()
+                    .map(|(number, point)| format!("{}: {}", number, point))
+                    .collect();
+                if lines.is_empty() {
+                    "no breakpoints or watchpoints".to_string()
+                } else {
+                    lines.join("\n")
+                }
+            }
+            "r" | "regs" => dump_registers(gameboy),
+            "set" => {
+                let (register, value) = match args {
+                    [register, value] => (register, parse_hex(value)?),
+                    _ => return Err("set <reg> <value>".to_string()),
+                };
+                let register = parse_register(register)
+                    .ok_or_else(|| format!("unknown register {}", register))?;
+                set_register(gameboy, register, value);
+                dump_registers(gameboy)
+            }
+            "x" | "mem" => {
+                let address = match args.first() {
+                    Some(address) => parse_hex(address)?,
+                    None => return Err("mem <addr> [len]".to_string()),
+                };
+                self.format_memory(gameboy, address, parse_count(args.get(1), 64)?)
+            }
+            "poke" => {
+                let (address, bytes) = match args.split_first() {
+                    Some((address, bytes)) if !bytes.is_empty() => (parse_hex(address)?, bytes),
+                    _ => return Err("poke <addr> <byte>...".to_string()),
+                };
+                for (i, byte) in bytes.iter().enumerate() {
+                    let value = parse_hex(byte)?;
+                    gameboy
+                        .get_mmu_mut()
+                        .write_byte(address.wrapping_add(i as u16), value as u8);
+                }
+                self.format_memory(gameboy, address, bytes.len())
+            }
+            "dis" => {
+                let from = match args.first() {
+                    Some(address) => Some(parse_hex(address)?),
+                    None => None,
+                };
+                self.format_disassembly(gameboy, from, parse_count(args.get(1), 6)?)
+            }
+            "bt" => self.format_call_stack(gameboy),
+            "h" | "help" => HELP.to_string(),
+            "q" | "quit" => return Ok((String::new(), true)),
+            _ => return Err(format!("unknown command {}, try help", command)),
+        };
+        Ok((output, false))
+    }
+}
+
+// Reads commands from `input` until quit or the end of it
+pub fn run<R: BufRead, W: Write>(gameboy: &mut GameBoy, input: R, mut output: W) -> io::Result<()> {
+    let mut debugger = Debugger::new(gameboy);
+    let pc = gameboy.get_cpu().get_register_pair(Reg16::Pc);
+    writeln!(output, "{:#06X}: {}", pc, disassemble(gameboy, pc).0)?;
+    let mut lines = input.lines();
+    loop {
+        write!(output, "(gb) ")?;
+        output.flush()?;
+        let line = match lines.next() {
+            Some(line) => line?,
+            None => return Ok(()),
+        };
+        match debugger.execute(gameboy, &line) {
+            Ok((_, true)) => return Ok(()),
+            Ok((text, false)) if text.is_empty() => {}
+            Ok((text, false)) => writeln!(output, "{}", text)?,
+            Err(error) => writeln!(output, "{}", error)?,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mmu::Model;
+
+    //   $0100: LD A,5; CALL $0200; JR -2
+    //   $0200: INC A; LD ($C000),A; RET
+    fn gameboy() -> GameBoy {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x107].copy_from_slice(&[0x3E, 0x05, 0xCD, 0x00, 0x02, 0x18, 0xFE]);
+        rom[0x200..0x205].copy_from_slice(&[0x3C, 0xEA, 0x00, 0xC0, 0xC9]);
+        GameBoy::new(Model::Dmg, &rom, None, None).unwrap()
+    }
+
+    fn pc(gameboy: &GameBoy) -> u16 {
+        gameboy.get_cpu().get_register_pair(Reg16::Pc)
+    }
+
+    #[test]
+    fn disassembles_with_the_operands() {
+        let gameboy = gameboy();
+        assert_eq!(disassemble(&gameboy, 0x100), ("LD A,0x05".to_string(), 2));
+        assert_eq!(disassemble(&gameboy, 0x102), ("CALL 0x0200".to_string(), 3));
+        assert_eq!(disassemble(&gameboy, 0x105), ("JR 0x0105".to_string(), 2));
+        assert_eq!(
+            disassemble(&gameboy, 0x201),
+            ("LD (0xC000),A".to_string(), 3)
+        );
+    }
+
+    #[test]
+    fn stops_at_breakpoints_and_tracks_calls() {
+        let mut gameboy = gameboy();
+        let mut debugger = Debugger::new(&mut gameboy);
+        let number = debugger.add_point(Point::Breakpoint {
+            address: Some(0x0201),
+            condition: None,
+        });
+        assert_eq!(
+            debugger.resume(&mut gameboy),
+            StopReason::Breakpoint(number)
+        );
+        assert_eq!(pc(&gameboy), 0x0201);
+        let frame = debugger.get_call_stack()[0];
+        assert_eq!(
+            (frame.call_site, frame.target, frame.return_address),
+            (0x0102, 0x0200, 0x0105)
+        );
+
+        debugger.step(&mut gameboy).unwrap();
+        debugger.step(&mut gameboy).unwrap();
+        assert_eq!(pc(&gameboy), 0x0105);
+        assert!(debugger.get_call_stack().is_empty());
+    }
+
+    #[test]
+    fn conditions_and_watchpoints() {
+        let mut gameboy = gameboy();
+        let mut debugger = Debugger::new(&mut gameboy);
+        let condition = Condition {
+            register: Register::R8(Reg8::A),
+            comparison: Comparison::Equal,
+            value: 6,
+        };
+        let breakpoint = debugger.add_point(Point::Breakpoint {
+            address: None,
+            condition: Some(condition),
+        });
+        assert_eq!(
+            debugger.resume(&mut gameboy),
+            StopReason::Breakpoint(breakpoint)
+        );
+        assert_eq!(pc(&gameboy), 0x0201);
+
+        debugger.delete_point(breakpoint);
+        let watchpoint = debugger.add_point(Point::Watchpoint {
+            address: 0xC000,
+            on_read: false,
+            on_write: true,
+        });
+        assert_eq!(
+            debugger.resume(&mut gameboy),
+            StopReason::Watchpoint(watchpoint, BusAccess::Write(0xC000, 6))
+        );
+        assert_eq!(pc(&gameboy), 0x0204);
+    }
+
+    #[test]
+    fn next_runs_the_whole_call() {
+        let mut gameboy = gameboy();
+        let mut debugger = Debugger::new(&mut gameboy);
+        debugger.next(&mut gameboy);
+        assert_eq!(debugger.next(&mut gameboy), StopReason::Step);
+        assert_eq!(pc(&gameboy), 0x0105);
+        assert_eq!(gameboy.get_mmu().read_byte(0xC000), 6);
+    }
+
+    #[test]
+    fn gives_up_after_the_run_limit() {
+        let mut gameboy = gameboy();
+        let mut debugger = Debugger::new(&mut gameboy);
+        debugger.set_run_limit(1000);
+        assert_eq!(debugger.resume(&mut gameboy), StopReason::Limit(1000));
+        let (output, _) = debugger.execute(&mut gameboy, "c 10").unwrap();
+        assert!(output.starts_with("stopped after 10 instructions\n"));
+    }
+
+    #[test]
+    fn runs_commands_from_the_input() {
+        let mut gameboy = gameboy();
+        let input =
+            "break 201\nc\nbt\n\nset a 42\nmem c000 2\npoke c000 12 34\nx c000 2\nfoo\nq\nstep\n";
+        let mut output = Vec::new();
+        run(&mut gameboy, input.as_bytes(), &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("1: break 0x0201"));
+        assert!(output.contains("breakpoint 1\n0x0201: LD (0xC000),A"));
+        assert!(output.contains("#0 0x0201 in 0x0200\n#1 0x0102"));
+        assert!(output.contains("A: 0x42"));
+        assert!(output.contains("0xC000: 12 34"));
+        assert!(output.contains("unknown command foo"));
+        assert_eq!(pc(&gameboy), 0x0201);
+    }
+}
@@ -0,0 +1,31 @@
+use std::error::Error;
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum EmuError {
+    // One of the opcodes that don't exist on the real CPU (0xD3, 0xDB...),
+    // the hardware locks up when it runs one and so do we
+    IllegalOpcode { opcode: u8, pc: u16 },
+    // $0147 - cartridge type, we run games without a memory controller or
+    // with a MBC1, MBC3 or MBC5
+    UnsupportedCartridge { cartridge_type: u8 },
+}
+
+impl fmt::Display for EmuError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EmuError::IllegalOpcode { opcode, pc } => {
+                write!(
+                    f,
+                    "illegal opcode {:#04X} at {:#06X}, the CPU locked up",
+                    opcode, pc
+                )
+            }
+            EmuError::UnsupportedCartridge { cartridge_type } => {
+                write!(f, "cartridge type {:#04X} is not supported", cartridge_type)
+            }
+        }
+    }
+}
+
+impl Error for EmuError {}
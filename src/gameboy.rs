@@ -0,0 +1,244 @@
+use crate::compat::ManualPalette;
+use crate::cpu::{Timing, CPU};
+use crate::error::EmuError;
+use crate::joypad::Button;
+use crate::mmu::{Model, MMU};
+use crate::pacer::CYCLES_PER_FRAME;
+use crate::ppu::{ColorCorrection, LIGHTEST_GREEN, PPU, SCREEN_HEIGHT, SCREEN_WIDTH};
+use crate::printer::Printer;
+use crate::savestate::{rom_checksum, StateError, StateReader, StateWriter};
+use crate::serial::SerialPeer;
+use crate::sgb::{SGB_SCREEN_HEIGHT, SGB_SCREEN_WIDTH};
+
+use std::path::PathBuf;
+
+// The whole console: owns the CPU, MMU and PPU and wires them together, so
+// frontends only deal with ROMs, frames and buttons.
+pub struct GameBoy {
+    cpu: CPU,
+    mmu: MMU,
+    ppu: PPU,
+    // clocks run past the end of the last frame
+    frame_cycles: usize,
+    // what the screen shows while the LCD is off
+    blank_screen: Vec<u32>,
+    // save states are only loaded into the ROM they came from
+    rom_checksum: u32,
+}
+
+impl GameBoy {
+    // Without a boot ROM the game starts right away at $0100, with the
+    // registers the boot ROM would have left behind. Color games switch to
+    // CGB on their own whatever model is asked for.
+    pub fn new(
+        model: Model,
+        rom: &[u8],
+        boot_rom: Option<&[u8]>,
+        save_data: Option<&[u8]>,
+    ) -> Result<GameBoy, EmuError> {
+        let mut cpu = CPU::new();
+        let mut mmu = MMU::new();
+        mmu.set_model(model);
+        mmu.from_rom_file(rom)?;
+        if let Some(save_data) = save_data {
+            mmu.load_external_ram(save_data);
+        }
+        match boot_rom {
+            Some(boot_rom) => mmu.load_boot_rom(boot_rom),
+            None => cpu.skip_boot_rom(&mut mmu),
+        }
+        let (width, height) = GameBoy::screen_size(&mmu);
+        let gameboy = GameBoy {
+            cpu,
+            mmu,
+            ppu: PPU::new(),
+            frame_cycles: 0,
+            blank_screen: vec![LIGHTEST_GREEN; width * height],
+            rom_checksum: rom_checksum(rom),
+        };
+        Ok(gameboy)
+    }
+
+    fn screen_size(mmu: &MMU) -> (usize, usize) {
+        // the SGB draws its border around the game
+        if mmu.get_model() == Model::Sgb {
+            (SGB_SCREEN_WIDTH, SGB_SCREEN_HEIGHT)
+        } else {
+            (SCREEN_WIDTH, SCREEN_HEIGHT)
+        }
+    }
+
+    // Returns the clocks the instruction took
+    pub fn step_instruction(&mut self) -> Result<usize, EmuError> {
+        self.cpu.run_instruction(&mut self.mmu, &mut self.ppu)
+    }
+
+    // Runs whole instructions until at least `cycles` clocks passed and
+    // returns how many actually did
+    pub fn run_cycles(&mut self, cycles: usize) -> Result<usize, EmuError> {
+        let mut cycles_passed = 0;
+        while cycles_passed < cycles {
+            cycles_passed += self.step_instruction()?;
+        }
+        Ok(cycles_passed)
+    }
+
+    // Runs one frame worth of clocks. Instructions don't end exactly on the
+    // frame boundary, the extra clocks are taken off the next frame. After an
+    // error calling it again carries on with the same frame.
+    pub fn run_frame(&mut self) -> Result<(), EmuError> {
+        while self.frame_cycles < CYCLES_PER_FRAME {
+            self.frame_cycles += self.step_instruction()?;
+        }
+        self.frame_cycles -= CYCLES_PER_FRAME;
+        Ok(())
+    }
+
+    // The colors of the four DMG shades, lightest first
+    pub fn set_palette(&mut self, palette: [u32; 4]) {
+        self.ppu.set_palette(palette);
+        self.blank_screen = vec![palette[0]; self.blank_screen.len()];
+        self.mmu.dirty_vram_flag = true;
+    }
+
+    // How CGB colors are turned into RGB, the background is redrawn with them
+    pub fn set_color_correction(&mut self, color_correction: ColorCorrection) {
+        self.ppu.set_color_correction(color_correction);
+        self.mmu.dirty_vram_flag = true;
+    }
+
+    // Timing::MCycle is slower but gets the timing test ROMs right
+    pub fn set_timing(&mut self, timing: Timing) {
+        self.cpu.set_timing(timing);
+    }
+
+    pub fn get_framebuffer(&self) -> &Vec<u32> {
+        if self.mmu.get_model() == Model::Sgb {
+            self.ppu.get_sgb_screen()
+        } else if self.ppu.is_lcd_enable(&self.mmu) {
+            self.ppu.get_viewport()
+        } else {
+            &self.blank_screen
+        }
+    }
+
+    // Colorises a monochrome game on a CGB with the palette of a button combo
+    pub fn set_manual_palette(&mut self, manual: ManualPalette) {
+        self.mmu.set_manual_palette(manual);
+    }
+
+    pub fn get_screen_width(&self) -> usize {
+        GameBoy::screen_size(&self.mmu).0
+    }
+
+    pub fn get_screen_height(&self) -> usize {
+        GameBoy::screen_size(&self.mmu).1
+    }
+
+    pub fn set_button(&mut self, button: Button, pressed: bool) {
+        if pressed {
+            self.mmu.press_button(button);
+        } else {
+            self.mmu.release_button(button);
+        }
+    }
+
+    pub fn press_button(&mut self, button: Button) {
+        self.mmu.press_button(button);
+    }
+
+    pub fn release_button(&mut self, button: Button) {
+        self.mmu.release_button(button);
+    }
+
+    // Plug another emulator, a printer... into the link port
+    pub fn connect_serial(&mut self, peer: Box<dyn SerialPeer>) {
+        self.mmu.connect_serial(peer);
+    }
+
+    // Printouts go to `output_dir` as PNGs, the last page when the Game Boy
+    // is dropped if the game never fed it out
+    pub fn connect_printer(&mut self, output_dir: PathBuf) {
+        self.connect_serial(Box::new(Printer::new(output_dir)));
+    }
+
+    // A transfer is held up by the other side of the link cable not
+    // answering, the game carries on but the frontend can say so
+    pub fn is_link_waiting(&self) -> bool {
+        self.mmu.is_serial_waiting()
+    }
+
+    // Everything needed to carry on from this exact point later
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut state = StateWriter::new(self.rom_checksum);
+        self.cpu.save_state(&mut state);
+        self.mmu.save_state(&mut state);
+        self.ppu.save_state(&mut state);
+        // the APU's place. There is no APU yet so it is empty, it has a
+        // length so states without sound can still be read once there is.
+        state.write_vec(&[]);
+        state.write_usize(self.frame_cycles);
+        state.finish()
+    }
+
+    // A state that doesn't load leaves the console as it was
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), StateError> {
+        let mut state = StateReader::new(data, self.rom_checksum)?;
+        let backup = self.save_state();
+        let result = self.read_state(&mut state).and_then(|_| state.finish());
+        if result.is_err() {
+            let mut backup = StateReader::new(&backup, self.rom_checksum)?;
+            self.read_state(&mut backup)?;
+        }
+        result
+    }
+
+    fn read_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.cpu.load_state(state)?;
+        self.mmu.load_state(state)?;
+        self.ppu.load_state(state)?;
+        state.read_vec()?;
+        self.frame_cycles = state.read_usize()?;
+        // the model may have changed the size of the screen
+        let (width, height) = GameBoy::screen_size(&self.mmu);
+        self.blank_screen = vec![self.ppu.get_palette()[0]; width * height];
+        Ok(())
+    }
+
+    pub fn get_rom_checksum(&self) -> u32 {
+        self.rom_checksum
+    }
+
+    // The cartridge RAM, to be written to disk as the save file
+    pub fn get_save_data(&self) -> &[u8] {
+        self.mmu.get_external_ram()
+    }
+
+    pub fn get_model(&self) -> Model {
+        self.mmu.get_model()
+    }
+
+    pub fn get_cpu(&self) -> &CPU {
+        &self.cpu
+    }
+
+    pub fn get_cpu_mut(&mut self) -> &mut CPU {
+        &mut self.cpu
+    }
+
+    pub fn get_mmu(&self) -> &MMU {
+        &self.mmu
+    }
+
+    pub fn get_mmu_mut(&mut self) -> &mut MMU {
+        &mut self.mmu
+    }
+
+    pub fn get_ppu(&self) -> &PPU {
+        &self.ppu
+    }
+
+    pub fn get_ppu_mut(&mut self) -> &mut PPU {
+        &mut self.ppu
+    }
+}
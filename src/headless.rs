@@ -0,0 +1,144 @@
+use crate::cpu::Reg16;
+use crate::error::EmuError;
+use crate::gameboy::GameBoy;
+use crate::joypad::Button;
+use crate::pacer::CYCLES_PER_FRAME;
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+// Running a ROM with no window, for CI: for some frames or clocks or until
+// it hits a breakpoint, pressing buttons when a script says so.
+
+// LD B,B, what test ROMs run when they are done
+const BREAKPOINT: u8 = 0x40;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Stop {
+    Frames(usize),
+    Cycles(usize),
+    // LD B,B, or that many frames at most
+    Breakpoint(usize),
+}
+
+// Button presses and releases by frame, read from lines like
+//   120 start press
+//   125 start release
+// with # starting a comment
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct InputScript {
+    events: Vec<(usize, Button, bool)>,
+}
+
+fn parse_button(name: &str) -> Option<Button> {
+    match name.to_lowercase().as_str() {
+        "right" => Some(Button::Right),
+        "left" => Some(Button::Left),
+        "up" => Some(Button::Up),
+        "down" => Some(Button::Down),
+        "a" => Some(Button::A),
+        "b" => Some(Button::B),
+        "select" => Some(Button::Select),
+        "start" => Some(Button::Start),
+        _ => None,
+    }
+}
+
+impl InputScript {
+    pub fn new() -> InputScript {
+        InputScript { events: Vec::new() }
+    }
+
+    pub fn parse(text: &str) -> Result<InputScript, String> {
+        let mut script = InputScript::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let event = match fields[..] {
+                [frame, button, action] => match (frame.parse(), parse_button(button), action) {
+                    (Ok(frame), Some(button), "press") => Some((frame, button, true)),
+                    (Ok(frame), Some(button), "release") => Some((frame, button, false)),
+                    _ => None,
+                },
+                _ => None,
+            };
+            match event {
+                Some((frame, button, pressed)) => script.add(frame, button, pressed),
+                None => {
+                    return Err(format!(
+                        "line {}: expected `<frame> <button> press|release`",
+                        number + 1
+                    ))
+                }
+            }
+        }
+        Ok(script)
+    }
+
+    pub fn from_file(path: &Path) -> io::Result<InputScript> {
+        let text = fs::read_to_string(path)?;
+        InputScript::parse(&text).map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))
+    }
+
+    pub fn add(&mut self, frame: usize, button: Button, pressed: bool) {
+        self.events.push((frame, button, pressed));
+        // stable, so presses and releases on the same frame keep their order
+        self.events.sort_by_key(|&(frame, _, _)| frame);
+    }
+
+    // Presses and releases what the script has for `frame`
+    pub fn apply(&self, gameboy: &mut GameBoy, frame: usize) {
+        for &(_, button, pressed) in self.events.iter().filter(|&&(at, _, _)| at == frame) {
+            gameboy.set_button(button, pressed);
+        }
+    }
+}
+
+// Returns how many whole frames ran
+pub fn run_headless(
+    gameboy: &mut GameBoy,
+    stop: Stop,
+    input: &InputScript,
+) -> Result<usize, EmuError> {
+    let max_cycles = match stop {
+        Stop::Frames(frames) | Stop::Breakpoint(frames) => frames * CYCLES_PER_FRAME,
+        Stop::Cycles(cycles) => cycles,
+    };
+    let mut cycles = 0;
+    let mut frame = 0;
+    let mut frame_cycles = 0;
+    input.apply(gameboy, frame);
+    while cycles < max_cycles {
+        let pc = gameboy.get_cpu().get_register_pair(Reg16::Pc);
+        let opcode = gameboy.get_mmu().read_byte(pc);
+        let clocks = gameboy.step_instruction()?;
+        cycles += clocks;
+        frame_cycles += clocks;
+        if frame_cycles >= CYCLES_PER_FRAME {
+            frame_cycles -= CYCLES_PER_FRAME;
+            frame += 1;
+            input.apply(gameboy, frame);
+        }
+        if let Stop::Breakpoint(_) = stop {
+            if opcode == BREAKPOINT && !gameboy.get_cpu().is_halted() {
+                break;
+            }
+        }
+    }
+    Ok(frame)
+}
+
+// The whole address space as the CPU sees it
+pub fn dump_memory(gameboy: &GameBoy) -> Vec<u8> {
+    (0..=0xFFFF)
+        .map(|address| gameboy.get_mmu().read_byte(address))
+        .collect()
+}
+
+pub fn dump_registers(gameboy: &GameBoy) -> String {
+    format!("{:?}", gameboy.get_cpu())
+}
@@ -1,136 +1,271 @@
-#[derive(Debug)]
+use crate::cpu::{Reg16, Reg8};
+
+// The SM83 opcodes follow a pattern, splitting the byte as
+//   x = bits 7-6, y = bits 5-3, z = bits 2-0, p = bits 5-4, q = bit 3
+// the register, pair, condition and operation of most instructions can be
+// read straight from those fields.
+
+// r[] - the 8 bit operand picked by y or z, 6 is the byte at (HL)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Operand {
+    Reg(Reg8),
+    HlIndirect,
+    Immediate(u8),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AluOp {
+    Add,
+    Adc,
+    Sub,
+    Sbc,
+    And,
+    Xor,
+    Or,
+    Cp,
+}
+
+// the CB prefixed rotates and shifts
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RotOp {
+    Rlc,
+    Rrc,
+    Rl,
+    Rr,
+    Sla,
+    Sra,
+    Swap,
+    Srl,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Condition {
+    Nz,
+    Z,
+    Nc,
+    C,
+}
+
+// where LD A,(...) and LD (...),A go to
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Address {
+    Bc,
+    De,
+    // (HL+) and (HL-)
+    HlIncrement,
+    HlDecrement,
+    Direct(u16),
+    // $FF00 + C
+    HighC,
+    // $FF00 + n
+    High(u8),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Instruction {
     Nop,
-    LdBc(u16),
-    LdDe(u16),
-    LdHl(u16),
-    LdHln(u8),
-    LdSp(u16),
-    LdA(u8),
-    LdB(u8),
-    LdC(u8),
-    LdD(u8),
-    LdE(u8),
-    LdH(u8),
-    LdL(u8),
-    LdAa,
-    LdBa,
-    LdCa,
-    LdDa,
-    LdEa,
-    LdHa,
-    LdLa,
-    LdBcA,
-    LdDeA,
-    LdHlA,
-    LdXxA(u16),
-    LdFf00U8a(u8),
-    LdAFf00U8(u8),
-    LdFf00Ca,
-    LddHlA,
-    LdiHlA,
-    LdiAHl,
-    LdABc,
-    LdADe,
-    LdAb,
-    LdAc,
-    LdAd,
-    LdAe,
-    LdAh,
-    LdAl,
-    XorA,
-    XorB,
-    XorC,
-    XorD,
-    XorE,
-    XorH,
-    XorL,
-    XorHl,
-    Xor(u8),
-    BitbA(u8),
-    BitbB(u8),
-    BitbC(u8),
-    BitbD(u8),
-    BitbE(u8),
-    BitbH(u8),
-    BitbL(u8),
-    BitbHL(u8),
-    Jr(i8),
-    JrNz(i8),
-    JrZ(i8),
-    JrNc(i8),
-    JrC(i8),
-    Jp(u16),
-    IncA,
-    IncB,
-    IncC,
-    IncD,
-    IncE,
-    IncH,
-    IncL,
-    IncBc,
-    IncDe,
-    IncHl,
-    IncSp,
-    IncHlNoflags,
-    Call(u16),
-    CallNz(u16),
-    CallZ(u16),
-    CallNc(u16),
-    CallC(u16),
-    PushAf,
-    PushBc,
-    PushDe,
-    PushHl,
-    PopAf,
-    PopBc,
-    PopDe,
-    PopHl,
-    RlA,
-    RlB,
-    RlC,
-    RlD,
-    RlE,
-    RlH,
-    RlL,
-    RlHl,
-    RLA,
-    DecA,
-    DecB,
-    DecC,
-    DecD,
-    DecE,
-    DecH,
-    DecL,
-    DecHl,
-    SubA,
-    SubB,
-    SubC,
-    SubD,
-    SubE,
-    SubH,
-    SubL,
-    SubHl,
-    Sub(u8),
-    AddAa,
-    AddAb,
-    AddAc,
-    AddAd,
-    AddAe,
-    AddAh,
-    AddAl,
-    AddAhl,
-    AddA(u8),
-    Ret,
-    CpA,
-    CpB,
-    CpC,
-    CpD,
-    CpE,
-    CpH,
-    CpL,
-    CpHl,
-    Cp(u8),
+    Stop,
+    Halt,
     Di,
     Ei,
+    Ld(Reg8, Reg8),
+    LdImmediate(Reg8, u8),
+    // LD r,(HL) and LD (HL),r
+    LdFromHl(Reg8),
+    LdToHl(Reg8),
+    LdHlImmediate(u8),
+    LdAFrom(Address),
+    LdATo(Address),
+    Ld16(Reg16, u16),
+    LdAddressSp(u16),
+    LdSpHl,
+    LdHlSpOffset(i8),
+    Push(Reg16),
+    Pop(Reg16),
+    Alu(AluOp, Operand),
+    Inc(Operand),
+    Dec(Operand),
+    Inc16(Reg16),
+    Dec16(Reg16),
+    AddHl(Reg16),
+    AddSp(i8),
+    Rlca,
+    Rrca,
+    Rla,
+    Rra,
+    Daa,
+    Cpl,
+    Scf,
+    Ccf,
+    Jp(Option<Condition>, u16),
+    JpHl,
+    Jr(Option<Condition>, i8),
+    Call(Option<Condition>, u16),
+    Ret(Option<Condition>),
+    Reti,
+    Rst(u16),
+    Rot(RotOp, Operand),
+    Bit(u8, Operand),
+    Res(u8, Operand),
+    Set(u8, Operand),
+    // 0xD3, 0xDB, 0xDD, 0xE3, 0xE4, 0xEB, 0xEC, 0xED, 0xF4, 0xFC and 0xFD
+    Illegal(u8),
+}
+
+const R: [Operand; 8] = [
+    Operand::Reg(Reg8::B),
+    Operand::Reg(Reg8::C),
+    Operand::Reg(Reg8::D),
+    Operand::Reg(Reg8::E),
+    Operand::Reg(Reg8::H),
+    Operand::Reg(Reg8::L),
+    Operand::HlIndirect,
+    Operand::Reg(Reg8::A),
+];
+const RP: [Reg16; 4] = [Reg16::Bc, Reg16::De, Reg16::Hl, Reg16::Sp];
+// PUSH and POP use AF instead of SP
+const RP2: [Reg16; 4] = [Reg16::Bc, Reg16::De, Reg16::Hl, Reg16::Af];
+const CC: [Condition; 4] = [Condition::Nz, Condition::Z, Condition::Nc, Condition::C];
+const ALU: [AluOp; 8] = [
+    AluOp::Add,
+    AluOp::Adc,
+    AluOp::Sub,
+    AluOp::Sbc,
+    AluOp::And,
+    AluOp::Xor,
+    AluOp::Or,
+    AluOp::Cp,
+];
+const ROT: [RotOp; 8] = [
+    RotOp::Rlc,
+    RotOp::Rrc,
+    RotOp::Rl,
+    RotOp::Rr,
+    RotOp::Sla,
+    RotOp::Sra,
+    RotOp::Swap,
+    RotOp::Srl,
+];
+
+fn register(operand: Operand) -> Reg8 {
+    match operand {
+        Operand::Reg(register) => register,
+        _ => unreachable!("only (HL) is not a register and it is handled apart"),
+    }
+}
+
+impl Instruction {
+    // `low` and `high` are the bytes after the opcode, only looked at by the
+    // instructions that have them. 0xCB is decoded with decode_cb.
+    pub fn decode(opcode: u8, low: u8, high: u8) -> Instruction {
+        let x = opcode >> 6;
+        let y = ((opcode >> 3) & 0b111) as usize;
+        let z = opcode & 0b111;
+        let p = y >> 1;
+        let q = y & 0b1;
+        let n = low;
+        let nn = ((high as u16) << 8) | low as u16;
+        let e = low as i8;
+
+        match x {
+            0 => match z {
+                0 => match y {
+                    0 => Instruction::Nop,
+                    1 => Instruction::LdAddressSp(nn),
+                    2 => Instruction::Stop,
+                    3 => Instruction::Jr(None, e),
+                    _ => Instruction::Jr(Some(CC[y - 4]), e),
+                },
+                1 if q == 0 => Instruction::Ld16(RP[p], nn),
+                1 => Instruction::AddHl(RP[p]),
+                2 => {
+                    let address = [
+                        Address::Bc,
+                        Address::De,
+                        Address::HlIncrement,
+                        Address::HlDecrement,
+                    ][p];
+                    if q == 0 {
+                        Instruction::LdATo(address)
+                    } else {
+                        Instruction::LdAFrom(address)
+                    }
+                }
+                3 if q == 0 => Instruction::Inc16(RP[p]),
+                3 => Instruction::Dec16(RP[p]),
+                4 => Instruction::Inc(R[y]),
+                5 => Instruction::Dec(R[y]),
+                6 => match R[y] {
+                    Operand::HlIndirect => Instruction::LdHlImmediate(n),
+                    operand => Instruction::LdImmediate(register(operand), n),
+                },
+                _ => [
+                    Instruction::Rlca,
+                    Instruction::Rrca,
+                    Instruction::Rla,
+                    Instruction::Rra,
+                    Instruction::Daa,
+                    Instruction::Cpl,
+                    Instruction::Scf,
+                    Instruction::Ccf,
+                ][y],
+            },
+            1 => match (R[y], R[z as usize]) {
+                (Operand::HlIndirect, Operand::HlIndirect) => Instruction::Halt,
+                (Operand::HlIndirect, from) => Instruction::LdToHl(register(from)),
+                (to, Operand::HlIndirect) => Instruction::LdFromHl(register(to)),
+                (to, from) => Instruction::Ld(register(to), register(from)),
+            },
+            2 => Instruction::Alu(ALU[y], R[z as usize]),
+            _ => match z {
+                0 => match y {
+                    0..=3 => Instruction::Ret(Some(CC[y])),
+                    4 => Instruction::LdATo(Address::High(n)),
+                    5 => Instruction::AddSp(e),
+                    6 => Instruction::LdAFrom(Address::High(n)),
+                    _ => Instruction::LdHlSpOffset(e),
+                },
+                1 if q == 0 => Instruction::Pop(RP2[p]),
+                1 => [
+                    Instruction::Ret(None),
+                    Instruction::Reti,
+                    Instruction::JpHl,
+                    Instruction::LdSpHl,
+                ][p],
+                2 => match y {
+                    0..=3 => Instruction::Jp(Some(CC[y]), nn),
+                    4 => Instruction::LdATo(Address::HighC),
+                    5 => Instruction::LdATo(Address::Direct(nn)),
+                    6 => Instruction::LdAFrom(Address::HighC),
+                    _ => Instruction::LdAFrom(Address::Direct(nn)),
+                },
+                3 => match y {
+                    0 => Instruction::Jp(None, nn),
+                    6 => Instruction::Di,
+                    7 => Instruction::Ei,
+                    // 1 is the CB prefix
+                    _ => Instruction::Illegal(opcode),
+                },
+                4 if y < 4 => Instruction::Call(Some(CC[y]), nn),
+                5 if q == 0 => Instruction::Push(RP2[p]),
+                5 if p == 0 => Instruction::Call(None, nn),
+                6 => Instruction::Alu(ALU[y], Operand::Immediate(n)),
+                7 => Instruction::Rst((y * 8) as u16),
+                _ => Instruction::Illegal(opcode),
+            },
+        }
+    }
+
+    // `opcode` is the byte after 0xCB
+    pub fn decode_cb(opcode: u8) -> Instruction {
+        let x = opcode >> 6;
+        let y = (opcode >> 3) & 0b111;
+        let operand = R[(opcode & 0b111) as usize];
+        match x {
+            0 => Instruction::Rot(ROT[y as usize], operand),
+            1 => Instruction::Bit(y, operand),
+            2 => Instruction::Res(y, operand),
+            _ => Instruction::Set(y, operand),
+        }
+    }
 }
@@ -0,0 +1,98 @@
+use crate::savestate::{StateError, StateReader, StateWriter};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Button {
+    Right,
+    Left,
+    Up,
+    Down,
+    A,
+    B,
+    Select,
+    Start,
+}
+
+impl Button {
+    // low nibble are the direction keys, high nibble the action buttons,
+    // each in the order they show up in P1
+    fn mask(self) -> u8 {
+        match self {
+            Button::Right => 0b0000_0001,
+            Button::Left => 0b0000_0010,
+            Button::Up => 0b0000_0100,
+            Button::Down => 0b0000_1000,
+            Button::A => 0b0001_0000,
+            Button::B => 0b0010_0000,
+            Button::Select => 0b0100_0000,
+            Button::Start => 0b1000_0000,
+        }
+    }
+}
+
+// $FF00 - P1/JOYP
+pub struct Joypad {
+    pressed: u8,
+    select: u8,
+}
+
+impl Joypad {
+    pub fn new() -> Joypad {
+        Joypad {
+            pressed: 0,
+            select: 0b0011_0000,
+        }
+    }
+
+    pub fn write(&mut self, value: u8) {
+        self.select = value & 0b0011_0000;
+    }
+
+    pub fn read(&self) -> u8 {
+        // 0 means pressed, and only for the selected group
+        let mut keys = 0b0000_1111;
+        if (self.select & 0b0001_0000) == 0 {
+            keys &= !(self.pressed & 0b0000_1111);
+        }
+        if (self.select & 0b0010_0000) == 0 {
+            keys &= !(self.pressed >> 4);
+        }
+        0b1100_0000 | self.select | keys
+    }
+
+    pub fn get_select(&self) -> u8 {
+        self.select
+    }
+
+    // Returns true when the button just went down, which requests the
+    // joypad interrupt
+    pub fn set_button(&mut self, button: Button, pressed: bool) -> bool {
+        let was_pressed = (self.pressed & button.mask()) != 0;
+        if pressed {
+            self.pressed |= button.mask();
+        } else {
+            self.pressed &= !button.mask();
+        }
+        pressed && !was_pressed
+    }
+
+    pub fn is_pressed(&self, button: Button) -> bool {
+        (self.pressed & button.mask()) != 0
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.pressed);
+        state.write_u8(self.select);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.pressed = state.read_u8()?;
+        self.select = state.read_u8()?;
+        Ok(())
+    }
+}
+
+impl Default for Joypad {
+    fn default() -> Joypad {
+        Joypad::new()
+    }
+}
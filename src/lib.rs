@@ -1,4 +1,25 @@
+pub mod cartridge;
+pub mod cli;
+pub mod compat;
 pub mod cpu;
+pub mod debugger;
+pub mod error;
+pub mod gameboy;
+pub mod headless;
 pub mod instruction;
+pub mod joypad;
+pub mod link;
 pub mod mmu;
+pub mod movie;
+pub mod opcodes;
+pub mod pacer;
 pub mod ppu;
+pub mod printer;
+pub mod rewind;
+pub mod romfile;
+pub mod savestate;
+pub mod screenshot;
+pub mod serial;
+pub mod sgb;
+pub mod testrom;
+pub mod timer;
@@ -0,0 +1,360 @@
+// Link cable between two emulators. Over a socket each side runs on its own
+// and the transfer of the one driving the clock holds until the other
+// answers, which keeps them in step on every transfer. LocalLink does the same inside one process
+// for tests, with run_lockstep deciding who runs next so it is deterministic.
+
+use crate::cpu::CPU;
+use crate::error::EmuError;
+use crate::gameboy::GameBoy;
+use crate::mmu::MMU;
+use crate::ppu::PPU;
+use crate::serial::SerialPeer;
+
+use std::cell::RefCell;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+#[cfg(unix)]
+use std::path::Path;
+use std::rc::Rc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// every message is 2 bytes: what it is and the byte being shifted
+const TRANSFER: u8 = 0x01;
+const REPLY: u8 = 0x02;
+// if the other side doesn't answer by then we act as if the cable was pulled
+const REPLY_TIMEOUT: Duration = Duration::from_secs(2);
+
+enum Stream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.read(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.write(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.flush(),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.flush(),
+        }
+    }
+}
+
+pub struct SocketLink {
+    stream: Stream,
+    // bytes of a message that hasn't fully arrived yet
+    partial: Vec<u8>,
+    connected: bool,
+    // the byte of the send waiting on its answer, and when we give up on it
+    outgoing: u8,
+    deadline: Option<Instant>,
+}
+
+impl SocketLink {
+    // The first emulator waits for the second one to connect
+    pub fn listen<A: ToSocketAddrs>(addr: A) -> io::Result<SocketLink> {
+        SocketLink::accept(&TcpListener::bind(addr)?)
+    }
+
+    // Same as listen on a listener bound already, e.g. to port 0
+    pub fn accept(listener: &TcpListener) -> io::Result<SocketLink> {
+        let (stream, _) = listener.accept()?;
+        SocketLink::from_tcp(stream)
+    }
+
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> io::Result<SocketLink> {
+        SocketLink::from_tcp(TcpStream::connect(addr)?)
+    }
+
+    fn from_tcp(stream: TcpStream) -> io::Result<SocketLink> {
+        // a transfer is 2 bytes, don't let them sit in a buffer
+        stream.set_nodelay(true)?;
+        stream.set_nonblocking(true)?;
+        Ok(SocketLink::from_stream(Stream::Tcp(stream)))
+    }
+
+    #[cfg(unix)]
+    pub fn listen_unix<P: AsRef<Path>>(path: P) -> io::Result<SocketLink> {
+        let listener = UnixListener::bind(path)?;
+        let (stream, _) = listener.accept()?;
+        stream.set_nonblocking(true)?;
+        Ok(SocketLink::from_stream(Stream::Unix(stream)))
+    }
+
+    #[cfg(unix)]
+    pub fn connect_unix<P: AsRef<Path>>(path: P) -> io::Result<SocketLink> {
+        let stream = UnixStream::connect(path)?;
+        stream.set_nonblocking(true)?;
+        Ok(SocketLink::from_stream(Stream::Unix(stream)))
+    }
+
+    fn from_stream(stream: Stream) -> SocketLink {
+        SocketLink {
+            stream,
+            partial: Vec::with_capacity(2),
+            connected: true,
+            outgoing: 0xFF,
+            deadline: None,
+        }
+    }
+
+    fn write_message(&mut self, kind: u8, byte: u8) {
+        if !self.connected {
+            return;
+        }
+        let message = [kind, byte];
+        let mut written = 0;
+        while written < message.len() {
+            match self.stream.write(&message[written..]) {
+                Ok(0) => {
+                    self.connected = false;
+                    return;
+                }
+                Ok(n) => written += n,
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => thread::yield_now(),
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(_) => {
+                    self.connected = false;
+                    return;
+                }
+            }
+        }
+    }
+
+    // Never blocks, returns a message only once both of its bytes are here
+    fn read_message(&mut self) -> Option<(u8, u8)> {
+        while self.connected && self.partial.len() < 2 {
+            let mut buf = [0; 2];
+            let wanted = 2 - self.partial.len();
+            match self.stream.read(&mut buf[..wanted]) {
+                Ok(0) => self.connected = false,
+                Ok(n) => self.partial.extend_from_slice(&buf[..n]),
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => return None,
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(_) => self.connected = false,
+            }
+        }
+        if self.partial.len() < 2 {
+            return None;
+        }
+        let message = (self.partial[0], self.partial[1]);
+        self.partial.clear();
+        Some(message)
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.connected
+    }
+}
+
+impl SerialPeer for SocketLink {
+    fn send(&mut self, outgoing: u8) -> Option<u8> {
+        self.write_message(TRANSFER, outgoing);
+        self.outgoing = outgoing;
+        self.deadline = Some(Instant::now() + REPLY_TIMEOUT);
+        self.poll_reply()
+    }
+
+    fn poll_reply(&mut self) -> Option<u8> {
+        while let Some(message) = self.read_message() {
+            match message {
+                (REPLY, incoming) => {
+                    self.deadline = None;
+                    return Some(incoming);
+                }
+                // both sides started with the internal clock at once, let
+                // the other one finish so it can answer us
+                (TRANSFER, _) => self.write_message(REPLY, self.outgoing),
+                _ => {}
+            }
+        }
+        let timed_out = match self.deadline {
+            Some(deadline) => Instant::now() >= deadline,
+            None => true,
+        };
+        if !self.connected || timed_out {
+            self.deadline = None;
+            return Some(0xFF);
+        }
+        None
+    }
+
+    fn receive(&mut self, shift_register: u8) -> Option<u8> {
+        match self.read_message() {
+            Some((TRANSFER, incoming)) => {
+                self.write_message(REPLY, shift_register);
+                Some(incoming)
+            }
+            _ => None,
+        }
+    }
+}
+
+struct Wire {
+    // bytes clocked into each side, waiting to be picked up
+    pending: [Option<u8>; 2],
+    // each side's SB, kept up to date as it changes
+    shift_registers: [u8; 2],
+}
+
+pub struct LocalLink {
+    wire: Rc<RefCell<Wire>>,
+    side: usize,
+}
+
+impl LocalLink {
+    // Both ends of the cable, plug one into each emulator
+    pub fn pair() -> (LocalLink, LocalLink) {
+        let wire = Rc::new(RefCell::new(Wire {
+            pending: [None, None],
+            shift_registers: [0xFF, 0xFF],
+        }));
+        (
+            LocalLink {
+                wire: wire.clone(),
+                side: 0,
+            },
+            LocalLink { wire, side: 1 },
+        )
+    }
+}
+
+impl SerialPeer for LocalLink {
+    fn send(&mut self, outgoing: u8) -> Option<u8> {
+        let mut wire = self.wire.borrow_mut();
+        let other = 1 - self.side;
+        wire.pending[other] = Some(outgoing);
+        Some(wire.shift_registers[other])
+    }
+
+    fn receive(&mut self, _shift_register: u8) -> Option<u8> {
+        self.wire.borrow_mut().pending[self.side].take()
+    }
+
+    // so send() swaps with what the other side has right now
+    fn shift_register_changed(&mut self, shift_register: u8) {
+        self.wire.borrow_mut().shift_registers[self.side] = shift_register;
+    }
+}
+
+// Something that runs one instruction at a time and tells how many clocks
+// it took
+pub trait Steppable {
+    fn step(&mut self) -> Result<usize, EmuError>;
+}
+
+impl Steppable for (CPU, MMU, PPU) {
+    fn step(&mut self) -> Result<usize, EmuError> {
+        self.0.run_instruction(&mut self.1, &mut self.2)
+    }
+}
+
+impl Steppable for GameBoy {
+    fn step(&mut self) -> Result<usize, EmuError> {
+        self.step_instruction()
+    }
+}
+
+// Runs both for `clocks`, always stepping the one that is behind, so the
+// same inputs give the same run every time
+pub fn run_lockstep<A: Steppable, B: Steppable>(
+    a: &mut A,
+    b: &mut B,
+    clocks: usize,
+) -> Result<(), EmuError> {
+    let mut a_clocks = 0;
+    let mut b_clocks = 0;
+    while a_clocks < clocks || b_clocks < clocks {
+        if a_clocks <= b_clocks {
+            a_clocks += a.step()?;
+        } else {
+            b_clocks += b.step()?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mmu::Model;
+
+    // Puts `sb` in SB, waits `delay` times round a loop and starts a
+    // transfer with `sc`, then spins
+    fn gameboy(sb: u8, delay: u8, sc: u8) -> GameBoy {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x10F].copy_from_slice(&[
+            0x3E, sb, // LD A,sb
+            0xE0, 0x01, // LDH ($01),A
+            0x06, delay, // LD B,delay
+            0x05,  // DEC B
+            0x20, 0xFD, // JR NZ,-3
+            0x3E, sc, // LD A,sc
+            0xE0, 0x02, // LDH ($02),A
+            0x18, 0xFE, // JR -2
+        ]);
+        GameBoy::new(Model::Dmg, &rom, None, None).unwrap()
+    }
+
+    fn assert_swapped(master: &GameBoy, slave: &GameBoy) {
+        for (gameboy, sb) in [(master, 0x99), (slave, 0x42)].iter() {
+            let mmu = gameboy.get_mmu();
+            assert_eq!(mmu.read_byte(0xFF01), *sb);
+            assert_eq!(mmu.read_byte(0xFF02) & 0b1000_0000, 0);
+            assert_eq!(mmu.read_byte(0xFF0F) & 0b0000_1000, 0b0000_1000);
+        }
+    }
+
+    #[test]
+    fn both_sides_swap_sb_in_lockstep() {
+        // the one on the external clock only gets ready long after the
+        // byte came in
+        let mut master = gameboy(0x42, 1, 0x81);
+        let mut slave = gameboy(0x99, 255, 0x80);
+        let (master_end, slave_end) = LocalLink::pair();
+        master.get_mmu_mut().connect_serial(Box::new(master_end));
+        slave.get_mmu_mut().connect_serial(Box::new(slave_end));
+        run_lockstep(&mut master, &mut slave, 20_000).unwrap();
+        assert_swapped(&master, &slave);
+    }
+
+    #[test]
+    fn both_sides_swap_sb_over_a_loopback_socket() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = SocketLink::connect(listener.local_addr().unwrap()).unwrap();
+        let server = SocketLink::accept(&listener).unwrap();
+        let mut master = gameboy(0x42, 1, 0x81);
+        let mut slave = gameboy(0x99, 255, 0x80);
+        master.get_mmu_mut().connect_serial(Box::new(client));
+        slave.get_mmu_mut().connect_serial(Box::new(server));
+
+        // the master's transfer waits on the slave, which takes its time
+        // getting ready, without holding up the master's instructions
+        run_lockstep(&mut master, &mut slave, 2_000).unwrap();
+        assert!(master.is_link_waiting());
+        run_lockstep(&mut master, &mut slave, 20_000).unwrap();
+        assert!(!master.is_link_waiting());
+        assert_swapped(&master, &slave);
+    }
+}
@@ -1,49 +1,428 @@
-use gbrustemu::cpu::CPU;
-use gbrustemu::mmu::MMU;
-use gbrustemu::ppu::{LIGHTEST_GREEN, PPU, SCREEN_HEIGHT, SCREEN_WIDTH};
-use minifb::{Key, Window, WindowOptions};
-use std::fs::File;
-use std::io::Read;
-use std::time::Instant;
+use gbrustemu::cartridge::has_battery;
+use gbrustemu::cli::{parse_args, Command, LinkCable, Options, USAGE};
+use gbrustemu::debugger;
+use gbrustemu::error::EmuError;
+use gbrustemu::gameboy::GameBoy;
+use gbrustemu::headless::{dump_memory, dump_registers, run_headless, InputScript, Stop};
+use gbrustemu::link::SocketLink;
+use gbrustemu::mmu::{is_cgb_rom, Model, DMG_BOOT_ROM};
+use gbrustemu::movie::{Movie, MoviePlayer, MovieRecorder};
+use gbrustemu::romfile::load_rom;
+use gbrustemu::screenshot::{capture, write_png};
+use gbrustemu::testrom::{format_matrix, run_test_rom_dir, Outcome, DEFAULT_TIMEOUT_CYCLES};
+use std::env;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::process;
 
-fn main() {
-    //    Read the rom file
-    let mut f = File::open("ROMS/tetris.gb").unwrap();
-    let mut rom_file = Vec::<u8>::new();
-    f.read_to_end(&mut rom_file).unwrap();
-
-    // put the rom file into the memory ram
-    let mut mmu = MMU::new();
-    mmu.from_rom_file(&rom_file);
-
-    // run make CPU run instructions over ram
-    //    println!("MMU BEFORE: {:?}", mmu);
-    let mut cpu = CPU::new();
-    let mut ppu = PPU::new();
-    //        cpu.set_debug_flag();
-
-    let mut screen = vec![LIGHTEST_GREEN; SCREEN_WIDTH * SCREEN_HEIGHT];
-    let mut window = Window::new(
-        "Test - ESC to exit",
-        SCREEN_WIDTH,
-        SCREEN_HEIGHT,
-        WindowOptions::default(),
-    )
-    .unwrap_or_else(|e| {
-        panic!("{}", e);
+// --record and --play
+enum MovieMode {
+    Off,
+    Recording(MovieRecorder),
+    Playing(MoviePlayer),
+}
+
+// Returns false when the screen didn't match the recording
+fn report_playback(player: &MoviePlayer) -> bool {
+    println!(
+        "played {} of {} movie frames",
+        player.get_frame(),
+        player.get_movie().get_frames()
+    );
+    match player.get_divergence() {
+        Some(divergence) => {
+            println!(
+                "the screen differs from frame {} on (hash {:08X}, recorded {:08X})",
+                divergence.frame, divergence.actual, divergence.expected
+            );
+            false
+        }
+        None => true,
+    }
+}
+
+#[cfg(feature = "window")]
+mod window {
+    use super::{exit_with, report_playback, MovieMode};
+    use gbrustemu::cli::Options;
+    use gbrustemu::error::EmuError;
+    use gbrustemu::gameboy::GameBoy;
+    use gbrustemu::headless::dump_registers;
+    use gbrustemu::joypad::Button;
+    use gbrustemu::pacer::FramePacer;
+    use gbrustemu::rewind::Rewind;
+    use minifb::{Key, KeyRepeat, Scale, Window, WindowOptions};
+    use std::fs;
+
+    const KEY_MAP: [(Key, Button); 8] = [
+        (Key::Right, Button::Right),
+        (Key::Left, Button::Left),
+        (Key::Up, Button::Up),
+        (Key::Down, Button::Down),
+        (Key::Z, Button::A),
+        (Key::X, Button::B),
+        (Key::Backspace, Button::Select),
+        (Key::Enter, Button::Start),
+    ];
+
+    const REWIND_KEY: Key = Key::R;
+    // F1-F4 load a save state slot, with Shift they save it
+    const STATE_KEYS: [Key; 4] = [Key::F1, Key::F2, Key::F3, Key::F4];
+    // half a second of the link cable holding up a transfer before the title
+    // says so, a normal transfer only waits a frame or two
+    const LINK_STALL_FRAMES: usize = 30;
+
+    fn scale(factor: usize) -> Scale {
+        match factor {
+            1 => Scale::X1,
+            2 => Scale::X2,
+            4 => Scale::X4,
+            8 => Scale::X8,
+            16 => Scale::X16,
+            _ => Scale::X32,
+        }
+    }
+
+    // A bad slot is reported and the game goes on
+    fn handle_state_keys(
+        window: &Window,
+        gameboy: &mut GameBoy,
+        options: &Options,
+        rom_name: &str,
+    ) {
+        let saving = window.is_key_down(Key::LeftShift) || window.is_key_down(Key::RightShift);
+        for (slot, key) in STATE_KEYS.iter().enumerate() {
+            if !window.is_key_pressed(*key, KeyRepeat::No) {
+                continue;
+            }
+            let path = options.get_state_path(rom_name, slot + 1);
+            let result = if saving {
+                fs::write(&path, gameboy.save_state()).map_err(|error| error.to_string())
+            } else {
+                fs::read(&path)
+                    .map_err(|error| error.to_string())
+                    .and_then(|state| {
+                        gameboy
+                            .load_state(&state)
+                            .map_err(|error| error.to_string())
+                    })
+            };
+            match result {
+                Ok(()) if saving => println!("saved state {} to {}", slot + 1, path.display()),
+                Ok(()) => println!("loaded state {} from {}", slot + 1, path.display()),
+                Err(error) => eprintln!("{}: {}", path.display(), error),
+            }
+        }
+    }
+
+    fn run_frame(gameboy: &mut GameBoy, movie: &mut MovieMode) -> Result<(), EmuError> {
+        match movie {
+            MovieMode::Off => gameboy.run_frame(),
+            MovieMode::Recording(recorder) => recorder.run_frame(gameboy),
+            MovieMode::Playing(player) => player.run_frame(gameboy).map(|_| ()),
+        }
+    }
+
+    // While a movie records or plays there is no rewinding or loading states,
+    // and a movie playing has the buttons until it is over
+    pub fn run(gameboy: &mut GameBoy, options: &Options, rom_name: &str, movie: &mut MovieMode) {
+        let title = format!("{} - ESC to exit", options.rom_path.display());
+        let waiting_title = format!("{} - waiting for the link cable", title);
+        let mut link_waiting_frames = 0;
+        let window_options = WindowOptions {
+            scale: scale(options.scale),
+            ..WindowOptions::default()
+        };
+        let mut window = Window::new(
+            &title,
+            gameboy.get_screen_width(),
+            gameboy.get_screen_height(),
+            window_options,
+        )
+        .unwrap_or_else(|error| exit_with(format!("can't open the window: {}", error)));
+        window
+            .update_with_buffer(gameboy.get_framebuffer())
+            .unwrap();
+
+        // there is no sound output yet, so the wall clock drives the frames
+        let mut pacer = FramePacer::new();
+        let rewinding_enabled = options.rewind_budget > 0;
+        let mut rewind = Rewind::new(options.rewind_interval, options.rewind_budget);
+
+        while window.is_open() && !window.is_key_down(Key::Escape) {
+            let movie_running = !matches!(movie, MovieMode::Off);
+            if !movie_running && rewinding_enabled && window.is_key_down(REWIND_KEY) {
+                // one snapshot back per frame while the key is held
+                if let Err(error) = rewind.rewind(gameboy) {
+                    eprintln!("can't rewind: {}", error);
+                }
+            } else {
+                match run_frame(gameboy, movie) {
+                    Ok(()) if rewinding_enabled && !movie_running => rewind.record(gameboy),
+                    Ok(()) => {}
+                    // the game hangs like it would on the real thing, keep showing it
+                    Err(error @ EmuError::IllegalOpcode { .. }) => {
+                        eprintln!("{}", error);
+                        if options.debug {
+                            eprintln!("{}", dump_registers(gameboy));
+                        }
+                    }
+                    Err(error) => {
+                        if options.debug {
+                            eprintln!("{}", dump_registers(gameboy));
+                        }
+                        exit_with(error.to_string());
+                    }
+                }
+            }
+
+            if let MovieMode::Playing(player) = movie {
+                if player.is_finished() {
+                    report_playback(player);
+                    *movie = MovieMode::Off;
+                }
+            }
+            if !matches!(movie, MovieMode::Playing(_)) {
+                for (key, button) in KEY_MAP.iter() {
+                    gameboy.set_button(*button, window.is_key_down(*key));
+                }
+            }
+            if !movie_running {
+                handle_state_keys(&window, gameboy, options, rom_name);
+            }
+
+            // the game keeps running while the other side is slow to answer,
+            // say so once it has been a while
+            let was_stalled = link_waiting_frames >= LINK_STALL_FRAMES;
+            if gameboy.is_link_waiting() {
+                link_waiting_frames += 1;
+            } else {
+                link_waiting_frames = 0;
+            }
+            let stalled = link_waiting_frames >= LINK_STALL_FRAMES;
+            if stalled != was_stalled {
+                window.set_title(if stalled { &waiting_title } else { &title });
+            }
+            window
+                .update_with_buffer(gameboy.get_framebuffer())
+                .unwrap();
+            pacer.end_frame();
+        }
+    }
+}
+
+#[cfg(not(feature = "window"))]
+mod window {
+    use super::{exit_with, MovieMode};
+    use gbrustemu::cli::Options;
+    use gbrustemu::gameboy::GameBoy;
+
+    pub fn run(
+        _gameboy: &mut GameBoy,
+        _options: &Options,
+        _rom_name: &str,
+        _movie: &mut MovieMode,
+    ) {
+        exit_with("built without the window feature, run with --headless".to_string());
+    }
+}
+
+fn exit_with(message: String) -> ! {
+    eprintln!("{}", message);
+    process::exit(1);
+}
+
+fn read_file(path: &Path) -> Vec<u8> {
+    fs::read(path).unwrap_or_else(|error| exit_with(format!("{}: {}", path.display(), error)))
+}
+
+// gbrustemu test-roms <dir>: runs every test ROM in there, no window
+fn run_test_roms(dir: &Path) {
+    let results = match run_test_rom_dir(dir, DEFAULT_TIMEOUT_CYCLES) {
+        Ok(results) => results,
+        Err(error) => exit_with(format!("{}: {}", dir.display(), error)),
+    };
+    print!("{}", format_matrix(&results));
+    if results
+        .iter()
+        .any(|(_, outcome)| *outcome != Outcome::Passed)
+    {
+        process::exit(1);
+    }
+}
+
+// A movie playing runs to its end whatever --frames says
+fn run_headless_movie(
+    gameboy: &mut GameBoy,
+    options: &Options,
+    input: &InputScript,
+    movie: &mut MovieMode,
+) -> Result<usize, EmuError> {
+    match movie {
+        MovieMode::Off => run_headless(gameboy, options.stop, input),
+        MovieMode::Recording(recorder) => {
+            // the command line only takes --frames with --record
+            let frames = match options.stop {
+                Stop::Frames(frames) => frames,
+                _ => 0,
+            };
+            for frame in 0..frames {
+                input.apply(gameboy, frame);
+                recorder.run_frame(gameboy)?;
+            }
+            Ok(frames)
+        }
+        MovieMode::Playing(player) => {
+            while player.run_frame(gameboy)? {}
+            Ok(player.get_frame())
+        }
+    }
+}
+
+fn run_headless_and_dump(gameboy: &mut GameBoy, options: &Options, movie: &mut MovieMode) {
+    let input = match &options.input_path {
+        Some(path) => InputScript::from_file(path)
+            .unwrap_or_else(|error| exit_with(format!("{}: {}", path.display(), error))),
+        None => InputScript::new(),
+    };
+    let frames = run_headless_movie(gameboy, options, &input, movie).unwrap_or_else(|error| {
+        if options.debug {
+            eprintln!("{}", dump_registers(gameboy));
+        }
+        exit_with(error.to_string())
     });
 
-    while window.is_open() && !window.is_key_down(Key::Escape) {
-        cpu.run_instruction(&mut mmu, &mut ppu);
-        //        let now = Instant::now();
-        if ppu.is_lcd_enable(&mmu) {
-            if mmu.dirty_viewport_flag || mmu.dirty_vram_flag {
-                let current_viewport = ppu.get_viewport();
-                window.update_with_buffer(current_viewport).unwrap();
+    println!("{} frames", frames);
+    println!("{}", dump_registers(gameboy));
+    if let Some(path) = &options.screen_path {
+        let screen = capture(gameboy);
+        write_png(
+            path,
+            gameboy.get_screen_width(),
+            gameboy.get_screen_height(),
+            &screen,
+        )
+        .unwrap_or_else(|error| exit_with(format!("{}: {}", path.display(), error)));
+    }
+    if let Some(path) = &options.memory_path {
+        fs::write(path, dump_memory(gameboy))
+            .unwrap_or_else(|error| exit_with(format!("{}: {}", path.display(), error)));
+    }
+}
+
+fn run(options: &Options) {
+    let rom_file = load_rom(&options.rom_path, options.rom_entry.as_deref())
+        .unwrap_or_else(|error| exit_with(format!("{}: {}", options.rom_path.display(), error)));
+    let rom = rom_file.data;
+    let boot_rom = match &options.boot_rom_path {
+        Some(path) => Some(read_file(path)),
+        // we only have the DMG boot ROM, on a CGB games start from the cartridge
+        None if !options.skip_boot_rom && options.model == Model::Dmg && !is_cgb_rom(&rom) => {
+            Some(DMG_BOOT_ROM.to_vec())
+        }
+        None => None,
+    };
+    let save_path = options.get_save_path(&rom_file.name);
+    let save_data = match fs::read(&save_path) {
+        Ok(save_data) => Some(save_data),
+        Err(error) if error.kind() == io::ErrorKind::NotFound => None,
+        Err(error) => exit_with(format!("{}: {}", save_path.display(), error)),
+    };
+
+    let mut gameboy = GameBoy::new(
+        options.model,
+        &rom,
+        boot_rom.as_deref(),
+        save_data.as_deref(),
+    )
+    .unwrap_or_else(|error| exit_with(format!("{}: {}", options.rom_path.display(), error)));
+    gameboy.set_palette(options.get_palette());
+    gameboy.set_color_correction(options.color_correction);
+    if let Some(manual) = options.compat_palette {
+        gameboy.set_manual_palette(manual);
+    }
+    if options.trace {
+        gameboy.get_cpu_mut().set_debug_flag();
+    }
+    if let Some(link) = &options.link {
+        let socket = match link {
+            LinkCable::Listen(addr) => {
+                println!("waiting for the other emulator on {}", addr);
+                SocketLink::listen(addr.as_str())
             }
+            LinkCable::Connect(addr) => SocketLink::connect(addr.as_str()),
+        };
+        let socket = socket.unwrap_or_else(|error| exit_with(format!("link cable: {}", error)));
+        gameboy.connect_serial(Box::new(socket));
+    }
+    if let Some(dir) = &options.printer_dir {
+        fs::create_dir_all(dir)
+            .unwrap_or_else(|error| exit_with(format!("{}: {}", dir.display(), error)));
+        gameboy.connect_printer(dir.clone());
+    }
+    if let Some(path) = &options.state_path {
+        gameboy
+            .load_state(&read_file(path))
+            .unwrap_or_else(|error| exit_with(format!("{}: {}", path.display(), error)));
+    }
+    let mut movie = if let Some(path) = &options.play_path {
+        let movie = Movie::from_bytes(&read_file(path), gameboy.get_rom_checksum())
+            .unwrap_or_else(|error| exit_with(format!("{}: {}", path.display(), error)));
+        let player = MoviePlayer::new(movie, &mut gameboy)
+            .unwrap_or_else(|error| exit_with(format!("{}: {}", path.display(), error)));
+        MovieMode::Playing(player)
+    } else if options.record_path.is_some() {
+        let from_state = options.state_path.is_some();
+        MovieMode::Recording(MovieRecorder::new(
+            &gameboy,
+            from_state,
+            options.hash_interval,
+        ))
+    } else {
+        MovieMode::Off
+    };
+
+    if options.debugger {
+        let stdin = io::stdin();
+        debugger::run(&mut gameboy, stdin.lock(), io::stdout())
+            .unwrap_or_else(|error| exit_with(format!("debugger: {}", error)));
+    } else if options.headless {
+        run_headless_and_dump(&mut gameboy, options, &mut movie);
+    } else {
+        window::run(&mut gameboy, options, &rom_file.name, &mut movie);
+        if options.debug {
+            eprintln!("{}", dump_registers(&gameboy));
+        }
+    }
+
+    if has_battery(&rom) {
+        fs::write(&save_path, gameboy.get_save_data())
+            .unwrap_or_else(|error| exit_with(format!("{}: {}", save_path.display(), error)));
+    }
+    match (movie, &options.record_path) {
+        (MovieMode::Recording(recorder), Some(path)) => {
+            let movie = recorder.finish();
+            fs::write(path, movie.to_bytes())
+                .unwrap_or_else(|error| exit_with(format!("{}: {}", path.display(), error)));
+            println!(
+                "recorded {} frames to {}",
+                movie.get_frames(),
+                path.display()
+            );
         }
-        //        println!("{:?}", Instant::now().duration_since(now));
-        //        println!("{:?}", ppu.get_scy(&mmu));
-        //        println!("{:?}", ppu.get_ly(&mmu));
+        // the window reports it as soon as it is over
+        (MovieMode::Playing(player), _) if !report_playback(&player) => process::exit(1),
+        _ => {}
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    match parse_args(&args) {
+        Ok(Command::Run(options)) => run(&options),
+        Ok(Command::TestRoms(dir)) => run_test_roms(&dir),
+        Ok(Command::Help) => println!("{}", USAGE),
+        Err(error) => exit_with(format!("{}\n\n{}", error, USAGE)),
     }
 }
@@ -1,10 +1,74 @@
+use crate::cartridge::Cartridge;
+use crate::compat::{CompatPalette, ManualPalette};
+use crate::error::EmuError;
+use crate::joypad::{Button, Joypad};
+use crate::savestate::{StateError, StateReader, StateWriter};
+use crate::serial::{Serial, SerialPeer};
+use crate::sgb::{Sgb, TRANSFER_SIZE};
+use crate::timer::Timer;
 use std::fmt;
 use std::fs::File;
 use std::io::Read;
 
+pub const VRAM_BANK_SIZE: usize = 0x2000;
+pub const WRAM_BANK_SIZE: usize = 0x1000;
+
+// $0143 - CGB flag
+const CGB_FLAG_ADDR: usize = 0x0143;
+// the CPU sleeps for 2050 M-cycles while switching speed
+const SPEED_SWITCH_CLOCKS: usize = 8_200;
+// 8 palettes of 4 colors, 2 bytes per color
+pub const PALETTE_RAM_SIZE: usize = 64;
+// VRAM DMA moves 16 bytes per block, halting the CPU for 8 M-cycles each
+const HDMA_BLOCK_SIZE: u16 = 0x10;
+const HDMA_BLOCK_CLOCKS: usize = 32;
+
+pub const DMG_BOOT_ROM: &[u8; 256] = include_bytes!("../ROMS/DMG_ROM.bin");
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Model {
+    Dmg,
+    // the Game Boy Pocket, a DMG that games tell apart by A after the boot
+    Mgb,
+    Cgb,
+    Sgb,
+}
+
 pub struct MMU {
     ram: [u8; 65_536], //0X0000 to 0xFFFF
-    boot_rom: [u8; 256],
+    // 256 bytes on DMG, the CGB one also maps $0200-$08FF
+    boot_rom: Vec<u8>,
+    vram: [[u8; VRAM_BANK_SIZE]; 2], //0x8000 to 0x9FFF, bank 1 only on CGB
+    wram: [[u8; WRAM_BANK_SIZE]; 8], //0xC000 to 0xDFFF, banks 2 to 7 only on CGB
+    vram_bank: usize,
+    wram_bank: usize,
+    model: Model,
+    // CGB features are on, false for DMG games even on CGB hardware
+    cgb_mode: bool,
+    // what the CGB boot ROM colorises monochrome games with, picked by the
+    // header or a button combo
+    compat_palette: CompatPalette,
+    double_speed: bool,
+    speed_switch_armed: bool,
+    bg_palette_ram: [u8; PALETTE_RAM_SIZE],
+    obj_palette_ram: [u8; PALETTE_RAM_SIZE],
+    bcps: u8,
+    ocps: u8,
+    hdma_source: u16,
+    hdma_destination: u16,
+    // blocks left minus one, like HDMA5 reports it
+    hdma_remaining: u8,
+    hdma_hblank_active: bool,
+    // clocks the CPU must stay halted because of a VRAM DMA
+    dma_stall_clocks: usize,
+    // $0000-$7FFF and $A000-$BFFF
+    cartridge: Cartridge,
+    joypad: Joypad,
+    sgb: Sgb,
+    serial: Serial,
+    timer: Timer,
+    // plain 64KB of RAM with nothing mapped, for testing the CPU alone
+    flat: bool,
     pub dirty_vram_flag: bool,
     pub dirty_viewport_flag: bool,
 }
@@ -23,7 +87,10 @@ impl fmt::Debug for MMU {
              $FF48 - OBP0: {:b}, \n\
              $FF49 - OBP1: {:b}, \n\
              $FF4A - WY: {:#X}, \n\
-             $FF4B - WY: {:#X}, \n
+             $FF4B - WY: {:#X}, \n\
+             $FF4D - KEY1: {:b}, \n\
+             $FF4F - VBK: {:?}, \n\
+             $FF70 - SVBK: {:?}, \n
              BG Tile Data: {:?}\n\
              BG Tile Map: {:?}\n\
              ",
@@ -39,8 +106,11 @@ impl fmt::Debug for MMU {
             &self.ram[0xFF49],
             &self.ram[0xFF4A],
             &self.ram[0xFF4B],
-            &self.ram[0x8000..0x87FF],
-            &self.ram[0x9800..0x9BFF],
+            self.read_byte(0xFF4D),
+            &self.vram_bank,
+            &self.wram_bank,
+            &self.vram[0][0x0000..0x07FF],
+            &self.vram[0][0x1800..0x1BFF],
             //            "VRAM: {:?}\n\nOAM RAM: {:?}\n\nIO RAM: {:?}\n\nH RAM: {:?}\n\n",
             //            &self.ram[0x8000..0xA001],
             //            &self.ram[0xFE00..0xFEA1],
@@ -54,37 +124,568 @@ impl MMU {
     pub fn new() -> MMU {
         let mmu = MMU {
             ram: [0; 65_536],
-            boot_rom: *include_bytes!("../ROMS/DMG_ROM.bin"),
+            boot_rom: DMG_BOOT_ROM.to_vec(),
+            vram: [[0; VRAM_BANK_SIZE]; 2],
+            wram: [[0; WRAM_BANK_SIZE]; 8],
+            vram_bank: 0,
+            wram_bank: 1,
+            model: Model::Dmg,
+            cgb_mode: false,
+            compat_palette: CompatPalette::from_header(&[]),
+            double_speed: false,
+            speed_switch_armed: false,
+            bg_palette_ram: [0xFF; PALETTE_RAM_SIZE],
+            obj_palette_ram: [0xFF; PALETTE_RAM_SIZE],
+            bcps: 0,
+            ocps: 0,
+            hdma_source: 0,
+            hdma_destination: 0,
+            hdma_remaining: 0,
+            hdma_hblank_active: false,
+            dma_stall_clocks: 0,
+            cartridge: Cartridge::new(),
+            joypad: Joypad::new(),
+            sgb: Sgb::new(),
+            serial: Serial::new(),
+            timer: Timer::new(),
+            flat: false,
             dirty_vram_flag: false,
             dirty_viewport_flag: false,
         };
         mmu
     }
 
+    // Every address is just RAM, no cartridge, boot ROM or registers. The
+    // CPU doesn't run the rest of the console against it either.
+    pub fn new_flat() -> MMU {
+        let mut mmu = MMU::new();
+        mmu.flat = true;
+        mmu
+    }
+
+    pub fn is_flat(&self) -> bool {
+        self.flat
+    }
+
     pub fn write_byte(&mut self, address: u16, value: u8) {
-        self.ram[address as usize] = value;
-        if address >= 0x8000 && address < 0xA000 {
-            self.dirty_vram_flag = true;
+        if self.flat {
+            self.ram[address as usize] = value;
+            return;
         }
-        if address == 0xFF42 || address == 0xFF43 {
-            self.dirty_viewport_flag = true;
+        match address {
+            0xFF00 => {
+                let previous = self.joypad.get_select();
+                self.joypad.write(value);
+                if self.model == Model::Sgb && self.sgb.write_p1(previous, value) {
+                    self.dirty_viewport_flag = true;
+                }
+            }
+            0xFF01 => self.serial.write_sb(value),
+            0xFF02 => self.serial.write_sc(value),
+            0xFF04..=0xFF07 => self.timer.write(address, value),
+            0x0000..=0x7FFF => self.cartridge.write_rom(address, value),
+            0xA000..=0xBFFF => self.cartridge.write_ram(address, value),
+            0x8000..=0x9FFF => {
+                self.vram[self.vram_bank][(address - 0x8000) as usize] = value;
+                self.dirty_vram_flag = true;
+            }
+            0xC000..=0xCFFF => self.wram[0][(address - 0xC000) as usize] = value,
+            0xD000..=0xDFFF => self.wram[self.wram_bank][(address - 0xD000) as usize] = value,
+            // echo RAM
+            0xE000..=0xFDFF => self.write_byte(address - 0x2000, value),
+            0xFF4D if self.cgb_mode => self.speed_switch_armed = (value & 0b1) != 0,
+            0xFF4F if self.cgb_mode => self.vram_bank = (value & 0b1) as usize,
+            0xFF70 if self.cgb_mode => {
+                // bank 0 can't be mapped at 0xD000, asking for it gives bank 1
+                self.wram_bank = match value & 0b111 {
+                    0 => 1,
+                    bank => bank as usize,
+                };
+            }
+            0xFF68 if self.cgb_mode => self.bcps = value & 0b1011_1111,
+            0xFF69 if self.cgb_mode => {
+                self.bg_palette_ram[(self.bcps & 0b0011_1111) as usize] = value;
+                self.bcps = MMU::auto_increment_palette_index(self.bcps);
+                // palettes are baked into the background buffer
+                self.dirty_vram_flag = true;
+            }
+            0xFF51 if self.cgb_mode => {
+                self.hdma_source = (self.hdma_source & 0x00FF) | ((value as u16) << 8)
+            }
+            0xFF52 if self.cgb_mode => {
+                self.hdma_source = (self.hdma_source & 0xFF00) | (value & 0xF0) as u16
+            }
+            0xFF53 if self.cgb_mode => {
+                self.hdma_destination =
+                    (self.hdma_destination & 0x00FF) | (((value & 0x1F) as u16) << 8)
+            }
+            0xFF54 if self.cgb_mode => {
+                self.hdma_destination = (self.hdma_destination & 0xFF00) | (value & 0xF0) as u16
+            }
+            0xFF55 if self.cgb_mode => self.start_hdma(value),
+            0xFF6A if self.cgb_mode => self.ocps = value & 0b1011_1111,
+            0xFF6B if self.cgb_mode => {
+                self.obj_palette_ram[(self.ocps & 0b0011_1111) as usize] = value;
+                self.ocps = MMU::auto_increment_palette_index(self.ocps);
+                // sprites are drawn onto the viewport
+                self.dirty_viewport_flag = true;
+            }
+            0xFE00..=0xFE9F => {
+                self.ram[address as usize] = value;
+                self.dirty_viewport_flag = true;
+            }
+            // OAM DMA, done all at once
+            0xFF46 => {
+                self.ram[address as usize] = value;
+                let source = (value as u16) << 8;
+                for i in 0..0xA0 {
+                    self.ram[0xFE00 + i as usize] = self.read_byte(source + i);
+                }
+                self.dirty_viewport_flag = true;
+            }
+            _ => {
+                // the map and tile data LCDC picks are baked into the background buffer
+                if address == 0xFF40 && ((self.ram[0xFF40] ^ value) & 0b0001_1000) != 0 {
+                    self.dirty_vram_flag = true;
+                }
+                // and the priority and sprite bits are used drawing the viewport
+                if address == 0xFF40 && ((self.ram[0xFF40] ^ value) & 0b0000_0111) != 0 {
+                    self.dirty_viewport_flag = true;
+                }
+                self.ram[address as usize] = value;
+                if address == 0xFF42 || address == 0xFF43 {
+                    self.dirty_viewport_flag = true;
+                }
+            }
         }
     }
 
     pub fn read_byte(&self, address: u16) -> u8 {
-        if address < 0x00FF && self.ram[0xFF50] == 0 {
-            self.boot_rom[address as usize]
+        if self.flat {
+            return self.ram[address as usize];
+        }
+        match address {
+            0x0000..=0x00FF | 0x0200..=0x08FF
+                if self.ram[0xFF50] == 0 && (address as usize) < self.boot_rom.len() =>
+            {
+                self.boot_rom[address as usize]
+            }
+            0x0000..=0x7FFF => self.cartridge.read_rom(address),
+            0xA000..=0xBFFF => self.cartridge.read_ram(address),
+            0x8000..=0x9FFF => self.vram[self.vram_bank][(address - 0x8000) as usize],
+            0xC000..=0xCFFF => self.wram[0][(address - 0xC000) as usize],
+            0xD000..=0xDFFF => self.wram[self.wram_bank][(address - 0xD000) as usize],
+            0xE000..=0xFDFF => self.read_byte(address - 0x2000),
+            0xFF00 => match self.sgb.get_joypad_id() {
+                Some(id) if self.model == Model::Sgb && self.joypad.get_select() == 0b0011_0000 => {
+                    0b1111_0000 | id
+                }
+                _ => self.joypad.read(),
+            },
+            0xFF01 => self.serial.read_sb(),
+            0xFF02 => self.serial.read_sc(self.cgb_mode),
+            0xFF04..=0xFF07 => self.timer.read(address),
+            0xFF4D if self.cgb_mode => {
+                let speed = if self.double_speed { 0b1000_0000 } else { 0 };
+                0b0111_1110 | speed | self.speed_switch_armed as u8
+            }
+            0xFF4F if self.cgb_mode => 0b1111_1110 | self.vram_bank as u8,
+            0xFF70 if self.cgb_mode => 0b1111_1000 | self.wram_bank as u8,
+            0xFF55 if self.cgb_mode => {
+                if self.hdma_hblank_active {
+                    self.hdma_remaining
+                } else {
+                    0b1000_0000 | self.hdma_remaining
+                }
+            }
+            0xFF68 if self.cgb_mode => 0b0100_0000 | self.bcps,
+            0xFF69 if self.cgb_mode => self.bg_palette_ram[(self.bcps & 0b0011_1111) as usize],
+            0xFF6A if self.cgb_mode => 0b0100_0000 | self.ocps,
+            0xFF6B if self.cgb_mode => self.obj_palette_ram[(self.ocps & 0b0011_1111) as usize],
+            _ => self.ram[address as usize],
+        }
+    }
+
+    // HDMA5: bit 7 clear is a general purpose DMA that runs right away,
+    // bit 7 set copies one block on every HBlank
+    fn start_hdma(&mut self, value: u8) {
+        let blocks = value & 0b0111_1111;
+        if self.hdma_hblank_active && (value & 0b1000_0000) == 0 {
+            // writing bit 7 clear while HBlank DMA runs cancels it
+            self.hdma_hblank_active = false;
+            return;
+        }
+        self.hdma_remaining = blocks;
+        if (value & 0b1000_0000) != 0 {
+            self.hdma_hblank_active = true;
         } else {
-            self.ram[address as usize]
+            for _ in 0..=blocks {
+                self.copy_hdma_block();
+            }
+            self.hdma_remaining = 0x7F;
         }
     }
 
-    pub fn from_rom_file(&mut self, rom_file: &[u8]) {
-        let mut i: u16 = 0x0000;
-        for &byte in rom_file.iter() {
-            //            println!("{:#X}", i);
-            self.write_byte(i, byte);
-            i += 1
+    fn copy_hdma_block(&mut self) {
+        for _ in 0..HDMA_BLOCK_SIZE {
+            let byte = self.read_byte(self.hdma_source);
+            self.write_byte(0x8000 | (self.hdma_destination & 0x1FFF), byte);
+            self.hdma_source = self.hdma_source.wrapping_add(1);
+            self.hdma_destination = self.hdma_destination.wrapping_add(1);
+        }
+        self.dma_stall_clocks += if self.double_speed {
+            HDMA_BLOCK_CLOCKS * 2
+        } else {
+            HDMA_BLOCK_CLOCKS
+        };
+    }
+
+    // Called by the PPU every time it enters HBlank
+    pub fn step_hblank_dma(&mut self) {
+        if !self.hdma_hblank_active {
+            return;
+        }
+        self.copy_hdma_block();
+        if self.hdma_remaining == 0 {
+            self.hdma_hblank_active = false;
+            self.hdma_remaining = 0x7F;
+        } else {
+            self.hdma_remaining -= 1;
+        }
+    }
+
+    // Sets the bit in IF ($FF0F), the CPU jumps to the handler when it is
+    // also enabled in IE
+    pub fn request_interrupt(&mut self, bit_mask: u8) {
+        self.ram[0xFF0F] |= bit_mask;
+    }
+
+    pub fn press_button(&mut self, button: Button) {
+        if self.joypad.set_button(button, true) {
+            // joypad interrupt
+            self.request_interrupt(0b0001_0000);
+        }
+    }
+
+    pub fn release_button(&mut self, button: Button) {
+        self.joypad.set_button(button, false);
+    }
+
+    pub fn get_joypad(&self) -> &Joypad {
+        &self.joypad
+    }
+
+    pub fn step_serial(&mut self, cpu_clocks_passed: usize) {
+        if self.serial.step(cpu_clocks_passed, self.cgb_mode) {
+            // serial interrupt
+            self.request_interrupt(0b0000_1000);
+        }
+    }
+
+    pub fn step_timer(&mut self, cpu_clocks_passed: usize) {
+        if self.timer.step(cpu_clocks_passed) {
+            // timer interrupt
+            self.request_interrupt(0b0000_0100);
+        }
+    }
+
+    // Keeps every byte sent from now on, for the test ROMs
+    pub fn capture_serial_output(&mut self) {
+        self.serial.capture_output();
+    }
+
+    pub fn get_serial_output(&self) -> &[u8] {
+        self.serial.get_output()
+    }
+
+    // Plug something into the link port
+    pub fn connect_serial(&mut self, peer: Box<dyn SerialPeer>) {
+        self.serial.connect(peer);
+    }
+
+    pub fn disconnect_serial(&mut self) -> Option<Box<dyn SerialPeer>> {
+        self.serial.disconnect()
+    }
+
+    pub fn is_serial_waiting(&self) -> bool {
+        self.serial.is_waiting_for_reply()
+    }
+
+    pub fn get_sgb(&self) -> &Sgb {
+        &self.sgb
+    }
+
+    // Called by the PPU when it enters VBlank, the SGB grabs VRAM transfers
+    // from the frame that was just shown
+    pub fn step_sgb_vblank(&mut self) {
+        if self.model != Model::Sgb || self.sgb.get_pending_transfer().is_none() {
+            return;
+        }
+        let data = self.read_sgb_transfer_data();
+        self.sgb.complete_transfer(&data);
+        self.dirty_viewport_flag = true;
+    }
+
+    // The SGB sees the 4KB as the first 256 tiles on screen, 20 per row, in
+    // whatever tile data and map LCDC selects
+    fn read_sgb_transfer_data(&self) -> Vec<u8> {
+        let lcdc = self.ram[0xFF40];
+        let tile_map: u16 = if (lcdc & 0b0000_1000) != 0 {
+            0x9C00
+        } else {
+            0x9800
+        };
+        let unsigned_tiles = (lcdc & 0b0001_0000) != 0;
+        let mut data = Vec::with_capacity(TRANSFER_SIZE);
+        for i in 0..(TRANSFER_SIZE / 16) as u16 {
+            let tile_number = self.read_vram(0, tile_map + (i / 20) * 32 + (i % 20));
+            let tile_addr = if unsigned_tiles {
+                0x8000 + tile_number as u16 * 16
+            } else {
+                (0x9000 + (tile_number as i8 as i32) * 16) as u16
+            };
+            for byte in 0..16 {
+                data.push(self.read_vram(0, tile_addr + byte));
+            }
         }
+        data
     }
+
+    pub fn take_dma_stall_clocks(&mut self) -> usize {
+        let clocks = self.dma_stall_clocks;
+        self.dma_stall_clocks = 0;
+        clocks
+    }
+
+    // BCPS/OCPS bit 7 asks for the index to move on after each data write
+    fn auto_increment_palette_index(cps: u8) -> u8 {
+        if (cps & 0b1000_0000) != 0 {
+            0b1000_0000 | (cps.wrapping_add(1) & 0b0011_1111)
+        } else {
+            cps
+        }
+    }
+
+    // Colors are little endian 15 bit RGB: 0bbbbbgg_gggrrrrr
+    pub fn get_bg_palette_color(&self, palette: u8, color: u8) -> u16 {
+        let index = ((palette as usize & 0b111) * 8) + ((color as usize & 0b11) * 2);
+        (self.bg_palette_ram[index] as u16) | ((self.bg_palette_ram[index + 1] as u16) << 8)
+    }
+
+    pub fn get_obj_palette_color(&self, palette: u8, color: u8) -> u16 {
+        let index = ((palette as usize & 0b111) * 8) + ((color as usize & 0b11) * 2);
+        (self.obj_palette_ram[index] as u16) | ((self.obj_palette_ram[index + 1] as u16) << 8)
+    }
+
+    // The PPU doesn't care which bank the CPU has selected in VBK
+    pub fn read_vram(&self, bank: usize, address: u16) -> u8 {
+        self.vram[bank][(address - 0x8000) as usize]
+    }
+
+    pub fn from_rom_file(&mut self, rom_file: &[u8]) -> Result<(), EmuError> {
+        self.cartridge = Cartridge::from_rom(rom_file)?;
+        // a SGB just runs color games as monochrome games
+        self.cgb_mode = self.model != Model::Sgb && is_cgb_rom(rom_file);
+        if self.cgb_mode {
+            self.model = Model::Cgb;
+        }
+        self.compat_palette = CompatPalette::from_header(rom_file);
+        Ok(())
+    }
+
+    pub fn load_boot_rom(&mut self, boot_rom: &[u8]) {
+        self.boot_rom = boot_rom.to_vec();
+    }
+
+    // Puts a save file back into the cartridge RAM
+    pub fn load_external_ram(&mut self, data: &[u8]) {
+        self.cartridge.load_ram(data);
+    }
+
+    pub fn get_external_ram(&self) -> &[u8] {
+        self.cartridge.get_ram()
+    }
+
+    // Color games pick CGB on their own, set this before loading the ROM to
+    // play monochrome games on a CGB or a SGB
+    pub fn set_model(&mut self, model: Model) {
+        self.model = model;
+    }
+
+    pub fn get_model(&self) -> Model {
+        self.model
+    }
+
+    pub fn is_cgb_mode(&self) -> bool {
+        self.cgb_mode
+    }
+
+    // A CGB running a monochrome game, colorised by the boot ROM palettes
+    pub fn is_dmg_compatibility_mode(&self) -> bool {
+        self.model == Model::Cgb && !self.cgb_mode
+    }
+
+    // What the CGB boot ROM does for monochrome games: BG uses palette 0,
+    // OBJ0 and OBJ1 use object palettes 0 and 1
+    pub fn load_compatibility_palette(&mut self, palette: &CompatPalette) {
+        for i in 0..4 {
+            let [bg_low, bg_high] = palette.bg[i].to_le_bytes();
+            let [obj0_low, obj0_high] = palette.obj0[i].to_le_bytes();
+            let [obj1_low, obj1_high] = palette.obj1[i].to_le_bytes();
+            self.bg_palette_ram[i * 2] = bg_low;
+            self.bg_palette_ram[i * 2 + 1] = bg_high;
+            self.obj_palette_ram[i * 2] = obj0_low;
+            self.obj_palette_ram[i * 2 + 1] = obj0_high;
+            self.obj_palette_ram[8 + i * 2] = obj1_low;
+            self.obj_palette_ram[8 + i * 2 + 1] = obj1_high;
+        }
+        self.dirty_vram_flag = true;
+    }
+
+    pub fn is_double_speed(&self) -> bool {
+        self.double_speed
+    }
+
+    // Called by STOP, returns how many clocks the CPU is stalled for
+    pub fn switch_speed(&mut self) -> usize {
+        if !self.cgb_mode || !self.speed_switch_armed {
+            return 0;
+        }
+        self.double_speed = !self.double_speed;
+        self.speed_switch_armed = false;
+        SPEED_SWITCH_CLOCKS
+    }
+
+    // We don't ship the CGB boot ROM so we leave the hardware the way it
+    // would after running it. Also used when skipping the DMG boot ROM.
+    pub fn skip_boot_rom(&mut self) {
+        let io_defaults: [(u16, u8); 21] = [
+            (0xFF05, 0x00), // TIMA
+            (0xFF06, 0x00), // TMA
+            (0xFF07, 0x00), // TAC
+            (0xFF10, 0x80), // NR10
+            (0xFF11, 0xBF), // NR11
+            (0xFF12, 0xF3), // NR12
+            (0xFF14, 0xBF), // NR14
+            (0xFF16, 0x3F), // NR21
+            (0xFF19, 0xBF), // NR24
+            (0xFF1A, 0x7F), // NR30
+            (0xFF1B, 0xFF), // NR31
+            (0xFF1C, 0x9F), // NR32
+            (0xFF1E, 0xBF), // NR34
+            (0xFF20, 0xFF), // NR41
+            (0xFF23, 0xBF), // NR44
+            (0xFF24, 0x77), // NR50
+            (0xFF25, 0xF3), // NR51
+            (0xFF26, 0xF1), // NR52
+            (0xFF40, 0x91), // LCDC
+            (0xFF47, 0xFC), // BGP
+            (0xFF50, 0x01), // boot ROM disabled
+        ];
+        for (address, value) in io_defaults.iter() {
+            self.write_byte(*address, *value);
+        }
+        self.vram_bank = 0;
+        self.wram_bank = 1;
+        if self.is_dmg_compatibility_mode() {
+            let palette = self.compat_palette;
+            self.load_compatibility_palette(&palette);
+        }
+    }
+
+    // Like holding the buttons while the boot logo shows. A boot ROM that
+    // runs afterwards picks its own.
+    pub fn set_manual_palette(&mut self, manual: ManualPalette) {
+        self.compat_palette = manual.palette();
+        if self.is_dmg_compatibility_mode() {
+            let palette = self.compat_palette;
+            self.load_compatibility_palette(&palette);
+        }
+    }
+
+    // The compatibility palette already went into the palette RAM
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_bytes(&self.ram);
+        state.write_vec(&self.boot_rom);
+        for bank in self.vram.iter() {
+            state.write_bytes(bank);
+        }
+        for bank in self.wram.iter() {
+            state.write_bytes(bank);
+        }
+        state.write_u8(self.vram_bank as u8);
+        state.write_u8(self.wram_bank as u8);
+        state.write_u8(match self.model {
+            Model::Dmg => 0,
+            Model::Mgb => 1,
+            Model::Cgb => 2,
+            Model::Sgb => 3,
+        });
+        state.write_bool(self.cgb_mode);
+        state.write_bool(self.double_speed);
+        state.write_bool(self.speed_switch_armed);
+        state.write_bytes(&self.bg_palette_ram);
+        state.write_bytes(&self.obj_palette_ram);
+        state.write_u8(self.bcps);
+        state.write_u8(self.ocps);
+        state.write_u16(self.hdma_source);
+        state.write_u16(self.hdma_destination);
+        state.write_u8(self.hdma_remaining);
+        state.write_bool(self.hdma_hblank_active);
+        state.write_usize(self.dma_stall_clocks);
+        self.cartridge.save_state(state);
+        self.joypad.save_state(state);
+        self.sgb.save_state(state);
+        self.serial.save_state(state);
+        self.timer.save_state(state);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.read_bytes(&mut self.ram)?;
+        self.boot_rom = state.read_vec()?;
+        for bank in self.vram.iter_mut() {
+            state.read_bytes(bank)?;
+        }
+        for bank in self.wram.iter_mut() {
+            state.read_bytes(bank)?;
+        }
+        self.vram_bank = state.read_u8()? as usize;
+        self.wram_bank = state.read_u8()? as usize;
+        if self.vram_bank > 1 || self.wram_bank == 0 || self.wram_bank > 7 {
+            return Err(StateError::Corrupt);
+        }
+        self.model = match state.read_u8()? {
+            0 => Model::Dmg,
+            1 => Model::Mgb,
+            2 => Model::Cgb,
+            3 => Model::Sgb,
+            _ => return Err(StateError::Corrupt),
+        };
+        self.cgb_mode = state.read_bool()?;
+        self.double_speed = state.read_bool()?;
+        self.speed_switch_armed = state.read_bool()?;
+        state.read_bytes(&mut self.bg_palette_ram)?;
+        state.read_bytes(&mut self.obj_palette_ram)?;
+        self.bcps = state.read_u8()?;
+        self.ocps = state.read_u8()?;
+        self.hdma_source = state.read_u16()?;
+        self.hdma_destination = state.read_u16()?;
+        self.hdma_remaining = state.read_u8()?;
+        self.hdma_hblank_active = state.read_bool()?;
+        self.dma_stall_clocks = state.read_usize()?;
+        self.cartridge.load_state(state)?;
+        self.joypad.load_state(state)?;
+        self.sgb.load_state(state)?;
+        self.serial.load_state(state)?;
+        self.timer.load_state(state)?;
+        // everything on screen has to be drawn again
+        self.dirty_vram_flag = true;
+        self.dirty_viewport_flag = true;
+        Ok(())
+    }
+}
+
+// bit 7 is set for both CGB enhanced (0x80) and CGB only (0xC0) games
+pub fn is_cgb_rom(rom_file: &[u8]) -> bool {
+    rom_file.len() > CGB_FLAG_ADDR && (rom_file[CGB_FLAG_ADDR] & 0x80) != 0
 }
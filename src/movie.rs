@@ -0,0 +1,349 @@
+use crate::error::EmuError;
+use crate::gameboy::GameBoy;
+use crate::joypad::Button;
+use crate::savestate::{StateError, StateReader, StateWriter};
+use crate::screenshot::capture;
+
+use std::error::Error;
+use std::fmt;
+
+// Movies: the buttons held on every frame from a known start, power-on or a
+// save state, so a session plays back exactly the same. Every so often the
+// screen is hashed too, playing it back tells the first frame that came out
+// different.
+
+const MAGIC: &[u8; 8] = b"GBRUSTMV";
+pub const FORMAT_VERSION: u16 = 1;
+pub const DEFAULT_HASH_INTERVAL: usize = 60;
+
+// bit n of a frame's byte is BUTTONS[n] held down
+const BUTTONS: [Button; 8] = [
+    Button::Right,
+    Button::Left,
+    Button::Up,
+    Button::Down,
+    Button::A,
+    Button::B,
+    Button::Select,
+    Button::Start,
+];
+
+#[derive(Debug)]
+pub enum MovieError {
+    NotAMovie,
+    UnsupportedVersion { version: u16 },
+    WrongRom { expected: u32, actual: u32 },
+    Corrupt,
+    // the save state it starts from didn't load
+    State(StateError),
+    Emulator(EmuError),
+}
+
+impl fmt::Display for MovieError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MovieError::NotAMovie => write!(f, "not a movie"),
+            MovieError::UnsupportedVersion { version } => write!(
+                f,
+                "movie format {} is not supported, expected {}",
+                version, FORMAT_VERSION
+            ),
+            MovieError::WrongRom { expected, actual } => write!(
+                f,
+                "movie is for another ROM (checksum {:08X}, this one is {:08X})",
+                actual, expected
+            ),
+            MovieError::Corrupt => write!(f, "movie is corrupt"),
+            MovieError::State(error) => write!(f, "{}", error),
+            MovieError::Emulator(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl Error for MovieError {}
+
+// Reading the movie file itself, the save state in it goes through
+// MovieError::State
+impl From<StateError> for MovieError {
+    fn from(error: StateError) -> MovieError {
+        match error {
+            StateError::NotAState => MovieError::NotAMovie,
+            StateError::UnsupportedVersion { version } => {
+                MovieError::UnsupportedVersion { version }
+            }
+            StateError::WrongRom { expected, actual } => MovieError::WrongRom { expected, actual },
+            StateError::Corrupt => MovieError::Corrupt,
+        }
+    }
+}
+
+impl From<EmuError> for MovieError {
+    fn from(error: EmuError) -> MovieError {
+        MovieError::Emulator(error)
+    }
+}
+
+// CRC-32 of the screen, in the plain grays so the palette doesn't matter
+pub fn hash_screen(gameboy: &GameBoy) -> u32 {
+    let mut crc = flate2::Crc::new();
+    for pixel in capture(gameboy).iter() {
+        crc.update(&pixel.to_le_bytes());
+    }
+    crc.sum()
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Movie {
+    rom_checksum: u32,
+    // None starts at power-on
+    start_state: Option<Vec<u8>>,
+    // 0 doesn't hash the screen
+    hash_interval: usize,
+    frames: Vec<u8>,
+    // the screen after every hash_interval frames
+    hashes: Vec<u32>,
+}
+
+impl Movie {
+    pub fn from_bytes(data: &[u8], rom_checksum: u32) -> Result<Movie, MovieError> {
+        let mut reader = StateReader::with_header(data, MAGIC, FORMAT_VERSION, rom_checksum)?;
+        let start_state = if reader.read_bool()? {
+            Some(reader.read_vec()?)
+        } else {
+            None
+        };
+        let hash_interval = reader.read_usize()?;
+        let frames = reader.read_vec()?;
+        let hash_count = reader.read_usize()?;
+        let expected_hashes = frames.len().checked_div(hash_interval).unwrap_or(0);
+        if hash_count != expected_hashes {
+            return Err(MovieError::Corrupt);
+        }
+        let mut hashes = vec![0; hash_count];
+        reader.read_u32s(&mut hashes)?;
+        reader.finish()?;
+        let movie = Movie {
+            rom_checksum,
+            start_state,
+            hash_interval,
+            frames,
+            hashes,
+        };
+        Ok(movie)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut writer = StateWriter::with_header(MAGIC, FORMAT_VERSION, self.rom_checksum);
+        writer.write_bool(self.start_state.is_some());
+        if let Some(state) = &self.start_state {
+            writer.write_vec(state);
+        }
+        writer.write_usize(self.hash_interval);
+        writer.write_vec(&self.frames);
+        writer.write_usize(self.hashes.len());
+        writer.write_u32s(&self.hashes);
+        writer.finish()
+    }
+
+    pub fn get_frames(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn starts_from_state(&self) -> bool {
+        self.start_state.is_some()
+    }
+}
+
+pub struct MovieRecorder {
+    movie: Movie,
+}
+
+impl MovieRecorder {
+    // `from_state` starts the movie from where the console is now, otherwise
+    // it has to be right after power-on
+    pub fn new(gameboy: &GameBoy, from_state: bool, hash_interval: usize) -> MovieRecorder {
+        let movie = Movie {
+            rom_checksum: gameboy.get_rom_checksum(),
+            start_state: if from_state {
+                Some(gameboy.save_state())
+            } else {
+                None
+            },
+            hash_interval,
+            frames: Vec::new(),
+            hashes: Vec::new(),
+        };
+        MovieRecorder { movie }
+    }
+
+    // Runs a frame with the buttons as they are now and writes them down.
+    // A frame that fails isn't, it runs again the next time.
+    pub fn run_frame(&mut self, gameboy: &mut GameBoy) -> Result<(), EmuError> {
+        let joypad = gameboy.get_mmu().get_joypad();
+        let buttons = BUTTONS
+            .iter()
+            .enumerate()
+            .filter(|(_, &button)| joypad.is_pressed(button))
+            .fold(0, |buttons, (bit, _)| buttons | (1 << bit));
+        gameboy.run_frame()?;
+        self.movie.frames.push(buttons);
+        // an interval of 0 has no remainder, it means no hashes
+        let interval = self.movie.hash_interval;
+        if self.movie.frames.len().checked_rem(interval) == Some(0) {
+            self.movie.hashes.push(hash_screen(gameboy));
+        }
+        Ok(())
+    }
+
+    pub fn get_frames(&self) -> usize {
+        self.movie.frames.len()
+    }
+
+    pub fn finish(self) -> Movie {
+        self.movie
+    }
+}
+
+// The first hash that didn't match, frames counted from 1
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Divergence {
+    pub frame: usize,
+    pub expected: u32,
+    pub actual: u32,
+}
+
+pub struct MoviePlayer {
+    movie: Movie,
+    frame: usize,
+    divergence: Option<Divergence>,
+}
+
+impl MoviePlayer {
+    // A movie from power-on needs a console made with the same model and
+    // boot ROM as the one it was recorded on, and nothing run on it yet
+    pub fn new(movie: Movie, gameboy: &mut GameBoy) -> Result<MoviePlayer, MovieError> {
+        if let Some(state) = &movie.start_state {
+            gameboy.load_state(state).map_err(MovieError::State)?;
+        }
+        let player = MoviePlayer {
+            movie,
+            frame: 0,
+            divergence: None,
+        };
+        Ok(player)
+    }
+
+    // Runs the next frame with its buttons, returns false once the movie is over
+    pub fn run_frame(&mut self, gameboy: &mut GameBoy) -> Result<bool, EmuError> {
+        let buttons = match self.movie.frames.get(self.frame) {
+            Some(&buttons) => buttons,
+            None => return Ok(false),
+        };
+        for (bit, &button) in BUTTONS.iter().enumerate() {
+            gameboy.set_button(button, (buttons >> bit) & 0b1 != 0);
+        }
+        gameboy.run_frame()?;
+        self.frame += 1;
+        let interval = self.movie.hash_interval;
+        if self.frame.checked_rem(interval) == Some(0) && self.divergence.is_none() {
+            let expected = self.movie.hashes[self.frame / interval - 1];
+            let actual = hash_screen(gameboy);
+            if actual != expected {
+                self.divergence = Some(Divergence {
+                    frame: self.frame,
+                    expected,
+                    actual,
+                });
+            }
+        }
+        Ok(true)
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.frame >= self.movie.frames.len()
+    }
+
+    pub fn get_frame(&self) -> usize {
+        self.frame
+    }
+
+    pub fn get_movie(&self) -> &Movie {
+        &self.movie
+    }
+
+    pub fn get_divergence(&self) -> Option<Divergence> {
+        self.divergence
+    }
+}
+
+// Plays the whole movie, stopping at the first frame that came out different
+pub fn play(movie: Movie, gameboy: &mut GameBoy) -> Result<Option<Divergence>, MovieError> {
+    let mut player = MoviePlayer::new(movie, gameboy)?;
+    while player.get_divergence().is_none() && player.run_frame(gameboy)? {}
+    Ok(player.get_divergence())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mmu::Model;
+
+    // Keeps the joypad in WRAM forever:
+    //   LD A,$10; LDH ($00),A; LDH A,($00); LD ($C000),A; JR -11
+    fn gameboy() -> GameBoy {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x10C].copy_from_slice(&[
+            0x3E, 0x10, 0xE0, 0x00, 0xF0, 0x00, 0xEA, 0x00, 0xC0, 0x18, 0xF5, 0x00,
+        ]);
+        GameBoy::new(Model::Dmg, &rom, None, None).unwrap()
+    }
+
+    fn record(from_state: bool) -> (Movie, Vec<u8>) {
+        let mut gameboy = gameboy();
+        if from_state {
+            gameboy.run_frame().unwrap();
+        }
+        let mut recorder = MovieRecorder::new(&gameboy, from_state, 4);
+        for frame in 0..20 {
+            gameboy.set_button(Button::A, frame % 3 == 0);
+            gameboy.set_button(Button::Start, frame >= 10);
+            recorder.run_frame(&mut gameboy).unwrap();
+        }
+        (recorder.finish(), gameboy.save_state())
+    }
+
+    #[test]
+    fn plays_back_the_same_session() {
+        for &from_state in [false, true].iter() {
+            let (movie, end_state) = record(from_state);
+            let movie = Movie::from_bytes(&movie.to_bytes(), movie.rom_checksum).unwrap();
+            assert_eq!(movie.get_frames(), 20);
+            assert_eq!(movie.starts_from_state(), from_state);
+
+            let mut gameboy = gameboy();
+            assert_eq!(play(movie, &mut gameboy).unwrap(), None);
+            assert_eq!(gameboy.save_state(), end_state);
+        }
+    }
+
+    #[test]
+    fn reports_the_first_frame_that_differs() {
+        let (mut movie, _) = record(false);
+        movie.hashes[2] ^= 1;
+        movie.hashes[3] ^= 1;
+        let divergence = play(movie, &mut gameboy()).unwrap().unwrap();
+        assert_eq!(divergence.frame, 12);
+        assert_eq!(divergence.actual ^ 1, divergence.expected);
+    }
+
+    #[test]
+    fn rejects_movies_of_other_roms() {
+        let (movie, _) = record(false);
+        let result = Movie::from_bytes(&movie.to_bytes(), movie.rom_checksum ^ 1);
+        assert!(matches!(result, Err(MovieError::WrongRom { .. })));
+        assert!(matches!(
+            Movie::from_bytes(b"nope", 0),
+            Err(MovieError::NotAMovie)
+        ));
+    }
+}
@@ -0,0 +1,50 @@
+use std::thread;
+use std::time::{Duration, Instant};
+
+// the DMG master clock, in T-cycles per second
+pub const CPU_CLOCK_HZ: usize = 4_194_304;
+// 154 lines of 456 dots each
+pub const CYCLES_PER_FRAME: usize = 70_224;
+// ~59.73 Hz
+pub const FRAME_RATE: f64 = CPU_CLOCK_HZ as f64 / CYCLES_PER_FRAME as f64;
+
+// if we get this far behind the wall clock we give up catching up
+const MAX_FRAMES_BEHIND: u32 = 4;
+
+pub struct FramePacer {
+    frame_duration: Duration,
+    next_frame: Instant,
+}
+
+impl Default for FramePacer {
+    fn default() -> FramePacer {
+        FramePacer::new()
+    }
+}
+
+impl FramePacer {
+    pub fn new() -> FramePacer {
+        FramePacer {
+            frame_duration: Duration::from_secs_f64(1.0 / FRAME_RATE),
+            next_frame: Instant::now(),
+        }
+    }
+
+    pub fn get_frame_duration(&self) -> Duration {
+        self.frame_duration
+    }
+
+    // Call once per emulated frame, sleeps until the next one is due. There
+    // is no APU, so no sound card to sync to, the wall clock is all we have.
+    pub fn end_frame(&mut self) {
+        let now = Instant::now();
+        if self.next_frame > now {
+            thread::sleep(self.next_frame - now);
+        } else if now - self.next_frame > self.frame_duration * MAX_FRAMES_BEHIND {
+            // we were paused or the host is too slow, don't try to run the
+            // missed frames as fast as possible
+            self.next_frame = now;
+        }
+        self.next_frame += self.frame_duration;
+    }
+}
@@ -1,4 +1,6 @@
-use crate::mmu::MMU;
+use crate::mmu::{Model, MMU};
+use crate::savestate::{StateError, StateReader, StateWriter};
+use crate::sgb::{MaskMode, GAME_X, GAME_Y, SGB_SCREEN_HEIGHT, SGB_SCREEN_WIDTH};
 
 const WIDTH: usize = 256;
 const HEIGHT: usize = 256;
@@ -13,11 +15,35 @@ pub const DARK_GREEN: u32 = 0xFF306230;
 pub const LIGHT_GREEN: u32 = 0xFF8BAC0F;
 pub const LIGHTEST_GREEN: u32 = 0xFF9BBC0F;
 
+// the four DMG shades, lightest first
+pub const GREEN_PALETTE: [u32; 4] = [LIGHTEST_GREEN, LIGHT_GREEN, DARK_GREEN, DARKEST_GREEN];
+pub const GRAY_PALETTE: [u32; 4] = [0xFFFFFFFF, 0xFFAAAAAA, 0xFF555555, 0xFF000000];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ColorCorrection {
+    // 5 bit channels just scaled up to 8 bits, looks oversaturated
+    Off,
+    // mixes the channels and flattens the curve like the CGB LCD does
+    CgbLcd,
+}
+
 pub struct PPU {
     mode: u8,
     mode_clock: usize,
     background_buffer: Vec<u32>,
+    // DMG shade (after BGP) of each background pixel, the SGB colors by it
+    background_shades: Vec<u8>,
+    // CGB only: color number (0-3) and BG-to-OAM priority bit of each
+    // background pixel, sprites are drawn over or under them by these
+    background_colors: Vec<u8>,
+    background_priority: Vec<bool>,
     viewport: Vec<u32>,
+    viewport_shades: Vec<u8>,
+    // 256x224 picture with the border around the game, SGB only
+    sgb_screen: Vec<u32>,
+    color_correction: ColorCorrection,
+    // what the DMG shades look like
+    palette: [u32; 4],
 }
 
 impl PPU {
@@ -25,12 +51,57 @@ impl PPU {
         let ppu = PPU {
             mode: 0,
             background_buffer: vec![LIGHTEST_GREEN; WIDTH * HEIGHT],
+            background_shades: vec![0; WIDTH * HEIGHT],
+            background_colors: vec![0; WIDTH * HEIGHT],
+            background_priority: vec![false; WIDTH * HEIGHT],
             mode_clock: 0,
             viewport: vec![LIGHTEST_GREEN; SCREEN_WIDTH * SCREEN_HEIGHT],
+            viewport_shades: vec![0; SCREEN_WIDTH * SCREEN_HEIGHT],
+            sgb_screen: vec![0xFF00_0000; SGB_SCREEN_WIDTH * SGB_SCREEN_HEIGHT],
+            color_correction: ColorCorrection::Off,
+            palette: GREEN_PALETTE,
         };
         ppu
     }
 
+    // The background is drawn with the colors baked in, the caller has to
+    // mark VRAM as dirty for it to be redrawn
+    pub fn set_palette(&mut self, palette: [u32; 4]) {
+        self.palette = palette;
+    }
+
+    pub fn get_palette(&self) -> [u32; 4] {
+        self.palette
+    }
+
+    // Same as the palette, the caller has to mark VRAM as dirty
+    pub fn set_color_correction(&mut self, color_correction: ColorCorrection) {
+        self.color_correction = color_correction;
+    }
+
+    // The palette and color correction are settings of the frontend, they
+    // stay as they are
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.mode);
+        state.write_usize(self.mode_clock);
+        state.write_u32s(&self.background_buffer);
+        state.write_bytes(&self.background_shades);
+        state.write_u32s(&self.viewport);
+        state.write_bytes(&self.viewport_shades);
+        state.write_u32s(&self.sgb_screen);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.mode = state.read_u8()?;
+        self.mode_clock = state.read_usize()?;
+        state.read_u32s(&mut self.background_buffer)?;
+        state.read_bytes(&mut self.background_shades)?;
+        state.read_u32s(&mut self.viewport)?;
+        state.read_bytes(&mut self.viewport_shades)?;
+        state.read_u32s(&mut self.sgb_screen)?;
+        Ok(())
+    }
+
     pub fn get_lcdc(&self, mmu: &MMU) -> u8 {
         mmu.read_byte(0xFF40)
     }
@@ -59,6 +130,10 @@ impl PPU {
         &self.viewport
     }
 
+    pub fn get_sgb_screen(&self) -> &Vec<u32> {
+        &self.sgb_screen
+    }
+
     pub fn is_lcd_enable(&self, mmu: &MMU) -> bool {
         (self.get_lcdc(mmu) & 0b1000_0000) != 0
     }
@@ -77,7 +152,7 @@ impl PPU {
         let mut tile_map: [u8; 1024] = [0; 1_024];
 
         for i in 0..1_024 {
-            tile_map[i] = mmu.read_byte((0x9800 + i) as u16);
+            tile_map[i] = mmu.read_vram(0, (0x9800 + i) as u16);
         }
         tile_map
     }
@@ -85,7 +160,7 @@ impl PPU {
     pub fn get_tile(&self, mmu: &MMU, first_tile_byte_addr: u16) -> [u8; 16] {
         let mut tile = [0; 16];
         for i in 0..16 {
-            tile[i] = mmu.read_byte(first_tile_byte_addr + i as u16);
+            tile[i] = mmu.read_vram(0, first_tile_byte_addr + i as u16);
         }
         tile
     }
@@ -93,23 +168,54 @@ impl PPU {
     pub fn transform_background_buffer_into_screen(&mut self, mmu: &MMU) {
         let scx = self.get_scx(mmu) as usize;
         let scy = self.get_scy(mmu) as usize;
-        //        let scx = 0;
-        //        let scy = 70;
 
-        self.viewport = self
-            .background_buffer
-            .iter()
-            .enumerate()
-            .filter(|(m, _)| {
-                let line = m / WIDTH;
-                let column = m % WIDTH;
-                line >= scy && line < (scy + 144) && column >= scx && column < (scx + 160)
-            })
-            .map(|(_, minifb_tile)| *minifb_tile)
-            .collect();
+        // the background map wraps around past the right and bottom edges
+        for y in 0..SCREEN_HEIGHT {
+            let line = ((scy + y) % HEIGHT) * WIDTH;
+            for x in 0..SCREEN_WIDTH {
+                let m = line + (scx + x) % WIDTH;
+                self.viewport[y * SCREEN_WIDTH + x] = self.background_buffer[m];
+                self.viewport_shades[y * SCREEN_WIDTH + x] = self.background_shades[m];
+            }
+        }
+        if mmu.is_cgb_mode() {
+            self.draw_cgb_sprites(mmu);
+        }
+        if mmu.get_model() == Model::Sgb {
+            self.refresh_sgb_screen(mmu);
+        }
+    }
+
+    pub fn refresh_sgb_screen(&mut self, mmu: &MMU) {
+        let sgb = mmu.get_sgb();
+        let backdrop = self.transform_cgb_color_to_minifb_color(sgb.get_backdrop_color());
+        for y in 0..SGB_SCREEN_HEIGHT {
+            for x in 0..SGB_SCREEN_WIDTH {
+                let in_game = (GAME_X..GAME_X + SCREEN_WIDTH).contains(&x)
+                    && (GAME_Y..GAME_Y + SCREEN_HEIGHT).contains(&y);
+                let pixel = match sgb.get_border_color(x, y) {
+                    Some(rgb555) => self.transform_cgb_color_to_minifb_color(rgb555),
+                    None if in_game => {
+                        if sgb.get_mask() == MaskMode::Freeze {
+                            continue;
+                        }
+                        let (game_x, game_y) = (x - GAME_X, y - GAME_Y);
+                        let shade = self.viewport_shades[game_y * SCREEN_WIDTH + game_x];
+                        let rgb555 = sgb.get_game_color(game_x, game_y, shade);
+                        self.transform_cgb_color_to_minifb_color(rgb555)
+                    }
+                    None => backdrop,
+                };
+                self.sgb_screen[y * SGB_SCREEN_WIDTH + x] = pixel;
+            }
+        }
     }
 
     pub fn populate_background_buffer(&mut self, mmu: &MMU) {
+        if mmu.is_cgb_mode() {
+            self.populate_cgb_background_buffer(mmu);
+            return;
+        }
         // get the tile set
         let tile_set = self.get_tile_set(mmu);
         // get the tile map
@@ -118,11 +224,132 @@ impl PPU {
         // in the process
         for (t, tile_map_item) in tile_map.iter().enumerate() {
             let tile = tile_set[*tile_map_item as usize];
-            let minifb_tile = self.transform_tile_to_minifb_tile(mmu, tile);
-            for (i, pixel) in minifb_tile.iter().enumerate() {
+            let shade_tile = self.transform_tile_to_shades(mmu, tile);
+            for (i, shade) in shade_tile.iter().enumerate() {
                 let h_offset = (i % 8) + ((t % 32) * 8);
                 let v_offset = ((i / 8) + (t / 32) * 8) * WIDTH;
-                self.background_buffer[h_offset + v_offset] = *pixel;
+                self.background_buffer[h_offset + v_offset] =
+                    self.transform_shade_to_minifb_color(mmu, *shade);
+                self.background_shades[h_offset + v_offset] = *shade;
+            }
+        }
+    }
+
+    pub fn populate_cgb_background_buffer(&mut self, mmu: &MMU) {
+        let lcdc = self.get_lcdc(mmu);
+        // LCDC bit 3 picks the map at 0x9C00, bit 4 the tiles at 0x8000
+        // numbered 0 to 255, without it they are at 0x9000 numbered -128 to 127
+        let tile_map_start = if (lcdc & 0b0000_1000) != 0 {
+            0x9C00
+        } else {
+            0x9800
+        };
+        let unsigned_tiles = (lcdc & 0b0001_0000) != 0;
+        for t in 0..1_024 {
+            // the attributes live in bank 1 at the same address as the tile number
+            let tile_map_addr = (tile_map_start + t) as u16;
+            let tile_number = mmu.read_vram(0, tile_map_addr);
+            let attributes = mmu.read_vram(1, tile_map_addr);
+            let palette = attributes & 0b0000_0111;
+            let bank = ((attributes & 0b0000_1000) >> 3) as usize;
+            let h_flip = (attributes & 0b0010_0000) != 0;
+            let v_flip = (attributes & 0b0100_0000) != 0;
+
+            let tile_addr = if unsigned_tiles {
+                0x8000 + (tile_number as u16 * 16)
+            } else {
+                0x9000u16.wrapping_add((tile_number as i8 as i16 * 16) as u16)
+            };
+            for y in 0..8 {
+                let tile_line = if v_flip { 7 - y } else { y };
+                let low = mmu.read_vram(bank, tile_addr + (tile_line * 2) as u16);
+                let high = mmu.read_vram(bank, tile_addr + (tile_line * 2 + 1) as u16);
+                for x in 0..8 {
+                    let bit = if h_flip { x } else { 7 - x };
+                    let color = (((high >> bit) & 0b1) << 1) | ((low >> bit) & 0b1);
+                    let rgb555 = mmu.get_bg_palette_color(palette, color);
+                    let h_offset = x + ((t % 32) * 8);
+                    let v_offset = (y + (t / 32) * 8) * WIDTH;
+                    self.background_buffer[h_offset + v_offset] =
+                        self.transform_cgb_color_to_minifb_color(rgb555);
+                    self.background_colors[h_offset + v_offset] = color;
+                    self.background_priority[h_offset + v_offset] = (attributes & 0b1000_0000) != 0;
+                }
+            }
+        }
+    }
+
+    // Sprites go straight onto the viewport, over the scrolled background.
+    // Lower OAM entries win over higher ones and only the first 10 on a line
+    // are drawn, like the CGB does.
+    pub fn draw_cgb_sprites(&mut self, mmu: &MMU) {
+        let lcdc = self.get_lcdc(mmu);
+        if (lcdc & 0b0000_0010) == 0 {
+            return;
+        }
+        let height = if (lcdc & 0b0000_0100) != 0 { 16 } else { 8 };
+        // with LCDC bit 0 off sprites are above the background whatever the
+        // priority bits say
+        let bg_master_priority = (lcdc & 0b0000_0001) != 0;
+        let scx = self.get_scx(mmu) as usize;
+        let scy = self.get_scy(mmu) as usize;
+
+        for y in 0..SCREEN_HEIGHT {
+            let sprites: Vec<u16> = (0..40)
+                .map(|sprite| 0xFE00 + sprite * 4)
+                .filter(|oam_addr| {
+                    let top = mmu.read_byte(*oam_addr) as isize - 16;
+                    (top..top + height).contains(&(y as isize))
+                })
+                .take(10)
+                .collect();
+            for oam_addr in sprites.iter().rev() {
+                let top = mmu.read_byte(*oam_addr) as isize - 16;
+                let left = mmu.read_byte(oam_addr + 1) as isize - 8;
+                let attributes = mmu.read_byte(oam_addr + 3);
+                let palette = attributes & 0b0000_0111;
+                let bank = ((attributes & 0b0000_1000) >> 3) as usize;
+                let x_flip = (attributes & 0b0010_0000) != 0;
+                let y_flip = (attributes & 0b0100_0000) != 0;
+                let behind_background = (attributes & 0b1000_0000) != 0;
+                // in 8x16 mode the top tile is the even one
+                let tile_number = if height == 16 {
+                    mmu.read_byte(oam_addr + 2) & 0xFE
+                } else {
+                    mmu.read_byte(oam_addr + 2)
+                };
+
+                let mut line = y as isize - top;
+                if y_flip {
+                    line = height - 1 - line;
+                }
+                let tile_addr = 0x8000 + tile_number as u16 * 16 + line as u16 * 2;
+                let low = mmu.read_vram(bank, tile_addr);
+                let high = mmu.read_vram(bank, tile_addr + 1);
+                for x in 0..8 {
+                    let screen_x = left + x;
+                    if !(0..SCREEN_WIDTH as isize).contains(&screen_x) {
+                        continue;
+                    }
+                    let bit = if x_flip { x } else { 7 - x };
+                    let color = (((high >> bit) & 0b1) << 1) | ((low >> bit) & 0b1);
+                    // color 0 is see through
+                    if color == 0 {
+                        continue;
+                    }
+                    let screen_x = screen_x as usize;
+                    let m = ((scy + y) % HEIGHT) * WIDTH + (scx + screen_x) % WIDTH;
+                    // background color 0 is always behind the sprites
+                    if bg_master_priority
+                        && self.background_colors[m] != 0
+                        && (behind_background || self.background_priority[m])
+                    {
+                        continue;
+                    }
+                    let rgb555 = mmu.get_obj_palette_color(palette, color);
+                    self.viewport[y * SCREEN_WIDTH + screen_x] =
+                        self.transform_cgb_color_to_minifb_color(rgb555);
+                }
             }
         }
     }
@@ -131,6 +358,25 @@ impl PPU {
         &self.background_buffer
     }
 
+    pub fn transform_cgb_color_to_minifb_color(&self, rgb555: u16) -> u32 {
+        let r = (rgb555 & 0x1F) as u32;
+        let g = ((rgb555 >> 5) & 0x1F) as u32;
+        let b = ((rgb555 >> 10) & 0x1F) as u32;
+        let (r, g, b) = match self.color_correction {
+            ColorCorrection::Off => (
+                (r << 3) | (r >> 2),
+                (g << 3) | (g >> 2),
+                (b << 3) | (b >> 2),
+            ),
+            ColorCorrection::CgbLcd => (
+                (r * 26 + g * 4 + b * 2).min(960) >> 2,
+                (g * 24 + b * 8).min(960) >> 2,
+                (r * 6 + g * 4 + b * 22).min(960) >> 2,
+            ),
+        };
+        0xFF00_0000 | (r << 16) | (g << 8) | b
+    }
+
     pub fn transform_pair_into_bgp_palette(&self, mmu: &MMU, pixel_pair: u8) -> u8 {
         let bgp_palette = self.get_bgp(&mmu);
         //        println!("bgp_palette: {:?}", bgp_palette);
@@ -144,17 +390,27 @@ impl PPU {
     }
 
     pub fn transform_from_bgp_to_minifb_color(&self, bgp_palette: u8) -> u32 {
-        match bgp_palette {
-            0b00 => LIGHTEST_GREEN,
-            0b01 => LIGHT_GREEN,
-            0b10 => DARK_GREEN,
-            0b11 => DARKEST_GREEN,
-            _ => LIGHTEST_GREEN,
+        self.palette[(bgp_palette & 0b11) as usize]
+    }
+
+    pub fn transform_shade_to_minifb_color(&self, mmu: &MMU, bgp_palette: u8) -> u32 {
+        if mmu.is_dmg_compatibility_mode() {
+            let rgb555 = mmu.get_bg_palette_color(0, bgp_palette);
+            self.transform_cgb_color_to_minifb_color(rgb555)
+        } else {
+            self.transform_from_bgp_to_minifb_color(bgp_palette)
         }
     }
 
     pub fn transform_tile_to_minifb_tile(&self, mmu: &MMU, tile: [u8; 16]) -> Vec<u32> {
-        let mut minifb_tile = vec![0; 64];
+        self.transform_tile_to_shades(mmu, tile)
+            .iter()
+            .map(|shade| self.transform_shade_to_minifb_color(mmu, *shade))
+            .collect()
+    }
+
+    pub fn transform_tile_to_shades(&self, mmu: &MMU, tile: [u8; 16]) -> Vec<u8> {
+        let mut shade_tile = vec![0; 64];
         for i in (0..tile.len()).step_by(2) {
             let pixel_part_1 = tile[i];
             let pixel_part_2 = tile[i + 1];
@@ -164,14 +420,12 @@ impl PPU {
                 let pair = ((bit_part_1 as u8) << 1) | (bit_part_2 as u8);
                 // TRANSFORM THIS PAIR INTO BGP PALETTE
                 let bgp_palette = self.transform_pair_into_bgp_palette(&mmu, pair);
-                // TRANSFORM INTO MINIFB COLOR
-                let minifb = self.transform_from_bgp_to_minifb_color(bgp_palette);
 
                 //                minifb_tile[i / 2][7 - j] = minifb;
-                minifb_tile[(i / 2 * 8) + (7 - j) as usize] = minifb;
+                shade_tile[(i / 2 * 8) + (7 - j) as usize] = bgp_palette;
             }
         }
-        minifb_tile
+        shade_tile
     }
 
     pub fn step(&mut self, cpu_clocks_passed: usize, mmu: &mut MMU) {
@@ -191,6 +445,7 @@ impl PPU {
                 }
             }
 
+            let previous_mode = self.mode;
             match self.mode_clock {
                 t if t <= 80 => self.mode = 2,
                 t if t <= 252 => self.mode = 3,
@@ -205,6 +460,14 @@ impl PPU {
                 }
                 _ => panic!("Not handled mode_clock"),
             }
+            if self.mode == 0 && previous_mode != 0 {
+                mmu.step_hblank_dma();
+            }
+            if self.mode == 1 && previous_mode != 1 {
+                // VBlank interrupt
+                mmu.request_interrupt(0b0000_0001);
+                mmu.step_sgb_vblank();
+            }
 
             // change the appropriated PPU register (LY, LYC, STAT)
             // @TODO Check LYC behavior
@@ -233,3 +496,124 @@ impl PPU {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_viewport_wraps_around_the_background_map() {
+        let mut mmu = MMU::new();
+        mmu.set_model(Model::Sgb);
+        mmu.write_byte(0xFF43, 200);
+        mmu.write_byte(0xFF42, 150);
+        let mut ppu = PPU::new();
+        // a mark at the top left corner of the map, 56 pixels in and 106
+        // down once it has wrapped around
+        ppu.background_shades[0] = 3;
+        ppu.background_buffer[0] = DARKEST_GREEN;
+        ppu.transform_background_buffer_into_screen(&mmu);
+        ppu.refresh_sgb_screen(&mmu);
+
+        assert_eq!(ppu.get_viewport().len(), SCREEN_WIDTH * SCREEN_HEIGHT);
+        assert_eq!(ppu.get_viewport()[106 * SCREEN_WIDTH + 56], DARKEST_GREEN);
+        assert_eq!(ppu.viewport_shades[106 * SCREEN_WIDTH + 56], 3);
+        let black = ppu.transform_cgb_color_to_minifb_color(0x0000);
+        let sgb_x = GAME_X + 56;
+        let sgb_y = GAME_Y + 106;
+        assert_eq!(
+            ppu.get_sgb_screen()[sgb_y * SGB_SCREEN_WIDTH + sgb_x],
+            black
+        );
+    }
+
+    #[test]
+    fn cgb_background_follows_the_lcdc_map_and_tile_data_bits() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x143] = 0x80;
+        let mut mmu = MMU::new();
+        mmu.from_rom_file(&rom).unwrap();
+        // palette 0 color 1 is red
+        mmu.write_byte(0xFF68, 0b1000_0010);
+        mmu.write_byte(0xFF69, 0x1F);
+        mmu.write_byte(0xFF69, 0x00);
+        // tile -1 sits at 0x8FF0, its top left pixel is color 1
+        mmu.write_byte(0x8FF0, 0b1000_0000);
+        mmu.write_byte(0x9C00, 0xFF);
+        let mut ppu = PPU::new();
+        let red = ppu.transform_cgb_color_to_minifb_color(0x001F);
+
+        // map at 0x9C00, signed tile numbers
+        mmu.dirty_vram_flag = false;
+        mmu.write_byte(0xFF40, 0b1000_1000);
+        assert!(mmu.dirty_vram_flag);
+        ppu.populate_background_buffer(&mmu);
+        assert_eq!(ppu.get_background_buffer()[0], red);
+
+        // map at 0x9800, where tile 0 is empty
+        mmu.write_byte(0xFF40, 0b1000_0000);
+        ppu.populate_background_buffer(&mmu);
+        assert_ne!(ppu.get_background_buffer()[0], red);
+    }
+
+    #[test]
+    fn cgb_sprites_use_obj_palettes_and_bg_priority() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x143] = 0x80;
+        let mut mmu = MMU::new();
+        mmu.from_rom_file(&rom).unwrap();
+        // background palette 0 color 1 is red, object palette 2 color 3 green
+        mmu.write_byte(0xFF68, 0b1000_0010);
+        mmu.write_byte(0xFF69, 0x1F);
+        mmu.write_byte(0xFF69, 0x00);
+        mmu.write_byte(0xFF6A, 0b1000_0000 | 22);
+        mmu.write_byte(0xFF6B, 0xE0);
+        mmu.write_byte(0xFF6B, 0x03);
+        // the map is all tile 0, its top left pixel is color 1 and the rest 0
+        mmu.write_byte(0x8000, 0b1000_0000);
+        // the first map entry has the BG-to-OAM priority bit
+        mmu.write_byte(0xFF4F, 1);
+        mmu.write_byte(0x9800, 0b1000_0000);
+        mmu.write_byte(0xFF4F, 0);
+        // tile 1 is solid color 3, sprite 0 puts it at the top left corner
+        for i in 0x8010..0x8020 {
+            mmu.write_byte(i, 0xFF);
+        }
+        mmu.write_byte(0xFE00, 16);
+        mmu.write_byte(0xFE01, 8);
+        mmu.write_byte(0xFE02, 1);
+        mmu.write_byte(0xFE03, 2);
+        mmu.write_byte(0xFF40, 0b1001_0011);
+        let mut ppu = PPU::new();
+        let red = ppu.transform_cgb_color_to_minifb_color(0x001F);
+        let green = ppu.transform_cgb_color_to_minifb_color(0x03E0);
+        let mut draw = |mmu: &MMU| {
+            ppu.populate_background_buffer(mmu);
+            ppu.transform_background_buffer_into_screen(mmu);
+            (ppu.get_viewport()[0], ppu.get_viewport()[1])
+        };
+
+        // the priority bit keeps the background on top, except for color 0
+        assert_eq!(draw(&mmu), (red, green));
+
+        mmu.write_byte(0xFF4F, 1);
+        mmu.write_byte(0x9800, 0);
+        mmu.write_byte(0xFF4F, 0);
+        assert_eq!(draw(&mmu), (green, green));
+
+        // the sprite's own priority bit does the same
+        mmu.write_byte(0xFE03, 0b1000_0010);
+        assert_eq!(draw(&mmu), (red, green));
+
+        // LCDC bit 0 off puts sprites above everything
+        mmu.write_byte(0xFF40, 0b1001_0010);
+        assert_eq!(draw(&mmu), (green, green));
+
+        // and bit 1 off hides them
+        mmu.write_byte(0xFF40, 0b1001_0001);
+        assert_eq!(
+            draw(&mmu).1,
+            ppu.transform_cgb_color_to_minifb_color(0xFFFF)
+        );
+    }
+}
@@ -0,0 +1,442 @@
+// Game Boy Printer, plugged into the serial port as a peer.
+// The game sends packets like:
+//   0x88 0x33 command compression len_lo len_hi data... sum_lo sum_hi 0x00 0x00
+// and the printer answers 0x81 on the first of the two trailing bytes and
+// its status on the second one. Everything else gets 0x00 back.
+
+use crate::serial::SerialPeer;
+
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+
+const COMMAND_INIT: u8 = 0x01;
+const COMMAND_PRINT: u8 = 0x02;
+const COMMAND_DATA: u8 = 0x04;
+const COMMAND_BREAK: u8 = 0x08;
+const COMMAND_STATUS: u8 = 0x0F;
+
+const STATUS_CHECKSUM_ERROR: u8 = 0b0000_0001;
+const STATUS_PRINTING: u8 = 0b0000_0010;
+const STATUS_IMAGE_FULL: u8 = 0b0000_0100;
+const STATUS_UNPROCESSED: u8 = 0b0000_1000;
+
+// the paper is 160 pixels wide, 20 tiles
+pub const PRINTER_WIDTH: usize = 160;
+const TILES_PER_ROW: usize = PRINTER_WIDTH / 8;
+// the printer holds 9 DATA packets of 2 tile rows each
+const BUFFER_SIZE: usize = 0x280 * 9;
+// how many STATUS packets report "printing" before the print is done
+const PRINTING_STATUS_CHECKS: u8 = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+    Magic1,
+    Magic2,
+    Command,
+    Compression,
+    LengthLow,
+    LengthHigh,
+    Data,
+    ChecksumLow,
+    ChecksumHigh,
+    Alive,
+    Status,
+}
+
+pub struct Printer {
+    state: State,
+    command: u8,
+    compressed: bool,
+    length: usize,
+    data: Vec<u8>,
+    checksum: u16,
+    received_checksum: u16,
+    status: u8,
+    printing_checks: u8,
+    // 2bpp tile data waiting for a PRINT command
+    buffer: Vec<u8>,
+    // shades (0-3) of the page being printed, pages without a bottom margin
+    // are continued by the next print
+    page: Vec<u8>,
+    output_dir: PathBuf,
+    pages_printed: usize,
+}
+
+impl Printer {
+    pub fn new(output_dir: PathBuf) -> Printer {
+        Printer {
+            state: State::Magic1,
+            command: 0,
+            compressed: false,
+            length: 0,
+            data: Vec::new(),
+            checksum: 0,
+            received_checksum: 0,
+            status: 0,
+            printing_checks: 0,
+            buffer: Vec::with_capacity(BUFFER_SIZE),
+            page: Vec::new(),
+            output_dir,
+            pages_printed: 0,
+        }
+    }
+
+    fn receive_byte(&mut self, value: u8) -> u8 {
+        let mut response = 0x00;
+        match self.state {
+            State::Magic1 => {
+                if value == 0x88 {
+                    self.state = State::Magic2;
+                }
+            }
+            State::Magic2 => {
+                self.state = if value == 0x33 {
+                    State::Command
+                } else {
+                    State::Magic1
+                };
+            }
+            State::Command => {
+                self.command = value;
+                self.checksum = value as u16;
+                self.state = State::Compression;
+            }
+            State::Compression => {
+                self.compressed = (value & 0b0000_0001) != 0;
+                self.checksum = self.checksum.wrapping_add(value as u16);
+                self.state = State::LengthLow;
+            }
+            State::LengthLow => {
+                self.length = value as usize;
+                self.checksum = self.checksum.wrapping_add(value as u16);
+                self.state = State::LengthHigh;
+            }
+            State::LengthHigh => {
+                self.length |= (value as usize) << 8;
+                self.checksum = self.checksum.wrapping_add(value as u16);
+                self.data.clear();
+                self.state = if self.length == 0 {
+                    State::ChecksumLow
+                } else {
+                    State::Data
+                };
+            }
+            State::Data => {
+                self.data.push(value);
+                self.checksum = self.checksum.wrapping_add(value as u16);
+                if self.data.len() == self.length {
+                    self.state = State::ChecksumLow;
+                }
+            }
+            State::ChecksumLow => {
+                self.received_checksum = value as u16;
+                self.state = State::ChecksumHigh;
+            }
+            State::ChecksumHigh => {
+                self.received_checksum |= (value as u16) << 8;
+                self.state = State::Alive;
+            }
+            State::Alive => {
+                response = 0x81;
+                // the status we answer with already knows about this packet
+                self.run_command();
+                self.state = State::Status;
+            }
+            State::Status => {
+                response = self.status;
+                self.state = State::Magic1;
+            }
+        }
+        response
+    }
+
+    fn run_command(&mut self) {
+        if self.checksum != self.received_checksum {
+            self.status |= STATUS_CHECKSUM_ERROR;
+            return;
+        }
+        self.status &= !STATUS_CHECKSUM_ERROR;
+        match self.command {
+            COMMAND_INIT => {
+                self.buffer.clear();
+                self.status = 0;
+                self.printing_checks = 0;
+            }
+            COMMAND_DATA => {
+                self.append_data();
+                if !self.buffer.is_empty() {
+                    self.status |= STATUS_UNPROCESSED;
+                }
+                if self.buffer.len() >= BUFFER_SIZE {
+                    self.status |= STATUS_IMAGE_FULL;
+                }
+            }
+            COMMAND_PRINT => {
+                if self.data.len() == 4 {
+                    let margins = self.data[1];
+                    let palette = self.data[2];
+                    self.print(margins, palette);
+                }
+                self.status &= !(STATUS_UNPROCESSED | STATUS_IMAGE_FULL);
+                self.status |= STATUS_PRINTING;
+                self.printing_checks = PRINTING_STATUS_CHECKS;
+            }
+            COMMAND_BREAK => {
+                self.buffer.clear();
+                self.status &= !(STATUS_PRINTING | STATUS_UNPROCESSED | STATUS_IMAGE_FULL);
+                self.printing_checks = 0;
+            }
+            // printing takes a few status checks to finish
+            COMMAND_STATUS if self.printing_checks > 0 => {
+                self.printing_checks -= 1;
+                if self.printing_checks == 0 {
+                    self.status &= !STATUS_PRINTING;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // Compressed data is a list of runs: a control byte with bit 7 set means
+    // repeat the next byte (n & 0x7F) + 2 times, otherwise n + 1 bytes
+    // follow as they are.
+    fn append_data(&mut self) {
+        if !self.compressed {
+            self.buffer.extend_from_slice(&self.data);
+        } else {
+            let mut i = 0;
+            while i < self.data.len() {
+                let control = self.data[i];
+                i += 1;
+                if (control & 0b1000_0000) != 0 {
+                    let count = (control & 0b0111_1111) as usize + 2;
+                    if let Some(&value) = self.data.get(i) {
+                        self.buffer.resize(self.buffer.len() + count, value);
+                    }
+                    i += 1;
+                } else {
+                    let count = control as usize + 1;
+                    let end = (i + count).min(self.data.len());
+                    self.buffer.extend_from_slice(&self.data[i..end]);
+                    i = end;
+                }
+            }
+        }
+        self.buffer.truncate(BUFFER_SIZE);
+    }
+
+    fn print(&mut self, margins: u8, palette: u8) {
+        let top_margin = (margins >> 4) as usize;
+        let bottom_margin = (margins & 0b0000_1111) as usize;
+        if top_margin > 0 && !self.page.is_empty() {
+            self.finish_page();
+        }
+        let tiles = self.buffer.len() / 16;
+        let rows = tiles / TILES_PER_ROW * 8;
+        let start = self.page.len();
+        self.page.resize(start + rows * PRINTER_WIDTH, 0);
+        for tile in 0..(rows / 8 * TILES_PER_ROW) {
+            let tile_x = (tile % TILES_PER_ROW) * 8;
+            let tile_y = (tile / TILES_PER_ROW) * 8;
+            for line in 0..8 {
+                let low = self.buffer[tile * 16 + line * 2];
+                let high = self.buffer[tile * 16 + line * 2 + 1];
+                for pixel in 0..8 {
+                    let bit = 7 - pixel;
+                    let color = (((high >> bit) & 0b1) << 1) | ((low >> bit) & 0b1);
+                    let shade = (palette >> (color * 2)) & 0b11;
+                    let y = tile_y + line;
+                    self.page[start + y * PRINTER_WIDTH + tile_x + pixel] = shade;
+                }
+            }
+        }
+        self.buffer.clear();
+        if bottom_margin > 0 {
+            self.finish_page();
+        }
+    }
+
+    // Writes whatever was printed so far as the next PNG
+    pub fn finish_page(&mut self) {
+        if self.page.is_empty() {
+            return;
+        }
+        self.pages_printed += 1;
+        let path = self
+            .output_dir
+            .join(format!("print_{:03}.png", self.pages_printed));
+        let page = std::mem::take(&mut self.page);
+        if let Err(error) = write_png(&path, &page) {
+            eprintln!("could not save printout {}: {}", path.display(), error);
+        }
+    }
+
+    pub fn get_pages_printed(&self) -> usize {
+        self.pages_printed
+    }
+}
+
+impl SerialPeer for Printer {
+    fn send(&mut self, outgoing: u8) -> Option<u8> {
+        Some(self.receive_byte(outgoing))
+    }
+
+    // the printer never drives the clock
+    fn receive(&mut self, _shift_register: u8) -> Option<u8> {
+        None
+    }
+}
+
+impl Drop for Printer {
+    fn drop(&mut self) {
+        // don't lose a page that never got its bottom margin
+        self.finish_page();
+    }
+}
+
+fn write_png(path: &Path, shades: &[u8]) -> Result<(), png::EncodingError> {
+    let height = shades.len() / PRINTER_WIDTH;
+    let file = File::create(path)?;
+    let mut encoder = png::Encoder::new(BufWriter::new(file), PRINTER_WIDTH as u32, height as u32);
+    encoder.set_color(png::ColorType::Grayscale);
+    encoder.set_depth(png::BitDepth::Eight);
+    let pixels: Vec<u8> = shades.iter().map(|shade| 255 - shade * 85).collect();
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&pixels)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    // A whole packet with its checksum and the two bytes the printer answers
+    fn packet(command: u8, compression: u8, data: &[u8]) -> Vec<u8> {
+        let length = (data.len() as u16).to_le_bytes();
+        let mut packet = vec![0x88, 0x33, command, compression, length[0], length[1]];
+        packet.extend_from_slice(data);
+        let checksum = packet[2..]
+            .iter()
+            .fold(0u16, |sum, byte| sum.wrapping_add(*byte as u16));
+        packet.extend_from_slice(&checksum.to_le_bytes());
+        packet.extend_from_slice(&[0x00, 0x00]);
+        packet
+    }
+
+    // Returns what the printer answered on the two trailing bytes
+    fn send(printer: &mut Printer, packet: &[u8]) -> (u8, u8) {
+        let answers: Vec<u8> = packet
+            .iter()
+            .map(|byte| printer.send(*byte).unwrap())
+            .collect();
+        assert!(answers[..answers.len() - 2]
+            .iter()
+            .all(|answer| *answer == 0));
+        (answers[answers.len() - 2], answers[answers.len() - 1])
+    }
+
+    // Two rows of tiles, the top line of the first tile is color 1 and
+    // everything else color 0
+    fn two_tile_rows() -> Vec<u8> {
+        let mut data = vec![0; TILES_PER_ROW * 2 * 16];
+        data[0] = 0xFF;
+        data
+    }
+
+    fn print_packet(margins: u8) -> Vec<u8> {
+        // palette 0b11_10_01_00 keeps each color as its shade
+        packet(COMMAND_PRINT, 0, &[0x01, margins, 0xE4, 0x40])
+    }
+
+    fn output_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("gbrustemu-printer-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn read_png(path: &Path) -> (u32, u32, Vec<u8>) {
+        let decoder = png::Decoder::new(File::open(path).unwrap());
+        let mut reader = decoder.read_info().unwrap();
+        let mut pixels = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut pixels).unwrap();
+        (info.width, info.height, pixels)
+    }
+
+    #[test]
+    fn prints_a_page_from_init_data_and_print_packets() {
+        let dir = output_dir("page");
+        let mut printer = Printer::new(dir.clone());
+        assert_eq!(send(&mut printer, &packet(COMMAND_INIT, 0, &[])), (0x81, 0));
+
+        let (alive, status) = send(&mut printer, &packet(COMMAND_DATA, 0, &two_tile_rows()));
+        assert_eq!((alive, status), (0x81, STATUS_UNPROCESSED));
+        assert_eq!(printer.buffer.len(), 0x280);
+        // an empty DATA packet ends the data
+        send(&mut printer, &packet(COMMAND_DATA, 0, &[]));
+
+        let (_, status) = send(&mut printer, &print_packet(0x01));
+        assert_eq!(status, STATUS_PRINTING);
+        assert!(printer.buffer.is_empty());
+        for _ in 1..PRINTING_STATUS_CHECKS {
+            let (_, status) = send(&mut printer, &packet(COMMAND_STATUS, 0, &[]));
+            assert_eq!(status, STATUS_PRINTING);
+        }
+        assert_eq!(send(&mut printer, &packet(COMMAND_STATUS, 0, &[])).1, 0);
+
+        // the bottom margin finished the page
+        assert_eq!(printer.get_pages_printed(), 1);
+        let (width, height, pixels) = read_png(&dir.join("print_001.png"));
+        assert_eq!((width, height), (PRINTER_WIDTH as u32, 16));
+        assert!(pixels[..8].iter().all(|pixel| *pixel == 255 - 85));
+        assert!(pixels[8..].iter().all(|pixel| *pixel == 255));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn decompresses_runs_of_repeated_and_literal_bytes() {
+        let mut printer = Printer::new(std::env::temp_dir());
+        // 0x81: the next byte 3 times, 0x01: the next 2 bytes as they are
+        let data = [0x81, 0xAA, 0x01, 0x11, 0x22];
+        send(&mut printer, &packet(COMMAND_DATA, 1, &data));
+        assert_eq!(printer.buffer, vec![0xAA, 0xAA, 0xAA, 0x11, 0x22]);
+    }
+
+    #[test]
+    fn a_bad_checksum_is_reported_and_the_packet_dropped() {
+        let mut printer = Printer::new(std::env::temp_dir());
+        let mut bad = packet(COMMAND_DATA, 0, &two_tile_rows());
+        let checksum_low = bad.len() - 4;
+        bad[checksum_low] ^= 0xFF;
+        assert_eq!(send(&mut printer, &bad), (0x81, STATUS_CHECKSUM_ERROR));
+        assert!(printer.buffer.is_empty());
+
+        // the next good packet clears it
+        let (_, status) = send(&mut printer, &packet(COMMAND_DATA, 0, &two_tile_rows()));
+        assert_eq!(status, STATUS_UNPROCESSED);
+    }
+
+    #[test]
+    fn prints_without_a_bottom_margin_continue_the_page() {
+        let dir = output_dir("margins");
+        let mut printer = Printer::new(dir.clone());
+        for _ in 0..2 {
+            send(&mut printer, &packet(COMMAND_DATA, 0, &two_tile_rows()));
+            send(&mut printer, &print_packet(0x00));
+        }
+        assert_eq!(printer.get_pages_printed(), 0);
+        // a top margin starts a new page, the bottom margin ends it
+        send(&mut printer, &packet(COMMAND_DATA, 0, &two_tile_rows()));
+        send(&mut printer, &print_packet(0x11));
+        assert_eq!(printer.get_pages_printed(), 2);
+
+        let (_, height, _) = read_png(&dir.join("print_001.png"));
+        assert_eq!(height, 32);
+        let (_, height, pixels) = read_png(&dir.join("print_002.png"));
+        assert_eq!(height, 16);
+        assert_eq!(pixels[0], 255 - 85);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
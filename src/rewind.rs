@@ -0,0 +1,202 @@
+use crate::gameboy::GameBoy;
+use crate::savestate::StateError;
+
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
+use std::collections::VecDeque;
+use std::io::{Read, Write};
+
+// Rewinding: a save state every few frames, going back one at a time. Only
+// the newest is kept whole, each older one is what changed from the one
+// after it, XORed and deflated. Most of the console stays the same between
+// frames, so those are tiny. The oldest go first when over the budget.
+
+pub const DEFAULT_INTERVAL: usize = 2;
+pub const DEFAULT_BUDGET: usize = 32 * 1024 * 1024;
+
+pub struct Rewind {
+    // frames between snapshots
+    interval: usize,
+    // bytes all the snapshots can take together
+    budget: usize,
+    frames_since_snapshot: usize,
+    newest: Option<Vec<u8>>,
+    // oldest first, each one turns the snapshot after it back into its own
+    deltas: VecDeque<Vec<u8>>,
+    size: usize,
+}
+
+// `a` ^ `b`, the shorter one padded with zeros
+fn xor(a: &[u8], b: &[u8]) -> Vec<u8> {
+    let (mut xor, other) = if a.len() >= b.len() {
+        (a.to_vec(), b)
+    } else {
+        (b.to_vec(), a)
+    };
+    for (byte, other) in xor.iter_mut().zip(other.iter()) {
+        *byte ^= other;
+    }
+    xor
+}
+
+// The length of `older`, then `newer` ^ `older` deflated
+fn delta(newer: &[u8], older: &[u8]) -> Vec<u8> {
+    let xor = xor(newer, older);
+    let mut encoder = DeflateEncoder::new(
+        (older.len() as u64).to_le_bytes().to_vec(),
+        Compression::fast(),
+    );
+    // writing to a Vec can't fail
+    encoder.write_all(&xor).unwrap();
+    encoder.finish().unwrap()
+}
+
+fn undo_delta(newer: &[u8], delta: &[u8]) -> Vec<u8> {
+    let mut length = [0; 8];
+    length.copy_from_slice(&delta[..8]);
+    let mut difference = Vec::new();
+    // only ever reads back what delta() wrote
+    DeflateDecoder::new(&delta[8..])
+        .read_to_end(&mut difference)
+        .unwrap();
+    let mut older = xor(&difference, newer);
+    older.truncate(u64::from_le_bytes(length) as usize);
+    older
+}
+
+impl Rewind {
+    pub fn new(interval: usize, budget: usize) -> Rewind {
+        Rewind {
+            interval: interval.max(1),
+            budget,
+            frames_since_snapshot: 0,
+            newest: None,
+            deltas: VecDeque::new(),
+            size: 0,
+        }
+    }
+
+    // Call it once per frame that ran
+    pub fn record(&mut self, gameboy: &GameBoy) {
+        self.frames_since_snapshot += 1;
+        if self.newest.is_some() && self.frames_since_snapshot < self.interval {
+            return;
+        }
+        self.frames_since_snapshot = 0;
+        let state = gameboy.save_state();
+        if let Some(newest) = self.newest.take() {
+            let delta = delta(&state, &newest);
+            self.size += delta.len();
+            self.size -= newest.len();
+            self.deltas.push_back(delta);
+        }
+        self.size += state.len();
+        self.newest = Some(state);
+        while self.size > self.budget && !self.deltas.is_empty() {
+            let oldest = self.deltas.pop_front().unwrap();
+            self.size -= oldest.len();
+        }
+    }
+
+    // Goes back to the last snapshot, or the one before it if nothing ran
+    // since. Returns false when there is nothing older to go back to.
+    pub fn rewind(&mut self, gameboy: &mut GameBoy) -> Result<bool, StateError> {
+        let newest = match self.newest.take() {
+            Some(newest) => newest,
+            None => return Ok(false),
+        };
+        let went_back = if self.frames_since_snapshot > 0 {
+            self.newest = Some(newest);
+            true
+        } else if let Some(delta) = self.deltas.pop_back() {
+            let older = undo_delta(&newest, &delta);
+            self.size = self.size - newest.len() - delta.len() + older.len();
+            self.newest = Some(older);
+            true
+        } else {
+            self.newest = Some(newest);
+            false
+        };
+        self.frames_since_snapshot = 0;
+        if let Some(newest) = &self.newest {
+            gameboy.load_state(newest)?;
+        }
+        Ok(went_back)
+    }
+
+    pub fn clear(&mut self) {
+        self.frames_since_snapshot = 0;
+        self.newest = None;
+        self.deltas.clear();
+        self.size = 0;
+    }
+
+    // How many snapshots there are to go back to
+    pub fn get_snapshots(&self) -> usize {
+        self.newest.iter().count() + self.deltas.len()
+    }
+
+    // Bytes taken by all of them
+    pub fn get_size(&self) -> usize {
+        self.size
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mmu::Model;
+
+    // INC A; LD ($C000),A; JR -6, forever
+    fn gameboy() -> GameBoy {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x106].copy_from_slice(&[0x3C, 0xEA, 0x00, 0xC0, 0x18, 0xFA]);
+        GameBoy::new(Model::Dmg, &rom, None, None).unwrap()
+    }
+
+    #[test]
+    fn deltas_give_back_the_older_state() {
+        let older = vec![1, 2, 3, 4, 5];
+        for newer in [vec![1, 2, 9, 4, 5], vec![1, 2], vec![7; 9]].iter() {
+            assert_eq!(undo_delta(newer, &delta(newer, &older)), older);
+        }
+    }
+
+    #[test]
+    fn rewinds_through_the_snapshots_in_order() {
+        let mut gameboy = gameboy();
+        let mut rewind = Rewind::new(2, DEFAULT_BUDGET);
+        let mut snapshots = Vec::new();
+        for frame in 1..=10 {
+            gameboy.run_frame().unwrap();
+            rewind.record(&gameboy);
+            // the first frame and then every other one
+            if frame % 2 == 1 {
+                snapshots.push(gameboy.save_state());
+            }
+        }
+        assert_eq!(rewind.get_snapshots(), 5);
+        // one frame ran since the last snapshot, the first step goes to it
+        for snapshot in snapshots.iter().rev() {
+            assert_eq!(rewind.rewind(&mut gameboy), Ok(true));
+            assert_eq!(&gameboy.save_state(), snapshot);
+        }
+        assert_eq!(rewind.rewind(&mut gameboy), Ok(false));
+        assert_eq!(&gameboy.save_state(), &snapshots[0]);
+    }
+
+    #[test]
+    fn the_oldest_snapshots_go_when_over_the_budget() {
+        let mut gameboy = gameboy();
+        gameboy.run_frame().unwrap();
+        let state_size = gameboy.save_state().len();
+        let mut rewind = Rewind::new(1, state_size + 8192);
+        for _ in 0..30 {
+            gameboy.run_frame().unwrap();
+            rewind.record(&gameboy);
+        }
+        assert!(rewind.get_size() <= state_size + 8192);
+        assert!(rewind.get_snapshots() > 1 && rewind.get_snapshots() < 30);
+    }
+}
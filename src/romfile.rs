@@ -0,0 +1,161 @@
+use flate2::read::GzDecoder;
+use std::fs;
+use std::io::{self, Cursor, Read};
+use std::path::Path;
+use zip::ZipArchive;
+
+// ROMs can come as they are, gzipped or inside a zip, what they are is told
+// by the first bytes and not by the extension.
+
+const ZIP_MAGIC: &[u8] = b"PK\x03\x04";
+const GZIP_MAGIC: &[u8] = &[0x1F, 0x8B];
+
+pub struct RomFile {
+    pub data: Vec<u8>,
+    // the name of the ROM itself, the one inside the archive if it came in
+    // one, so saves are named after it
+    pub name: String,
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn is_rom_name(name: &str) -> bool {
+    let name = name.to_lowercase();
+    name.ends_with(".gb") || name.ends_with(".gbc")
+}
+
+// Just the file name, archives keep the folders in the entry names
+fn file_name(path: &str) -> String {
+    Path::new(path)
+        .file_name()
+        .map_or(path.to_string(), |name| name.to_string_lossy().to_string())
+}
+
+// `entry` picks a file inside a zip, otherwise the first .gb or .gbc is used
+pub fn load_rom(path: &Path, entry: Option<&str>) -> io::Result<RomFile> {
+    let bytes = fs::read(path)?;
+    let name = path
+        .file_name()
+        .map_or(String::new(), |name| name.to_string_lossy().to_string());
+    load_rom_bytes(bytes, &name, entry)
+}
+
+pub fn load_rom_bytes(bytes: Vec<u8>, name: &str, entry: Option<&str>) -> io::Result<RomFile> {
+    if bytes.starts_with(ZIP_MAGIC) {
+        load_zip(bytes, entry)
+    } else if bytes.starts_with(GZIP_MAGIC) {
+        load_gzip(bytes, name)
+    } else {
+        let rom = RomFile {
+            data: bytes,
+            name: name.to_string(),
+        };
+        Ok(rom)
+    }
+}
+
+fn load_zip(bytes: Vec<u8>, entry: Option<&str>) -> io::Result<RomFile> {
+    let mut archive = ZipArchive::new(Cursor::new(bytes))?;
+    let wanted = |name: &str| match entry {
+        Some(entry) => name == entry,
+        None => is_rom_name(name),
+    };
+    let index = (0..archive.len())
+        .find(|&index| {
+            archive
+                .by_index_raw(index)
+                .is_ok_and(|file| wanted(file.name()))
+        })
+        .ok_or_else(|| match entry {
+            Some(entry) => invalid_data(format!("no {} in the zip", entry)),
+            None => invalid_data("no .gb or .gbc file in the zip".to_string()),
+        })?;
+    let mut file = archive.by_index(index)?;
+    let mut data = Vec::new();
+    file.read_to_end(&mut data)?;
+    let rom = RomFile {
+        data,
+        name: file_name(file.name()),
+    };
+    Ok(rom)
+}
+
+fn load_gzip(bytes: Vec<u8>, name: &str) -> io::Result<RomFile> {
+    let mut decoder = GzDecoder::new(&bytes[..]);
+    let mut data = Vec::new();
+    decoder.read_to_end(&mut data)?;
+    // the gzip header may keep the original name, otherwise drop the .gz
+    let original_name = decoder
+        .header()
+        .and_then(|header| header.filename())
+        .map(|filename| file_name(&String::from_utf8_lossy(filename)));
+    let name = match original_name {
+        Some(original_name) => original_name,
+        None => name
+            .strip_suffix(".gz")
+            .or_else(|| name.strip_suffix(".GZ"))
+            .unwrap_or(name)
+            .to_string(),
+    };
+    let rom = RomFile { data, name };
+    Ok(rom)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use flate2::{Compression, GzBuilder};
+    use std::io::Write;
+    use zip::write::{FileOptions, ZipWriter};
+
+    fn zip(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+        for (name, data) in files.iter() {
+            writer.start_file(*name, FileOptions::default()).unwrap();
+            writer.write_all(data).unwrap();
+        }
+        writer.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn plain_roms_are_left_alone() {
+        let rom = load_rom_bytes(vec![1, 2, 3], "tetris.gb", None).unwrap();
+        assert_eq!(rom.data, vec![1, 2, 3]);
+        assert_eq!(rom.name, "tetris.gb");
+    }
+
+    #[test]
+    fn zip_gives_the_first_rom_or_the_named_one() {
+        let archive = zip(&[
+            ("readme.txt", b"hi"),
+            ("roms/tetris.gb", &[1, 2]),
+            ("roms/zelda.gbc", &[3, 4]),
+        ]);
+        let rom = load_rom_bytes(archive.clone(), "games.zip", None).unwrap();
+        assert_eq!((rom.data, rom.name.as_str()), (vec![1, 2], "tetris.gb"));
+
+        let rom = load_rom_bytes(archive.clone(), "games.zip", Some("roms/zelda.gbc")).unwrap();
+        assert_eq!((rom.data, rom.name.as_str()), (vec![3, 4], "zelda.gbc"));
+
+        assert!(load_rom_bytes(archive, "games.zip", Some("mario.gb")).is_err());
+        assert!(load_rom_bytes(zip(&[("readme.txt", b"hi")]), "games.zip", None).is_err());
+    }
+
+    #[test]
+    fn gzip_is_named_after_the_original_file() {
+        let mut encoder = GzBuilder::new()
+            .filename("pokemon.gb")
+            .write(Vec::new(), Compression::default());
+        encoder.write_all(&[5, 6]).unwrap();
+        let rom = load_rom_bytes(encoder.finish().unwrap(), "red.gz", None).unwrap();
+        assert_eq!((rom.data, rom.name.as_str()), (vec![5, 6], "pokemon.gb"));
+
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&[7]).unwrap();
+        let rom = load_rom_bytes(encoder.finish().unwrap(), "tetris.gb.gz", None).unwrap();
+        assert_eq!((rom.data, rom.name.as_str()), (vec![7], "tetris.gb"));
+    }
+}
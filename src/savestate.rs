@@ -0,0 +1,315 @@
+use std::convert::TryFrom;
+use std::error::Error;
+use std::fmt;
+
+// Save states: every part of the console writes its fields, in order, after
+// a header with the format version and a checksum of the ROM, so a state is
+// only ever loaded into the game it was made with. Numbers are little endian.
+
+const MAGIC: &[u8; 8] = b"GBRUSTSS";
+// bump it whenever what a part writes changes
+pub const FORMAT_VERSION: u16 = 1;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum StateError {
+    NotAState,
+    UnsupportedVersion { version: u16 },
+    // made for another ROM, checksums of the whole ROM
+    WrongRom { expected: u32, actual: u32 },
+    // cut short or holding values nothing could have written
+    Corrupt,
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StateError::NotAState => write!(f, "not a save state"),
+            StateError::UnsupportedVersion { version } => write!(
+                f,
+                "save state format {} is not supported, expected {}",
+                version, FORMAT_VERSION
+            ),
+            StateError::WrongRom { expected, actual } => write!(
+                f,
+                "save state is for another ROM (checksum {:08X}, this one is {:08X})",
+                actual, expected
+            ),
+            StateError::Corrupt => write!(f, "save state is corrupt"),
+        }
+    }
+}
+
+impl Error for StateError {}
+
+// CRC-32 of the whole ROM, what the header ties a state to
+pub fn rom_checksum(rom: &[u8]) -> u32 {
+    let mut crc = flate2::Crc::new();
+    crc.update(rom);
+    crc.sum()
+}
+
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new(rom_checksum: u32) -> StateWriter {
+        StateWriter::with_header(MAGIC, FORMAT_VERSION, rom_checksum)
+    }
+
+    // For other files tied to a ROM, like movies
+    pub fn with_header(magic: &[u8; 8], version: u16, rom_checksum: u32) -> StateWriter {
+        let mut writer = StateWriter { data: Vec::new() };
+        writer.write_bytes(magic);
+        writer.write_u16(version);
+        writer.write_u32(rom_checksum);
+        writer
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.data
+    }
+
+    pub fn write_u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    pub fn write_bool(&mut self, value: bool) {
+        self.write_u8(value as u8);
+    }
+
+    pub fn write_u16(&mut self, value: u16) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_u32(&mut self, value: u32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    // usize is 64 bits in the file whatever the machine
+    pub fn write_usize(&mut self, value: usize) {
+        self.data.extend_from_slice(&(value as u64).to_le_bytes());
+    }
+
+    // Fixed size data, the reader has to know how much to read back
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        self.data.extend_from_slice(bytes);
+    }
+
+    pub fn write_u16s(&mut self, values: &[u16]) {
+        for &value in values.iter() {
+            self.write_u16(value);
+        }
+    }
+
+    pub fn write_u32s(&mut self, values: &[u32]) {
+        for &value in values.iter() {
+            self.write_u32(value);
+        }
+    }
+
+    pub fn write_bools(&mut self, values: &[bool]) {
+        for &value in values.iter() {
+            self.write_bool(value);
+        }
+    }
+
+    // Data that can change size, with its length first
+    pub fn write_vec(&mut self, bytes: &[u8]) {
+        self.write_usize(bytes.len());
+        self.write_bytes(bytes);
+    }
+}
+
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> StateReader<'a> {
+    // Checks the header against the ROM that is running
+    pub fn new(data: &'a [u8], rom_checksum: u32) -> Result<StateReader<'a>, StateError> {
+        StateReader::with_header(data, MAGIC, FORMAT_VERSION, rom_checksum)
+    }
+
+    pub fn with_header(
+        data: &'a [u8],
+        magic: &[u8; 8],
+        format_version: u16,
+        rom_checksum: u32,
+    ) -> Result<StateReader<'a>, StateError> {
+        if !data.starts_with(magic) {
+            return Err(StateError::NotAState);
+        }
+        let mut reader = StateReader {
+            data,
+            position: magic.len(),
+        };
+        let version = reader.read_u16()?;
+        if version != format_version {
+            return Err(StateError::UnsupportedVersion { version });
+        }
+        let checksum = reader.read_u32()?;
+        if checksum != rom_checksum {
+            return Err(StateError::WrongRom {
+                expected: rom_checksum,
+                actual: checksum,
+            });
+        }
+        Ok(reader)
+    }
+
+    // Trailing bytes mean the parts didn't read what they wrote
+    pub fn finish(self) -> Result<(), StateError> {
+        if self.position == self.data.len() {
+            Ok(())
+        } else {
+            Err(StateError::Corrupt)
+        }
+    }
+
+    fn take(&mut self, length: usize) -> Result<&'a [u8], StateError> {
+        let end = self
+            .position
+            .checked_add(length)
+            .ok_or(StateError::Corrupt)?;
+        let bytes = self
+            .data
+            .get(self.position..end)
+            .ok_or(StateError::Corrupt)?;
+        self.position = end;
+        Ok(bytes)
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, StateError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn read_bool(&mut self) -> Result<bool, StateError> {
+        match self.read_u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(StateError::Corrupt),
+        }
+    }
+
+    pub fn read_u16(&mut self) -> Result<u16, StateError> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32, StateError> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    pub fn read_usize(&mut self) -> Result<usize, StateError> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        usize::try_from(u64::from_le_bytes(bytes)).map_err(|_| StateError::Corrupt)
+    }
+
+    pub fn read_bytes(&mut self, bytes: &mut [u8]) -> Result<(), StateError> {
+        bytes.copy_from_slice(self.take(bytes.len())?);
+        Ok(())
+    }
+
+    pub fn read_u16s(&mut self, values: &mut [u16]) -> Result<(), StateError> {
+        for value in values.iter_mut() {
+            *value = self.read_u16()?;
+        }
+        Ok(())
+    }
+
+    pub fn read_u32s(&mut self, values: &mut [u32]) -> Result<(), StateError> {
+        for value in values.iter_mut() {
+            *value = self.read_u32()?;
+        }
+        Ok(())
+    }
+
+    pub fn read_bools(&mut self, values: &mut [bool]) -> Result<(), StateError> {
+        for value in values.iter_mut() {
+            *value = self.read_bool()?;
+        }
+        Ok(())
+    }
+
+    pub fn read_vec(&mut self) -> Result<Vec<u8>, StateError> {
+        let length = self.read_usize()?;
+        Ok(self.take(length)?.to_vec())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gameboy::GameBoy;
+    use crate::mmu::Model;
+
+    // INC A; LD ($C000),A; JR -6, forever
+    fn rom(id: u8) -> Vec<u8> {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x106].copy_from_slice(&[0x3C, 0xEA, 0x00, 0xC0, 0x18, 0xFA]);
+        rom[0x134] = id;
+        rom
+    }
+
+    fn gameboy(rom: &[u8]) -> GameBoy {
+        GameBoy::new(Model::Dmg, rom, None, None).unwrap()
+    }
+
+    #[test]
+    fn a_loaded_state_carries_on_the_same_way() {
+        let mut gameboy = gameboy(&rom(0));
+        gameboy.run_cycles(12_345).unwrap();
+        let state = gameboy.save_state();
+        gameboy.run_frame().unwrap();
+        let after_a_frame = gameboy.save_state();
+
+        gameboy.run_frame().unwrap();
+        gameboy.load_state(&state).unwrap();
+        assert_eq!(gameboy.save_state(), state);
+        gameboy.run_frame().unwrap();
+        assert_eq!(gameboy.save_state(), after_a_frame);
+    }
+
+    #[test]
+    fn states_of_another_rom_or_version_are_rejected() {
+        let state = gameboy(&rom(0)).save_state();
+        let mut other = gameboy(&rom(1));
+        assert!(matches!(
+            other.load_state(&state),
+            Err(StateError::WrongRom { .. })
+        ));
+        assert_eq!(other.load_state(b"not a state"), Err(StateError::NotAState));
+
+        let mut newer = state.clone();
+        newer[MAGIC.len()..MAGIC.len() + 2].copy_from_slice(&(FORMAT_VERSION + 1).to_le_bytes());
+        assert_eq!(
+            gameboy(&rom(0)).load_state(&newer),
+            Err(StateError::UnsupportedVersion {
+                version: FORMAT_VERSION + 1
+            })
+        );
+    }
+
+    #[test]
+    fn a_broken_state_leaves_the_console_alone() {
+        let mut gameboy = gameboy(&rom(0));
+        gameboy.run_frame().unwrap();
+        let state = gameboy.save_state();
+        gameboy.run_frame().unwrap();
+        let before = gameboy.save_state();
+
+        assert_eq!(
+            gameboy.load_state(&state[..state.len() - 1]),
+            Err(StateError::Corrupt)
+        );
+        assert_eq!(gameboy.save_state(), before);
+        let mut longer = state.clone();
+        longer.push(0);
+        assert_eq!(gameboy.load_state(&longer), Err(StateError::Corrupt));
+        assert_eq!(gameboy.save_state(), before);
+    }
+}
@@ -0,0 +1,269 @@
+use crate::error::EmuError;
+use crate::gameboy::GameBoy;
+use crate::pacer::CYCLES_PER_FRAME;
+use crate::ppu::GRAY_PALETTE;
+
+use png::{BitDepth, ColorType, Decoder, Encoder, Transformations};
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::path::{Path, PathBuf};
+
+// Compares what is on the screen with a reference picture, like the
+// dmg-acid2 and cgb-acid2 ones. The DMG shades are turned into the plain
+// grays the references use, whatever the palette, CGB colors are compared
+// as they are.
+
+// LD B,B, the acid2 ROMs run it once the picture is done
+const BREAKPOINT: u8 = 0x40;
+
+#[derive(Debug)]
+pub enum ScreenshotError {
+    Emulator(EmuError),
+    Io(io::Error),
+    Decoding(png::DecodingError),
+    Encoding(png::EncodingError),
+    SizeMismatch {
+        expected: (usize, usize),
+        actual: (usize, usize),
+    },
+    // a picture of where they differ was written to `diff`
+    Mismatch {
+        pixels: usize,
+        diff: PathBuf,
+    },
+}
+
+impl fmt::Display for ScreenshotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ScreenshotError::Emulator(error) => write!(f, "{}", error),
+            ScreenshotError::Io(error) => write!(f, "{}", error),
+            ScreenshotError::Decoding(error) => write!(f, "{}", error),
+            ScreenshotError::Encoding(error) => write!(f, "{}", error),
+            ScreenshotError::SizeMismatch { expected, actual } => write!(
+                f,
+                "the reference is {}x{} but the screen is {}x{}",
+                expected.0, expected.1, actual.0, actual.1
+            ),
+            ScreenshotError::Mismatch { pixels, diff } => {
+                write!(f, "{} pixels differ, see {}", pixels, diff.display())
+            }
+        }
+    }
+}
+
+impl Error for ScreenshotError {}
+
+impl From<EmuError> for ScreenshotError {
+    fn from(error: EmuError) -> ScreenshotError {
+        ScreenshotError::Emulator(error)
+    }
+}
+
+impl From<io::Error> for ScreenshotError {
+    fn from(error: io::Error) -> ScreenshotError {
+        ScreenshotError::Io(error)
+    }
+}
+
+impl From<png::DecodingError> for ScreenshotError {
+    fn from(error: png::DecodingError) -> ScreenshotError {
+        ScreenshotError::Decoding(error)
+    }
+}
+
+impl From<png::EncodingError> for ScreenshotError {
+    fn from(error: png::EncodingError) -> ScreenshotError {
+        ScreenshotError::Encoding(error)
+    }
+}
+
+// Runs until the ROM hits LD B,B or `max_frames` frames went by. After the
+// breakpoint one more frame runs so the picture is on the screen.
+pub fn run_until_done(gameboy: &mut GameBoy, max_frames: usize) -> Result<(), EmuError> {
+    let mut cycles = 0;
+    while cycles < max_frames * CYCLES_PER_FRAME {
+        cycles += gameboy.step_instruction()?;
+        if gameboy.get_cpu().get_last_opcode() == Some(BREAKPOINT) {
+            return gameboy.run_frame();
+        }
+    }
+    Ok(())
+}
+
+// The screen as 0xRRGGBB, with the DMG shades swapped for grays
+pub fn capture(gameboy: &GameBoy) -> Vec<u32> {
+    to_grays(gameboy.get_framebuffer(), gameboy.get_ppu().get_palette())
+}
+
+// The four colors of `palette` become the plain grays of GRAY_PALETTE, the
+// rest stay as they are
+pub fn to_grays(framebuffer: &[u32], palette: [u32; 4]) -> Vec<u32> {
+    framebuffer
+        .iter()
+        .map(|&color| {
+            let shade = palette.iter().position(|&shade| shade == color);
+            shade.map_or(color, |shade| GRAY_PALETTE[shade]) & 0x00FF_FFFF
+        })
+        .collect()
+}
+
+// Returns the width, height and 0xRRGGBB pixels, whatever the PNG format
+pub fn read_png(path: &Path) -> Result<(usize, usize, Vec<u32>), ScreenshotError> {
+    let mut decoder = Decoder::new(File::open(path)?);
+    decoder.set_transformations(Transformations::normalize_to_color8());
+    let mut reader = decoder.read_info()?;
+    let mut buffer = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut buffer)?;
+    let bytes = &buffer[..info.buffer_size()];
+
+    let pixels = match info.color_type {
+        ColorType::Grayscale => bytes.iter().map(|&y| gray(y)).collect(),
+        ColorType::GrayscaleAlpha => bytes.chunks(2).map(|ya| gray(ya[0])).collect(),
+        ColorType::Rgb => bytes
+            .chunks(3)
+            .map(|rgb| color(rgb[0], rgb[1], rgb[2]))
+            .collect(),
+        // palettes are expanded to RGB(A) when decoding
+        _ => bytes
+            .chunks(4)
+            .map(|rgba| color(rgba[0], rgba[1], rgba[2]))
+            .collect(),
+    };
+    Ok((info.width as usize, info.height as usize, pixels))
+}
+
+fn gray(y: u8) -> u32 {
+    color(y, y, y)
+}
+
+fn color(r: u8, g: u8, b: u8) -> u32 {
+    ((r as u32) << 16) | ((g as u32) << 8) | b as u32
+}
+
+pub fn write_png(
+    path: &Path,
+    width: usize,
+    height: usize,
+    pixels: &[u32],
+) -> Result<(), ScreenshotError> {
+    let file = File::create(path)?;
+    let mut encoder = Encoder::new(BufWriter::new(file), width as u32, height as u32);
+    encoder.set_color(ColorType::Rgb);
+    encoder.set_depth(BitDepth::Eight);
+    let mut bytes = Vec::with_capacity(pixels.len() * 3);
+    for &pixel in pixels.iter() {
+        bytes.push((pixel >> 16) as u8);
+        bytes.push((pixel >> 8) as u8);
+        bytes.push(pixel as u8);
+    }
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&bytes)?;
+    Ok(())
+}
+
+// Pixel exact comparison. On a mismatch `diff` gets the reference faded out
+// with the wrong pixels in red.
+pub fn compare_with_reference(
+    screen: &[u32],
+    width: usize,
+    height: usize,
+    reference: &Path,
+    diff: &Path,
+) -> Result<(), ScreenshotError> {
+    let (expected_width, expected_height, expected) = read_png(reference)?;
+    if (expected_width, expected_height) != (width, height) {
+        return Err(ScreenshotError::SizeMismatch {
+            expected: (expected_width, expected_height),
+            actual: (width, height),
+        });
+    }
+    let pixels = screen
+        .iter()
+        .zip(expected.iter())
+        .filter(|(actual, expected)| actual != expected)
+        .count();
+    if pixels == 0 {
+        return Ok(());
+    }
+
+    let diff_pixels: Vec<u32> = screen
+        .iter()
+        .zip(expected.iter())
+        .map(|(&actual, &expected)| {
+            if actual == expected {
+                // a quarter of the color over white
+                0xC0C0C0 + ((expected >> 2) & 0x3F3F3F)
+            } else {
+                0xFF0000
+            }
+        })
+        .collect();
+    write_png(diff, width, height, &diff_pixels)?;
+    Err(ScreenshotError::Mismatch {
+        pixels,
+        diff: diff.to_path_buf(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ppu::GREEN_PALETTE;
+    use std::fs;
+
+    const WIDTH: usize = 4;
+    const HEIGHT: usize = 2;
+
+    #[test]
+    fn dmg_shades_become_grays_and_the_rest_stays() {
+        let mut framebuffer = GREEN_PALETTE.to_vec();
+        framebuffer.extend_from_slice(&[0xFF12_3456, GREEN_PALETTE[3], 0, 0]);
+        assert_eq!(
+            to_grays(&framebuffer, GREEN_PALETTE),
+            vec![0xFFFFFF, 0xAAAAAA, 0x555555, 0x000000, 0x123456, 0x000000, 0, 0]
+        );
+    }
+
+    #[test]
+    fn compares_with_a_reference_and_draws_the_differences() {
+        let dir = std::env::temp_dir().join(format!("gbrustemu-screenshot-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let mut framebuffer = GREEN_PALETTE.to_vec();
+        framebuffer.extend_from_slice(&GREEN_PALETTE);
+        let screen = to_grays(&framebuffer, GREEN_PALETTE);
+        let diff = dir.join("diff.png");
+
+        let reference = dir.join("same.png");
+        write_png(&reference, WIDTH, HEIGHT, &screen).unwrap();
+        assert!(compare_with_reference(&screen, WIDTH, HEIGHT, &reference, &diff).is_ok());
+        assert!(!diff.exists());
+
+        // one pixel off
+        let mut other = screen.clone();
+        other[5] = 0xFFFFFF;
+        let reference = dir.join("other.png");
+        write_png(&reference, WIDTH, HEIGHT, &other).unwrap();
+        match compare_with_reference(&screen, WIDTH, HEIGHT, &reference, &diff) {
+            Err(ScreenshotError::Mismatch { pixels: 1, .. }) => {}
+            other => panic!("{:?}", other),
+        }
+        let (width, height, pixels) = read_png(&diff).unwrap();
+        assert_eq!((width, height), (WIDTH, HEIGHT));
+        assert_eq!(pixels[5], 0xFF0000);
+        // the rest is the reference faded towards white
+        assert_eq!(pixels[0], 0xFFFFFF);
+        assert_eq!(pixels[3], 0xC0C0C0);
+        assert_eq!(pixels[4], 0xFFFFFF);
+
+        match compare_with_reference(&screen, WIDTH, 1, &reference, &diff) {
+            Err(ScreenshotError::SizeMismatch { expected, actual }) => {
+                assert_eq!((expected, actual), ((WIDTH, HEIGHT), (WIDTH, 1)))
+            }
+            other => panic!("{:?}", other),
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
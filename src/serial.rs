@@ -0,0 +1,338 @@
+use crate::savestate::{StateError, StateReader, StateWriter};
+
+// $FF01 - SB, $FF02 - SC
+// With the internal clock we shift one bit every 512 clocks (8192 Hz), on
+// CGB bit 1 of SC speeds that up to 262144 Hz.
+const CLOCKS_PER_BIT: usize = 512;
+const FAST_CLOCKS_PER_BIT: usize = 16;
+
+// Whatever is plugged into the link port: another Game Boy, a printer...
+pub trait SerialPeer {
+    // We drive the clock: our byte goes out, theirs comes back. None when
+    // their answer takes a while to come, like over a socket, the transfer
+    // then waits on poll_reply.
+    fn send(&mut self, outgoing: u8) -> Option<u8>;
+    // Asked on every step until the answer to a send is here
+    fn poll_reply(&mut self) -> Option<u8> {
+        Some(0xFF)
+    }
+    // The other side drives the clock: if it sent us a byte, hand it over
+    // and answer with what is in our shift register.
+    fn receive(&mut self, shift_register: u8) -> Option<u8>;
+    // SB changed, for peers that answer a send straight from it
+    fn shift_register_changed(&mut self, _shift_register: u8) {}
+}
+
+pub struct Serial {
+    sb: u8,
+    sc: u8,
+    clocks: usize,
+    bits_left: u8,
+    // what the other side sends back while we shift our byte out
+    incoming: u8,
+    // send didn't answer yet, the bits only shift in once it does
+    waiting_for_reply: bool,
+    // every byte we sent, blargg's test ROMs print their results this way.
+    // Only kept after capture_output, games can send bytes forever.
+    output: Option<Vec<u8>>,
+    peer: Option<Box<dyn SerialPeer>>,
+    // we only look for bytes from an external clock every so often
+    poll_clocks: usize,
+}
+
+impl Default for Serial {
+    fn default() -> Serial {
+        Serial::new()
+    }
+}
+
+impl Serial {
+    pub fn new() -> Serial {
+        Serial {
+            sb: 0,
+            sc: 0,
+            clocks: 0,
+            bits_left: 0,
+            incoming: 0xFF,
+            waiting_for_reply: false,
+            output: None,
+            peer: None,
+            poll_clocks: 0,
+        }
+    }
+
+    pub fn read_sb(&self) -> u8 {
+        self.sb
+    }
+
+    pub fn write_sb(&mut self, value: u8) {
+        self.set_sb(value);
+    }
+
+    fn set_sb(&mut self, value: u8) {
+        self.sb = value;
+        if let Some(peer) = self.peer.as_mut() {
+            peer.shift_register_changed(value);
+        }
+    }
+
+    pub fn read_sc(&self, cgb_mode: bool) -> u8 {
+        if cgb_mode {
+            0b0111_1100 | self.sc
+        } else {
+            0b0111_1110 | self.sc
+        }
+    }
+
+    pub fn write_sc(&mut self, value: u8) {
+        self.sc = value & 0b1000_0011;
+        if self.is_transferring() && self.is_internal_clock() {
+            self.clocks = 0;
+            self.bits_left = 8;
+            if let Some(output) = self.output.as_mut() {
+                output.push(self.sb);
+            }
+            let reply = match self.peer.as_mut() {
+                Some(peer) => peer.send(self.sb),
+                // no cable connected: the line floats high
+                None => Some(0xFF),
+            };
+            self.waiting_for_reply = reply.is_none();
+            self.incoming = reply.unwrap_or(0xFF);
+        }
+    }
+
+    pub fn connect(&mut self, mut peer: Box<dyn SerialPeer>) {
+        peer.shift_register_changed(self.sb);
+        self.peer = Some(peer);
+    }
+
+    pub fn disconnect(&mut self) -> Option<Box<dyn SerialPeer>> {
+        self.peer.take()
+    }
+
+    fn is_transferring(&self) -> bool {
+        (self.sc & 0b1000_0000) != 0
+    }
+
+    fn is_internal_clock(&self) -> bool {
+        (self.sc & 0b0000_0001) != 0
+    }
+
+    // Returns true when a transfer finished, which requests the serial
+    // interrupt
+    pub fn step(&mut self, cpu_clocks_passed: usize, cgb_mode: bool) -> bool {
+        if self.peer.is_some() && self.step_external_clock(cpu_clocks_passed) {
+            return true;
+        }
+        if !self.is_transferring() || !self.is_internal_clock() || self.bits_left == 0 {
+            return false;
+        }
+        let clocks_per_bit = if cgb_mode && (self.sc & 0b0000_0010) != 0 {
+            FAST_CLOCKS_PER_BIT
+        } else {
+            CLOCKS_PER_BIT
+        };
+        self.clocks += cpu_clocks_passed;
+        if self.waiting_for_reply {
+            // the clocks keep counting, so a late answer shifts in at once
+            let reply = match self.peer.as_mut() {
+                Some(peer) => peer.poll_reply(),
+                None => Some(0xFF),
+            };
+            match reply {
+                Some(incoming) => {
+                    self.incoming = incoming;
+                    self.waiting_for_reply = false;
+                }
+                None => return false,
+            }
+        }
+        while self.clocks >= clocks_per_bit && self.bits_left > 0 {
+            self.clocks -= clocks_per_bit;
+            self.bits_left -= 1;
+            let bit = (self.incoming >> self.bits_left) & 0b1;
+            self.sb = (self.sb << 1) | bit;
+        }
+        if self.bits_left > 0 {
+            return false;
+        }
+        self.set_sb(self.sb);
+        self.sc &= 0b0111_1111;
+        true
+    }
+
+    // The other Game Boy clocks a whole byte into us at once
+    fn step_external_clock(&mut self, cpu_clocks_passed: usize) -> bool {
+        self.poll_clocks += cpu_clocks_passed;
+        if self.poll_clocks < CLOCKS_PER_BIT {
+            return false;
+        }
+        self.poll_clocks = 0;
+        // leave the byte on the wire until a transfer is waiting for it here
+        if !self.is_transferring() || self.is_internal_clock() {
+            return false;
+        }
+        let shift_register = self.sb;
+        let incoming = match self
+            .peer
+            .as_mut()
+            .and_then(|peer| peer.receive(shift_register))
+        {
+            Some(incoming) => incoming,
+            None => return false,
+        };
+        self.set_sb(incoming);
+        self.sc &= 0b0111_1111;
+        true
+    }
+
+    // A transfer we started is held up until the other side answers
+    pub fn is_waiting_for_reply(&self) -> bool {
+        self.waiting_for_reply
+    }
+
+    pub fn capture_output(&mut self) {
+        self.output.get_or_insert_with(Vec::new);
+    }
+
+    // Empty unless capture_output was called
+    pub fn get_output(&self) -> &[u8] {
+        self.output.as_deref().unwrap_or(&[])
+    }
+
+    // The printed output and whatever is plugged in are not part of it, so
+    // neither is an answer we were waiting on
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.sb);
+        state.write_u8(self.sc);
+        state.write_usize(self.clocks);
+        state.write_u8(self.bits_left);
+        state.write_u8(self.incoming);
+        state.write_usize(self.poll_clocks);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.sb = state.read_u8()?;
+        self.sc = state.read_u8()?;
+        self.clocks = state.read_usize()?;
+        self.bits_left = state.read_u8()?;
+        self.incoming = state.read_u8()?;
+        self.poll_clocks = state.read_usize()?;
+        self.waiting_for_reply = false;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mmu::MMU;
+
+    // The other side drives the clock and sends one byte
+    struct Sender(Option<u8>);
+
+    impl SerialPeer for Sender {
+        fn send(&mut self, _outgoing: u8) -> Option<u8> {
+            Some(0xFF)
+        }
+
+        fn receive(&mut self, _shift_register: u8) -> Option<u8> {
+            self.0.take()
+        }
+    }
+
+    // Answers a send after being asked `polls` times
+    struct SlowPeer {
+        polls: usize,
+    }
+
+    impl SerialPeer for SlowPeer {
+        fn send(&mut self, _outgoing: u8) -> Option<u8> {
+            None
+        }
+
+        fn poll_reply(&mut self) -> Option<u8> {
+            if self.polls == 0 {
+                return Some(0x3C);
+            }
+            self.polls -= 1;
+            None
+        }
+
+        fn receive(&mut self, _shift_register: u8) -> Option<u8> {
+            None
+        }
+    }
+
+    #[test]
+    fn shifts_a_bit_every_512_clocks() {
+        let mut serial = Serial::new();
+        serial.write_sb(0x42);
+        serial.write_sc(0x81);
+        // half our bits went out and the line, high with no cable, came in
+        assert!(!serial.step(CLOCKS_PER_BIT * 4, false));
+        assert_eq!(serial.read_sb(), 0x2F);
+        assert!(!serial.step(CLOCKS_PER_BIT * 4 - 1, false));
+        assert_eq!(serial.read_sc(false) & 0b1000_0000, 0b1000_0000);
+        assert!(serial.step(1, false));
+        assert_eq!(serial.read_sb(), 0xFF);
+        assert_eq!(serial.read_sc(false) & 0b1000_0000, 0);
+    }
+
+    #[test]
+    fn the_cgb_fast_clock_shifts_a_bit_every_16_clocks() {
+        let mut serial = Serial::new();
+        serial.write_sc(0x83);
+        assert!(!serial.step(FAST_CLOCKS_PER_BIT * 8 - 1, true));
+        assert!(serial.step(1, true));
+        // only in CGB mode
+        serial.write_sc(0x83);
+        assert!(!serial.step(FAST_CLOCKS_PER_BIT * 8, false));
+    }
+
+    #[test]
+    fn a_finished_transfer_requests_the_serial_interrupt() {
+        let mut mmu = MMU::new();
+        mmu.capture_serial_output();
+        mmu.write_byte(0xFF01, b'A');
+        mmu.write_byte(0xFF02, 0x81);
+        mmu.step_serial(CLOCKS_PER_BIT * 8 - 1);
+        assert_eq!(mmu.read_byte(0xFF0F) & 0b0000_1000, 0);
+        mmu.step_serial(1);
+        assert_eq!(mmu.read_byte(0xFF0F) & 0b0000_1000, 0b0000_1000);
+        assert_eq!(mmu.get_serial_output(), b"A");
+    }
+
+    #[test]
+    fn output_is_only_kept_when_asked_for() {
+        let mut serial = Serial::new();
+        serial.write_sc(0x81);
+        assert!(serial.get_output().is_empty());
+    }
+
+    #[test]
+    fn an_external_byte_waits_for_a_transfer() {
+        let mut serial = Serial::new();
+        serial.connect(Box::new(Sender(Some(0x5A))));
+        assert!(!serial.step(CLOCKS_PER_BIT, false));
+        serial.write_sc(0x80);
+        assert!(serial.step(CLOCKS_PER_BIT, false));
+        assert_eq!(serial.read_sb(), 0x5A);
+    }
+
+    #[test]
+    fn a_slow_answer_holds_the_transfer_without_blocking() {
+        let mut serial = Serial::new();
+        serial.connect(Box::new(SlowPeer { polls: 2 }));
+        serial.write_sc(0x81);
+        assert!(serial.is_waiting_for_reply());
+        assert!(!serial.step(CLOCKS_PER_BIT * 8, false));
+        assert!(!serial.step(CLOCKS_PER_BIT * 8, false));
+        assert_eq!(serial.read_sc(false) & 0b1000_0000, 0b1000_0000);
+        // the time for all 8 bits went by already
+        assert!(serial.step(4, false));
+        assert!(!serial.is_waiting_for_reply());
+        assert_eq!(serial.read_sb(), 0x3C);
+    }
+}
@@ -0,0 +1,722 @@
+// Super Game Boy: the game talks to the SNES by bit-banging 16 byte packets
+// through P1, and the SNES colorises the picture and draws a border around it.
+
+use crate::savestate::{StateError, StateReader, StateWriter};
+
+pub const SGB_SCREEN_WIDTH: usize = 256;
+pub const SGB_SCREEN_HEIGHT: usize = 224;
+// where the 160x144 game picture sits inside the border
+pub const GAME_X: usize = 48;
+pub const GAME_Y: usize = 40;
+
+// one palette number for each 8x8 block of the game picture
+const ATTRIBUTE_WIDTH: usize = 20;
+const ATTRIBUTE_HEIGHT: usize = 18;
+const ATTRIBUTE_FILE_SIZE: usize = 90;
+const ATTRIBUTE_FILES: usize = 45;
+const SYSTEM_PALETTES: usize = 512;
+
+// CHR_TRN moves 128 4bpp SNES tiles at a time
+const BORDER_TILE_SIZE: usize = 32;
+const BORDER_TILES: usize = 256;
+// 32x32 map entries of 2 bytes, the palettes follow them in PCT_TRN
+const BORDER_MAP_SIZE: usize = 0x800;
+const BORDER_PALETTES: usize = 4;
+
+const PACKET_SIZE: usize = 16;
+// VRAM transfers read 4KB from what is on screen
+pub const TRANSFER_SIZE: usize = 0x1000;
+
+const PAL01: u8 = 0x00;
+const PAL23: u8 = 0x01;
+const PAL03: u8 = 0x02;
+const PAL12: u8 = 0x03;
+const ATTR_BLK: u8 = 0x04;
+const ATTR_LIN: u8 = 0x05;
+const ATTR_DIV: u8 = 0x06;
+const ATTR_CHR: u8 = 0x07;
+const PAL_SET: u8 = 0x0A;
+const PAL_TRN: u8 = 0x0B;
+const MLT_REQ: u8 = 0x11;
+const CHR_TRN: u8 = 0x13;
+const PCT_TRN: u8 = 0x14;
+const ATTR_TRN: u8 = 0x15;
+const ATTR_SET: u8 = 0x16;
+const MASK_EN: u8 = 0x17;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MaskMode {
+    Cancel,
+    // keep showing the last picture
+    Freeze,
+    Black,
+    // fill with color 0
+    Color0,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Transfer {
+    ChrLow,
+    ChrHigh,
+    Pct,
+    Pal,
+    Attr,
+}
+
+pub struct Sgb {
+    // packet receiver
+    receiving: bool,
+    ready_for_bit: bool,
+    bits_received: usize,
+    packet: [u8; PACKET_SIZE],
+    command: Vec<u8>,
+    packets_left: usize,
+    // what the packets set up
+    palettes: [[u16; 4]; 4],
+    system_palettes: Vec<[u16; 4]>,
+    attribute_map: [u8; ATTRIBUTE_WIDTH * ATTRIBUTE_HEIGHT],
+    attribute_files: Vec<u8>,
+    mask: MaskMode,
+    border_tiles: Vec<u8>,
+    border_map: Vec<u8>,
+    border_palettes: [[u16; 16]; BORDER_PALETTES],
+    pending_transfer: Option<Transfer>,
+    players: u8,
+    current_player: u8,
+}
+
+impl Sgb {
+    pub fn new() -> Sgb {
+        Sgb {
+            receiving: false,
+            ready_for_bit: false,
+            bits_received: 0,
+            packet: [0; PACKET_SIZE],
+            command: Vec::new(),
+            packets_left: 0,
+            // the SGB starts with shades of grey
+            palettes: [[0x7FFF, 0x56B5, 0x294A, 0x0000]; 4],
+            system_palettes: vec![[0; 4]; SYSTEM_PALETTES],
+            attribute_map: [0; ATTRIBUTE_WIDTH * ATTRIBUTE_HEIGHT],
+            attribute_files: vec![0; ATTRIBUTE_FILE_SIZE * ATTRIBUTE_FILES],
+            mask: MaskMode::Cancel,
+            border_tiles: vec![0; BORDER_TILE_SIZE * BORDER_TILES],
+            border_map: vec![0; BORDER_MAP_SIZE],
+            border_palettes: [[0; 16]; BORDER_PALETTES],
+            pending_transfer: None,
+            players: 1,
+            current_player: 0,
+        }
+    }
+
+    // Every write to P1 goes through here. Returns true when the picture
+    // needs to be redrawn.
+    pub fn write_p1(&mut self, previous: u8, value: u8) -> bool {
+        // MLT_REQ: each time P15 goes low on its own the next controller is
+        // selected. The reset pulse and the bits of a packet don't count.
+        let p15_falls = (previous & 0b0010_0000) != 0 && (value & 0b0011_0000) == 0b0001_0000;
+        if !self.receiving && p15_falls {
+            self.current_player = (self.current_player + 1) % self.players;
+        }
+
+        match value & 0b0011_0000 {
+            // both lines low: reset pulse, a packet starts
+            0b0000_0000 => {
+                self.receiving = true;
+                self.ready_for_bit = false;
+                self.bits_received = 0;
+                self.packet = [0; PACKET_SIZE];
+                false
+            }
+            // both lines high: between bits
+            0b0011_0000 => {
+                self.ready_for_bit = true;
+                false
+            }
+            // P14 low is a 0, P15 low is a 1
+            lines => {
+                if !self.receiving || !self.ready_for_bit {
+                    return false;
+                }
+                self.ready_for_bit = false;
+                if lines == 0b0001_0000 {
+                    self.packet[self.bits_received / 8] |= 1 << (self.bits_received % 8);
+                }
+                self.bits_received += 1;
+                if self.bits_received < PACKET_SIZE * 8 {
+                    return false;
+                }
+                // the stop bit that follows is ignored
+                self.receiving = false;
+                self.receive_packet()
+            }
+        }
+    }
+
+    fn receive_packet(&mut self) -> bool {
+        if self.packets_left == 0 {
+            self.command.clear();
+            self.packets_left = (self.packet[0] & 0b0000_0111) as usize;
+            // a length of 0 is taken as 1
+            if self.packets_left == 0 {
+                self.packets_left = 1;
+            }
+        }
+        self.command.extend_from_slice(&self.packet);
+        self.packets_left -= 1;
+        if self.packets_left > 0 {
+            return false;
+        }
+        let command = std::mem::take(&mut self.command);
+        self.run_command(&command)
+    }
+
+    fn run_command(&mut self, data: &[u8]) -> bool {
+        match data[0] >> 3 {
+            PAL01 => self.set_palette_pair(0, 1, data),
+            PAL23 => self.set_palette_pair(2, 3, data),
+            PAL03 => self.set_palette_pair(0, 3, data),
+            PAL12 => self.set_palette_pair(1, 2, data),
+            ATTR_BLK => self.attr_blk(data),
+            ATTR_LIN => self.attr_lin(data),
+            ATTR_DIV => self.attr_div(data),
+            ATTR_CHR => self.attr_chr(data),
+            PAL_SET => self.pal_set(data),
+            PAL_TRN => self.pending_transfer = Some(Transfer::Pal),
+            MLT_REQ => {
+                self.players = match data[1] & 0b11 {
+                    1 => 2,
+                    3 => 4,
+                    _ => 1,
+                };
+                self.current_player = 0;
+            }
+            CHR_TRN => {
+                self.pending_transfer = if (data[1] & 0b1) == 0 {
+                    Some(Transfer::ChrLow)
+                } else {
+                    Some(Transfer::ChrHigh)
+                };
+            }
+            PCT_TRN => self.pending_transfer = Some(Transfer::Pct),
+            ATTR_TRN => self.pending_transfer = Some(Transfer::Attr),
+            ATTR_SET => {
+                self.apply_attribute_file((data[1] & 0b0011_1111) as usize);
+                if (data[1] & 0b0100_0000) != 0 {
+                    self.mask = MaskMode::Cancel;
+                }
+            }
+            MASK_EN => {
+                self.mask = match data[1] & 0b11 {
+                    1 => MaskMode::Freeze,
+                    2 => MaskMode::Black,
+                    3 => MaskMode::Color0,
+                    _ => MaskMode::Cancel,
+                };
+            }
+            // sound, SNES program upload and the rest don't change the picture
+            _ => return false,
+        }
+        true
+    }
+
+    fn read_color(data: &[u8], offset: usize) -> u16 {
+        (data[offset] as u16) | ((data[offset + 1] as u16) << 8)
+    }
+
+    fn set_palette_pair(&mut self, first: usize, second: usize, data: &[u8]) {
+        // color 0 is shared by all four palettes
+        let color_0 = Sgb::read_color(data, 1);
+        for palette in self.palettes.iter_mut() {
+            palette[0] = color_0;
+        }
+        for color in 1..4 {
+            self.palettes[first][color] = Sgb::read_color(data, 1 + color * 2);
+            self.palettes[second][color] = Sgb::read_color(data, 7 + color * 2);
+        }
+    }
+
+    fn set_attribute(&mut self, x: usize, y: usize, palette: u8) {
+        if x < ATTRIBUTE_WIDTH && y < ATTRIBUTE_HEIGHT {
+            self.attribute_map[y * ATTRIBUTE_WIDTH + x] = palette & 0b11;
+        }
+    }
+
+    fn attr_blk(&mut self, data: &[u8]) {
+        let data_sets = (data[1] & 0b0001_1111) as usize;
+        for set in data[2..].chunks(6).take(data_sets) {
+            if set.len() < 6 {
+                break;
+            }
+            let control = set[0] & 0b111;
+            let inside = set[1] & 0b11;
+            let mut border = (set[1] >> 2) & 0b11;
+            let outside = (set[1] >> 4) & 0b11;
+            // with only inside or only outside set the border follows it
+            let change_border = match control {
+                0b001 => {
+                    border = inside;
+                    true
+                }
+                0b100 => {
+                    border = outside;
+                    true
+                }
+                _ => (control & 0b010) != 0,
+            };
+            let (x1, y1) = (set[2] as usize, set[3] as usize);
+            let (x2, y2) = (set[4] as usize, set[5] as usize);
+            for y in 0..ATTRIBUTE_HEIGHT {
+                for x in 0..ATTRIBUTE_WIDTH {
+                    let within = x >= x1 && x <= x2 && y >= y1 && y <= y2;
+                    let on_border = within && (x == x1 || x == x2 || y == y1 || y == y2);
+                    if on_border {
+                        if change_border {
+                            self.set_attribute(x, y, border);
+                        }
+                    } else if within {
+                        if (control & 0b001) != 0 {
+                            self.set_attribute(x, y, inside);
+                        }
+                    } else if (control & 0b100) != 0 {
+                        self.set_attribute(x, y, outside);
+                    }
+                }
+            }
+        }
+    }
+
+    fn attr_lin(&mut self, data: &[u8]) {
+        let data_sets = data[1] as usize;
+        for line in data[2..].iter().take(data_sets) {
+            let number = (line & 0b0001_1111) as usize;
+            let palette = (line >> 5) & 0b11;
+            if (line & 0b1000_0000) != 0 {
+                for x in 0..ATTRIBUTE_WIDTH {
+                    self.set_attribute(x, number, palette);
+                }
+            } else {
+                for y in 0..ATTRIBUTE_HEIGHT {
+                    self.set_attribute(number, y, palette);
+                }
+            }
+        }
+    }
+
+    fn attr_div(&mut self, data: &[u8]) {
+        let after = data[1] & 0b11;
+        let before = (data[1] >> 2) & 0b11;
+        let on_line = (data[1] >> 4) & 0b11;
+        let horizontal = (data[1] & 0b0100_0000) != 0;
+        let line = data[2] as usize;
+        for y in 0..ATTRIBUTE_HEIGHT {
+            for x in 0..ATTRIBUTE_WIDTH {
+                let position = if horizontal { y } else { x };
+                let palette = if position < line {
+                    before
+                } else if position == line {
+                    on_line
+                } else {
+                    after
+                };
+                self.set_attribute(x, y, palette);
+            }
+        }
+    }
+
+    fn attr_chr(&mut self, data: &[u8]) {
+        let mut x = data[1] as usize;
+        let mut y = data[2] as usize;
+        let count = ((data[3] as usize) | ((data[4] as usize) << 8)).min(360);
+        let top_to_bottom = data[5] != 0;
+        for i in 0..count {
+            let byte = match data.get(6 + i / 4) {
+                Some(byte) => *byte,
+                None => break,
+            };
+            let palette = (byte >> (6 - (i % 4) * 2)) & 0b11;
+            self.set_attribute(x, y, palette);
+            if top_to_bottom {
+                y += 1;
+                if y >= ATTRIBUTE_HEIGHT {
+                    y = 0;
+                    x += 1;
+                }
+            } else {
+                x += 1;
+                if x >= ATTRIBUTE_WIDTH {
+                    x = 0;
+                    y += 1;
+                }
+            }
+        }
+    }
+
+    fn pal_set(&mut self, data: &[u8]) {
+        for palette in 0..4 {
+            let number = (Sgb::read_color(data, 1 + palette * 2) & 0x01FF) as usize;
+            self.palettes[palette] = self.system_palettes[number];
+        }
+        let attribute_file = data[9];
+        if (attribute_file & 0b1000_0000) != 0 {
+            self.apply_attribute_file((attribute_file & 0b0011_1111) as usize);
+        }
+        if (attribute_file & 0b0100_0000) != 0 {
+            self.mask = MaskMode::Cancel;
+        }
+    }
+
+    fn apply_attribute_file(&mut self, file: usize) {
+        if file >= ATTRIBUTE_FILES {
+            return;
+        }
+        let start = file * ATTRIBUTE_FILE_SIZE;
+        for i in 0..(ATTRIBUTE_WIDTH * ATTRIBUTE_HEIGHT) {
+            let byte = self.attribute_files[start + i / 4];
+            self.attribute_map[i] = (byte >> (6 - (i % 4) * 2)) & 0b11;
+        }
+    }
+
+    // VRAM transfers happen on the frame after the command, once the game has
+    // put the data on screen
+    pub fn get_pending_transfer(&self) -> Option<Transfer> {
+        self.pending_transfer
+    }
+
+    pub fn complete_transfer(&mut self, data: &[u8]) {
+        let transfer = match self.pending_transfer.take() {
+            Some(transfer) => transfer,
+            None => return,
+        };
+        match transfer {
+            Transfer::ChrLow | Transfer::ChrHigh => {
+                let half = BORDER_TILE_SIZE * BORDER_TILES / 2;
+                let start = if transfer == Transfer::ChrLow {
+                    0
+                } else {
+                    half
+                };
+                self.border_tiles[start..start + half].copy_from_slice(&data[..half]);
+            }
+            Transfer::Pct => {
+                self.border_map.copy_from_slice(&data[..BORDER_MAP_SIZE]);
+                for (palette, colors) in self.border_palettes.iter_mut().enumerate() {
+                    for (color, value) in colors.iter_mut().enumerate() {
+                        *value =
+                            Sgb::read_color(data, BORDER_MAP_SIZE + (palette * 16 + color) * 2);
+                    }
+                }
+            }
+            Transfer::Pal => {
+                for (i, palette) in self.system_palettes.iter_mut().enumerate() {
+                    for (color, value) in palette.iter_mut().enumerate() {
+                        *value = Sgb::read_color(data, (i * 4 + color) * 2);
+                    }
+                }
+            }
+            Transfer::Attr => {
+                let size = ATTRIBUTE_FILE_SIZE * ATTRIBUTE_FILES;
+                self.attribute_files.copy_from_slice(&data[..size]);
+            }
+        }
+    }
+
+    // Reading P1 with both lines high tells which controller is selected
+    pub fn get_joypad_id(&self) -> Option<u8> {
+        if self.players > 1 {
+            Some(0x0F - self.current_player)
+        } else {
+            None
+        }
+    }
+
+    pub fn get_mask(&self) -> MaskMode {
+        self.mask
+    }
+
+    // 15 bit color of a game pixel at (x, y) showing DMG shade `shade`
+    pub fn get_game_color(&self, x: usize, y: usize, shade: u8) -> u16 {
+        match self.mask {
+            MaskMode::Black => 0x0000,
+            MaskMode::Color0 => self.palettes[0][0],
+            _ => {
+                let palette = self.attribute_map[(y / 8) * ATTRIBUTE_WIDTH + (x / 8)] as usize;
+                match shade & 0b11 {
+                    // color 0 of palette 0 is the one used everywhere
+                    0 => self.palettes[0][0],
+                    shade => self.palettes[palette][shade as usize],
+                }
+            }
+        }
+    }
+
+    // None where the border is transparent
+    pub fn get_border_color(&self, x: usize, y: usize) -> Option<u16> {
+        let entry = ((y / 8) * 32 + (x / 8)) * 2;
+        let tile = self.border_map[entry] as usize;
+        let attributes = self.border_map[entry + 1];
+        let palette = (((attributes >> 2) & 0b111) as usize).saturating_sub(4) % BORDER_PALETTES;
+        let tile_x = if (attributes & 0b0100_0000) != 0 {
+            x % 8
+        } else {
+            7 - (x % 8)
+        };
+        let tile_y = if (attributes & 0b1000_0000) != 0 {
+            7 - (y % 8)
+        } else {
+            y % 8
+        };
+
+        // SNES 4bpp: bitplanes 0 and 1 interleaved, then 2 and 3
+        let row = tile * BORDER_TILE_SIZE + tile_y * 2;
+        let planes = [
+            self.border_tiles[row],
+            self.border_tiles[row + 1],
+            self.border_tiles[row + 16],
+            self.border_tiles[row + 17],
+        ];
+        let mut color = 0;
+        for (bit, plane) in planes.iter().enumerate() {
+            color |= ((plane >> tile_x) & 0b1) << bit;
+        }
+        if color == 0 {
+            None
+        } else {
+            Some(self.border_palettes[palette][color as usize])
+        }
+    }
+
+    // what shows behind the game and through the border
+    pub fn get_backdrop_color(&self) -> u16 {
+        self.palettes[0][0]
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.receiving);
+        state.write_bool(self.ready_for_bit);
+        state.write_usize(self.bits_received);
+        state.write_bytes(&self.packet);
+        state.write_vec(&self.command);
+        state.write_usize(self.packets_left);
+        for palette in self.palettes.iter() {
+            state.write_u16s(palette);
+        }
+        for palette in self.system_palettes.iter() {
+            state.write_u16s(palette);
+        }
+        state.write_bytes(&self.attribute_map);
+        state.write_bytes(&self.attribute_files);
+        // numbered like MASK_EN does
+        state.write_u8(match self.mask {
+            MaskMode::Cancel => 0,
+            MaskMode::Freeze => 1,
+            MaskMode::Black => 2,
+            MaskMode::Color0 => 3,
+        });
+        state.write_bytes(&self.border_tiles);
+        state.write_bytes(&self.border_map);
+        for palette in self.border_palettes.iter() {
+            state.write_u16s(palette);
+        }
+        state.write_u8(match self.pending_transfer {
+            None => 0,
+            Some(Transfer::ChrLow) => 1,
+            Some(Transfer::ChrHigh) => 2,
+            Some(Transfer::Pct) => 3,
+            Some(Transfer::Pal) => 4,
+            Some(Transfer::Attr) => 5,
+        });
+        state.write_u8(self.players);
+        state.write_u8(self.current_player);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.receiving = state.read_bool()?;
+        self.ready_for_bit = state.read_bool()?;
+        self.bits_received = state.read_usize()?;
+        state.read_bytes(&mut self.packet)?;
+        self.command = state.read_vec()?;
+        self.packets_left = state.read_usize()?;
+        for palette in self.palettes.iter_mut() {
+            state.read_u16s(palette)?;
+        }
+        for palette in self.system_palettes.iter_mut() {
+            state.read_u16s(palette)?;
+        }
+        state.read_bytes(&mut self.attribute_map)?;
+        state.read_bytes(&mut self.attribute_files)?;
+        self.mask = match state.read_u8()? {
+            0 => MaskMode::Cancel,
+            1 => MaskMode::Freeze,
+            2 => MaskMode::Black,
+            3 => MaskMode::Color0,
+            _ => return Err(StateError::Corrupt),
+        };
+        state.read_bytes(&mut self.border_tiles)?;
+        state.read_bytes(&mut self.border_map)?;
+        for palette in self.border_palettes.iter_mut() {
+            state.read_u16s(palette)?;
+        }
+        self.pending_transfer = match state.read_u8()? {
+            0 => None,
+            1 => Some(Transfer::ChrLow),
+            2 => Some(Transfer::ChrHigh),
+            3 => Some(Transfer::Pct),
+            4 => Some(Transfer::Pal),
+            5 => Some(Transfer::Attr),
+            _ => return Err(StateError::Corrupt),
+        };
+        self.players = state.read_u8()?;
+        self.current_player = state.read_u8()?;
+        // they index the packet and pick the controller
+        if self.bits_received > PACKET_SIZE * 8 || self.current_player >= self.players {
+            return Err(StateError::Corrupt);
+        }
+        Ok(())
+    }
+}
+
+impl Default for Sgb {
+    fn default() -> Sgb {
+        Sgb::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Writes P1 the way games do, remembering what was there before
+    fn write(sgb: &mut Sgb, p1: &mut u8, value: u8) -> bool {
+        let redraw = sgb.write_p1(*p1, value);
+        *p1 = value;
+        redraw
+    }
+
+    // Reset pulse, 128 bits low bit first and the stop bit
+    fn send(sgb: &mut Sgb, p1: &mut u8, packet: &[u8; PACKET_SIZE]) -> bool {
+        write(sgb, p1, 0x00);
+        write(sgb, p1, 0x30);
+        let mut redraw = false;
+        for bit in 0..PACKET_SIZE * 8 {
+            let one = (packet[bit / 8] >> (bit % 8)) & 0b1 != 0;
+            redraw |= write(sgb, p1, if one { 0x10 } else { 0x20 });
+            write(sgb, p1, 0x30);
+        }
+        write(sgb, p1, 0x20);
+        write(sgb, p1, 0x30);
+        redraw
+    }
+
+    fn packet(bytes: &[u8]) -> [u8; PACKET_SIZE] {
+        let mut packet = [0; PACKET_SIZE];
+        packet[..bytes.len()].copy_from_slice(bytes);
+        packet
+    }
+
+    #[test]
+    fn assembles_pal01_from_the_p1_pulses() {
+        let mut sgb = Sgb::new();
+        let mut p1 = 0x30;
+        let colors = [0x7FFF, 0x001F, 0x03E0, 0x7C00, 0x1234, 0x5678, 0x0ABC];
+        let mut bytes = vec![(PAL01 << 3) | 1];
+        for color in colors.iter() {
+            bytes.extend_from_slice(&u16::to_le_bytes(*color));
+        }
+        assert!(send(&mut sgb, &mut p1, &packet(&bytes)));
+        assert_eq!(sgb.palettes[0], [0x7FFF, 0x001F, 0x03E0, 0x7C00]);
+        assert_eq!(sgb.palettes[1], [0x7FFF, 0x1234, 0x5678, 0x0ABC]);
+        // color 0 is shared
+        assert_eq!(sgb.palettes[3][0], 0x7FFF);
+    }
+
+    #[test]
+    fn runs_commands_that_take_several_packets() {
+        let mut sgb = Sgb::new();
+        let mut p1 = 0x30;
+        // three ATTR_BLK sets, the last one goes over into the second packet
+        let first = packet(&[
+            (ATTR_BLK << 3) | 2,
+            3,
+            0b001,
+            0b01,
+            0,
+            0,
+            1,
+            1,
+            0b001,
+            0b11,
+            5,
+            5,
+            6,
+            6,
+            0b001,
+            0b10,
+        ]);
+        let second = packet(&[10, 10, 10, 10]);
+        assert!(!send(&mut sgb, &mut p1, &first));
+        assert!(send(&mut sgb, &mut p1, &second));
+        let attribute = |x: usize, y: usize| sgb.attribute_map[y * ATTRIBUTE_WIDTH + x];
+        assert_eq!(
+            (attribute(0, 0), attribute(1, 1), attribute(2, 2)),
+            (1, 1, 0)
+        );
+        assert_eq!((attribute(5, 6), attribute(7, 7)), (3, 0));
+        assert_eq!((attribute(10, 10), attribute(11, 10)), (2, 0));
+    }
+
+    #[test]
+    fn mask_en_masks_the_picture() {
+        let mut sgb = Sgb::new();
+        let mut p1 = 0x30;
+        send(&mut sgb, &mut p1, &packet(&[(MASK_EN << 3) | 1, 1]));
+        assert_eq!(sgb.get_mask(), MaskMode::Freeze);
+        send(&mut sgb, &mut p1, &packet(&[(MASK_EN << 3) | 1, 2]));
+        assert_eq!(sgb.get_game_color(0, 0, 3), 0x0000);
+        send(&mut sgb, &mut p1, &packet(&[(MASK_EN << 3) | 1, 0]));
+        assert_eq!(sgb.get_mask(), MaskMode::Cancel);
+    }
+
+    #[test]
+    fn mlt_req_counts_controller_reads_but_not_packets() {
+        let mut sgb = Sgb::new();
+        let mut p1 = 0x30;
+        assert_eq!(sgb.get_joypad_id(), None);
+        send(&mut sgb, &mut p1, &packet(&[(MLT_REQ << 3) | 1, 1]));
+        assert_eq!(sgb.get_joypad_id(), Some(0x0F));
+
+        write(&mut sgb, &mut p1, 0x10);
+        write(&mut sgb, &mut p1, 0x30);
+        assert_eq!(sgb.get_joypad_id(), Some(0x0E));
+        // another packet, reset pulse and all, leaves the controller alone
+        send(&mut sgb, &mut p1, &packet(&[(MASK_EN << 3) | 1, 0]));
+        assert_eq!(sgb.get_joypad_id(), Some(0x0E));
+        write(&mut sgb, &mut p1, 0x10);
+        write(&mut sgb, &mut p1, 0x30);
+        assert_eq!(sgb.get_joypad_id(), Some(0x0F));
+    }
+
+    #[test]
+    fn draws_the_border_sent_over_vram() {
+        let mut sgb = Sgb::new();
+        let mut p1 = 0x30;
+        send(&mut sgb, &mut p1, &packet(&[(CHR_TRN << 3) | 1, 0]));
+        assert_eq!(sgb.get_pending_transfer(), Some(Transfer::ChrLow));
+        let mut tiles = vec![0; TRANSFER_SIZE];
+        // the leftmost pixel of tile 0's first row is color 1
+        tiles[0] = 0b1000_0000;
+        sgb.complete_transfer(&tiles);
+
+        send(&mut sgb, &mut p1, &packet(&[(PCT_TRN << 3) | 1]));
+        assert_eq!(sgb.get_pending_transfer(), Some(Transfer::Pct));
+        let mut map = vec![0; TRANSFER_SIZE];
+        // tile 0 with SNES palette 4, the first border palette
+        map[1] = 4 << 2;
+        map[BORDER_MAP_SIZE + 2..BORDER_MAP_SIZE + 4].copy_from_slice(&0x001Fu16.to_le_bytes());
+        sgb.complete_transfer(&map);
+
+        assert_eq!(sgb.get_pending_transfer(), None);
+        assert_eq!(sgb.get_border_color(0, 0), Some(0x001F));
+        assert_eq!(sgb.get_border_color(1, 0), None);
+    }
+}
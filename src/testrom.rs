@@ -0,0 +1,236 @@
+use crate::cpu::Reg8;
+use crate::error::EmuError;
+use crate::gameboy::GameBoy;
+use crate::mmu::Model;
+use crate::pacer::CYCLES_PER_FRAME;
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+// Runs test ROMs without a window and tells whether they passed:
+// - blargg's print "Passed" or "Failed" through the serial port
+// - mooneye's run LD B,B when they are done, with B, C, D, E, H and L set
+//   to the Fibonacci numbers 3, 5, 8, 13, 21, 34 if they passed
+
+// two minutes of emulated time, the slowest blargg ROMs need about one
+pub const DEFAULT_TIMEOUT_CYCLES: usize = CYCLES_PER_FRAME * 60 * 120;
+
+// LD B,B, the mooneye debug breakpoint
+const MOONEYE_BREAKPOINT: u8 = 0x40;
+const MOONEYE_PASSED: [(Reg8, u8); 6] = [
+    (Reg8::B, 3),
+    (Reg8::C, 5),
+    (Reg8::D, 8),
+    (Reg8::E, 13),
+    (Reg8::H, 21),
+    (Reg8::L, 34),
+];
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Outcome {
+    Passed,
+    // what the ROM said about it
+    Failed(String),
+    Timeout,
+    Error(EmuError),
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Outcome::Passed => write!(f, "PASS"),
+            Outcome::Failed(reason) => write!(f, "FAIL  {}", reason),
+            Outcome::Timeout => write!(f, "TIME"),
+            Outcome::Error(error) => write!(f, "ERR   {}", error),
+        }
+    }
+}
+
+pub fn run_test_rom(rom: &[u8], timeout_cycles: usize) -> Outcome {
+    let mut gameboy = match GameBoy::new(Model::Dmg, rom, None, None) {
+        Ok(gameboy) => gameboy,
+        Err(error) => return Outcome::Error(error),
+    };
+    gameboy.get_mmu_mut().capture_serial_output();
+    let mut cycles = 0;
+    let mut serial_length = 0;
+    while cycles < timeout_cycles {
+        cycles += match gameboy.step_instruction() {
+            Ok(clocks) => clocks,
+            Err(error) => return Outcome::Error(error),
+        };
+
+        // what actually ran, PC can sit on LD B,B while an interrupt is taken
+        if gameboy.get_cpu().get_last_opcode() == Some(MOONEYE_BREAKPOINT) {
+            return mooneye_outcome(&gameboy);
+        }
+        // only look at the text again when something new came in
+        let output = gameboy.get_mmu().get_serial_output();
+        if output.len() != serial_length {
+            serial_length = output.len();
+            let text = String::from_utf8_lossy(output);
+            if text.contains("Passed") {
+                return Outcome::Passed;
+            }
+            // the test number or count comes after, wait for the whole line
+            if let Some(at) = text.find("Failed") {
+                if text[at..].contains('\n') {
+                    return Outcome::Failed(text.split_whitespace().collect::<Vec<_>>().join(" "));
+                }
+            }
+        }
+    }
+    Outcome::Timeout
+}
+
+fn mooneye_outcome(gameboy: &GameBoy) -> Outcome {
+    let cpu = gameboy.get_cpu();
+    if MOONEYE_PASSED
+        .iter()
+        .all(|&(register, value)| cpu.get_register(register) == value)
+    {
+        return Outcome::Passed;
+    }
+    let registers: Vec<String> = MOONEYE_PASSED
+        .iter()
+        .map(|&(register, _)| format!("{:?}={}", register, cpu.get_register(register)))
+        .collect();
+    Outcome::Failed(registers.join(" "))
+}
+
+fn find_roms(dir: &Path, roms: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            find_roms(&path, roms)?;
+        } else if path
+            .extension()
+            .is_some_and(|extension| extension == "gb" || extension == "gbc")
+        {
+            roms.push(path);
+        }
+    }
+    Ok(())
+}
+
+// Runs every .gb and .gbc under `dir`, sub folders included. The names are
+// the paths from `dir`.
+pub fn run_test_rom_dir(dir: &Path, timeout_cycles: usize) -> io::Result<Vec<(String, Outcome)>> {
+    let mut roms = Vec::new();
+    find_roms(dir, &mut roms)?;
+    roms.sort();
+
+    let mut results = Vec::new();
+    for path in roms.iter() {
+        let rom = fs::read(path)?;
+        let name = path.strip_prefix(dir).unwrap_or(path).display().to_string();
+        results.push((name, run_test_rom(&rom, timeout_cycles)));
+    }
+    Ok(results)
+}
+
+// One line per ROM and a count at the end
+pub fn format_matrix(results: &[(String, Outcome)]) -> String {
+    let width = results
+        .iter()
+        .map(|(name, _)| name.len())
+        .max()
+        .unwrap_or(0);
+    let mut matrix = String::new();
+    for (name, outcome) in results.iter() {
+        matrix.push_str(&format!("{:<width$}  {}\n", name, outcome, width = width));
+    }
+    let passed = results
+        .iter()
+        .filter(|(_, outcome)| *outcome == Outcome::Passed)
+        .count();
+    matrix.push_str(&format!("{}/{} passed\n", passed, results.len()));
+    matrix
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // `code` at the entry point, `handler` at the timer interrupt vector
+    fn rom(code: &[u8], handler: &[u8]) -> Vec<u8> {
+        let mut rom = vec![0; 0x8000];
+        rom[0x50..0x50 + handler.len()].copy_from_slice(handler);
+        rom[0x100..0x100 + code.len()].copy_from_slice(code);
+        rom
+    }
+
+    // Sends `text` over the serial port and spins
+    fn serial_rom(text: &str) -> Vec<u8> {
+        let mut code = Vec::new();
+        for byte in text.bytes() {
+            code.extend_from_slice(&[
+                0x3E, byte, // LD A,byte
+                0xE0, 0x01, // LDH ($01),A
+                0x3E, 0x81, // LD A,$81
+                0xE0, 0x02, // LDH ($02),A
+            ]);
+        }
+        code.extend_from_slice(&[0x18, 0xFE]); // JR -2
+        rom(&code, &[])
+    }
+
+    const FIBONACCI: [u8; 12] = [
+        0x06, 3, // LD B,3
+        0x0E, 5, // LD C,5
+        0x16, 8, // LD D,8
+        0x1E, 13, // LD E,13
+        0x26, 21, // LD H,21
+        0x2E, 34, // LD L,34
+    ];
+
+    #[test]
+    fn passed_and_failed_come_from_the_serial_output() {
+        let outcome = run_test_rom(&serial_rom("cpu_instrs\n\nPassed\n"), 100_000);
+        assert_eq!(outcome, Outcome::Passed);
+        let outcome = run_test_rom(&serial_rom("01-special\n\nFailed #2\n"), 100_000);
+        assert_eq!(outcome, Outcome::Failed("01-special Failed #2".to_string()));
+    }
+
+    #[test]
+    fn ld_b_b_passes_with_the_fibonacci_registers() {
+        let mut code = FIBONACCI.to_vec();
+        code.extend_from_slice(&[0x40, 0x18, 0xFE]); // LD B,B; JR -2
+        assert_eq!(run_test_rom(&rom(&code, &[]), 100_000), Outcome::Passed);
+
+        code[1] = 4;
+        assert_eq!(
+            run_test_rom(&rom(&code, &[]), 100_000),
+            Outcome::Failed("B=4 C=5 D=8 E=13 H=21 L=34".to_string())
+        );
+    }
+
+    #[test]
+    fn ld_b_b_only_counts_once_it_ran() {
+        // the timer interrupt is taken with PC on LD B,B, its handler sets
+        // the registers and returns to it
+        let code = [
+            0x3E, 0x04, // LD A,$04
+            0xE0, 0xFF, // LDH ($FF),A
+            0xE0, 0x0F, // LDH ($0F),A
+            0xFB, // EI
+            0x00, // NOP
+            0x40, // LD B,B
+            0x18, 0xFE, // JR -2
+        ];
+        let mut handler = FIBONACCI.to_vec();
+        handler.push(0xD9); // RETI
+        assert_eq!(
+            run_test_rom(&rom(&code, &handler), 100_000),
+            Outcome::Passed
+        );
+    }
+
+    #[test]
+    fn a_rom_that_never_says_times_out() {
+        let rom = rom(&[0x18, 0xFE], &[]);
+        assert_eq!(run_test_rom(&rom, 10_000), Outcome::Timeout);
+    }
+}
@@ -0,0 +1,205 @@
+use crate::savestate::{StateError, StateReader, StateWriter};
+
+// $FF04 - DIV, $FF05 - TIMA, $FF06 - TMA, $FF07 - TAC
+// DIV is the upper byte of a 16 bit counter that goes up every clock. TIMA
+// goes up whenever the counter bit picked by TAC goes from 1 to 0.
+
+// clocks between the overflow and TIMA being reloaded from TMA
+const RELOAD_DELAY: u8 = 4;
+
+pub struct Timer {
+    counter: u16,
+    tima: u8,
+    tma: u8,
+    tac: u8,
+    // TIMA reads 0 for a moment after it overflows
+    reload_clocks: u8,
+}
+
+impl Timer {
+    pub fn new() -> Timer {
+        Timer {
+            counter: 0,
+            tima: 0,
+            tma: 0,
+            tac: 0,
+            reload_clocks: 0,
+        }
+    }
+
+    pub fn read(&self, address: u16) -> u8 {
+        match address {
+            0xFF04 => (self.counter >> 8) as u8,
+            0xFF05 => self.tima,
+            0xFF06 => self.tma,
+            _ => 0b1111_1000 | self.tac,
+        }
+    }
+
+    pub fn write(&mut self, address: u16, value: u8) {
+        // changing the counter or TAC can make the selected bit fall too
+        let was_high = self.timer_bit();
+        match address {
+            0xFF04 => self.counter = 0,
+            0xFF05 => {
+                self.tima = value;
+                // writing during the reload delay cancels the reload
+                self.reload_clocks = 0;
+            }
+            0xFF06 => self.tma = value,
+            _ => self.tac = value & 0b0000_0111,
+        }
+        if was_high && !self.timer_bit() {
+            self.increment_tima();
+        }
+    }
+
+    fn timer_bit(&self) -> bool {
+        let bit = match self.tac & 0b11 {
+            0b00 => 9, // 4096 Hz
+            0b01 => 3, // 262144 Hz
+            0b10 => 5, // 65536 Hz
+            _ => 7,    // 16384 Hz
+        };
+        (self.tac & 0b100) != 0 && (self.counter >> bit) & 0b1 != 0
+    }
+
+    fn increment_tima(&mut self) {
+        let (tima, overflow) = self.tima.overflowing_add(1);
+        self.tima = tima;
+        if overflow {
+            self.reload_clocks = RELOAD_DELAY;
+        }
+    }
+
+    // Returns true when TIMA was reloaded, which requests the timer
+    // interrupt
+    pub fn step(&mut self, cpu_clocks_passed: usize) -> bool {
+        let mut interrupt = false;
+        for _ in 0..cpu_clocks_passed {
+            if self.reload_clocks > 0 {
+                self.reload_clocks -= 1;
+                if self.reload_clocks == 0 {
+                    self.tima = self.tma;
+                    interrupt = true;
+                }
+            }
+            let was_high = self.timer_bit();
+            self.counter = self.counter.wrapping_add(1);
+            if was_high && !self.timer_bit() {
+                self.increment_tima();
+            }
+        }
+        interrupt
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_u16(self.counter);
+        state.write_u8(self.tima);
+        state.write_u8(self.tma);
+        state.write_u8(self.tac);
+        state.write_u8(self.reload_clocks);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.counter = state.read_u16()?;
+        self.tima = state.read_u8()?;
+        self.tma = state.read_u8()?;
+        self.tac = state.read_u8()?;
+        self.reload_clocks = state.read_u8()?;
+        Ok(())
+    }
+}
+
+impl Default for Timer {
+    fn default() -> Timer {
+        Timer::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // TIMA going up every 16 clocks, on bit 3 of the counter
+    fn fast_timer() -> Timer {
+        let mut timer = Timer::new();
+        timer.write(0xFF07, 0b101);
+        timer
+    }
+
+    #[test]
+    fn tima_goes_up_when_the_picked_counter_bit_falls() {
+        let mut timer = fast_timer();
+        timer.step(15);
+        assert_eq!(timer.read(0xFF05), 0);
+        timer.step(1);
+        assert_eq!(timer.read(0xFF05), 1);
+        timer.step(16 * 3);
+        assert_eq!(timer.read(0xFF05), 4);
+        // DIV is the top byte of the same counter
+        assert_eq!(timer.read(0xFF04), 0);
+        timer.step(256 - 64);
+        assert_eq!(timer.read(0xFF04), 1);
+    }
+
+    #[test]
+    fn tima_is_reloaded_from_tma_one_m_cycle_after_it_overflows() {
+        let mut timer = fast_timer();
+        timer.write(0xFF05, 0xFF);
+        timer.write(0xFF06, 0x10);
+        assert!(!timer.step(16));
+        // TIMA reads 0 until the reload
+        assert_eq!(timer.read(0xFF05), 0);
+        assert!(!timer.step(RELOAD_DELAY as usize - 1));
+        assert_eq!(timer.read(0xFF05), 0);
+        assert!(timer.step(1));
+        assert_eq!(timer.read(0xFF05), 0x10);
+    }
+
+    #[test]
+    fn tma_written_during_the_reload_delay_is_the_one_loaded() {
+        let mut timer = fast_timer();
+        timer.write(0xFF05, 0xFF);
+        timer.write(0xFF06, 0x10);
+        timer.step(16);
+        timer.write(0xFF06, 0x42);
+        assert!(timer.step(RELOAD_DELAY as usize));
+        assert_eq!(timer.read(0xFF05), 0x42);
+    }
+
+    #[test]
+    fn writing_tima_during_the_reload_delay_cancels_it() {
+        let mut timer = fast_timer();
+        timer.write(0xFF05, 0xFF);
+        timer.write(0xFF06, 0x10);
+        timer.step(16);
+        timer.write(0xFF05, 0x80);
+        assert!(!timer.step(RELOAD_DELAY as usize));
+        assert_eq!(timer.read(0xFF05), 0x80);
+    }
+
+    #[test]
+    fn writes_that_make_the_picked_bit_fall_bump_tima() {
+        // bit 3 of the counter is high after 8 clocks
+        let mut timer = fast_timer();
+        timer.step(8);
+        timer.write(0xFF04, 0x12);
+        assert_eq!(timer.read(0xFF04), 0);
+        assert_eq!(timer.read(0xFF05), 1);
+
+        // turning the timer off
+        timer.step(8);
+        timer.write(0xFF07, 0b001);
+        assert_eq!(timer.read(0xFF05), 2);
+
+        // switching to a bit that is low, bit 9 for 4096 Hz
+        timer.write(0xFF07, 0b101);
+        timer.write(0xFF07, 0b100);
+        assert_eq!(timer.read(0xFF05), 3);
+
+        // and nothing when the bit was low already
+        timer.write(0xFF04, 0);
+        assert_eq!(timer.read(0xFF05), 3);
+    }
+}
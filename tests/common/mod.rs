@@ -0,0 +1,34 @@
+// Shared by the integration tests that run suites too big to ship with the
+// crate. A handful of cases is checked in under tests/fixtures and always
+// runs, the whole suite runs when it is found.
+
+use std::env;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+// tests/fixtures/<name>
+pub fn fixtures_dir(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests")
+        .join("fixtures")
+        .join(name)
+}
+
+// The folder `var` points at, or `default`. When it isn't there the
+// `_required` copy of a test fails and the plain one says it skipped.
+pub fn suite_dir(var: &str, default: &str, what: &str, required: bool) -> Option<PathBuf> {
+    let dir = PathBuf::from(env::var(var).unwrap_or_else(|_| default.to_string()));
+    if dir.is_dir() {
+        return Some(dir);
+    }
+    assert!(!required, "no {} in {}", what, dir.display());
+    // straight to stderr, the test harness swallows println! and eprintln!
+    let _ = writeln!(
+        io::stderr(),
+        "skipped: no {} in {}, point {} at them",
+        what,
+        dir.display(),
+        var
+    );
+    None
+}
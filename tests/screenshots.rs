@@ -0,0 +1,107 @@
+// Runs every ROM under SCREENSHOTS (defaults to tests/screenshots) that has
+// a reference picture next to it, named like the ROM (dmg-acid2.gb and
+// dmg-acid2.png), and checks the screen matches it pixel for pixel. .gbc
+// ROMs run on a CGB. Without the folder this says it skipped and passes,
+// `cargo test -- --ignored` runs a copy that fails instead. A ROM drawing a
+// single tile and its picture are checked in under tests/fixtures/screenshots.
+//
+// The ROMs stop at LD B,B like the acid2 ones do, or after SCREENSHOT_FRAMES
+// frames (defaults to 120). The pictures of what differed go to
+// target/tmp.
+
+mod common;
+
+use gbrustemu::gameboy::GameBoy;
+use gbrustemu::mmu::Model;
+use gbrustemu::screenshot::{capture, compare_with_reference, run_until_done};
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const DEFAULT_SCREENSHOTS_DIR: &str = "tests/screenshots";
+const DEFAULT_FRAMES: usize = 120;
+
+fn check(rom_path: &Path, reference: &Path, frames: usize) -> Result<(), String> {
+    let rom = fs::read(rom_path).map_err(|error| error.to_string())?;
+    let model = if rom_path
+        .extension()
+        .is_some_and(|extension| extension == "gbc")
+    {
+        Model::Cgb
+    } else {
+        Model::Dmg
+    };
+    let mut gameboy = GameBoy::new(model, &rom, None, None).map_err(|error| error.to_string())?;
+    run_until_done(&mut gameboy, frames).map_err(|error| error.to_string())?;
+
+    let stem = rom_path.file_stem().unwrap().to_string_lossy();
+    let diff = Path::new(env!("CARGO_TARGET_TMPDIR")).join(format!("{}-diff.png", stem));
+    compare_with_reference(
+        &capture(&gameboy),
+        gameboy.get_screen_width(),
+        gameboy.get_screen_height(),
+        reference,
+        &diff,
+    )
+    .map_err(|error| error.to_string())
+}
+
+fn run_screenshot_dir(dir: &Path) {
+    let frames = env::var("SCREENSHOT_FRAMES")
+        .ok()
+        .and_then(|frames| frames.parse().ok())
+        .unwrap_or(DEFAULT_FRAMES);
+    let mut roms: Vec<PathBuf> = fs::read_dir(dir)
+        .unwrap_or_else(|error| panic!("{}: {}", dir.display(), error))
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            path.extension()
+                .is_some_and(|extension| extension == "gb" || extension == "gbc")
+        })
+        .collect();
+    roms.sort();
+
+    let mut failed = Vec::new();
+    for rom in roms.iter() {
+        let reference = rom.with_extension("png");
+        if !reference.exists() {
+            continue;
+        }
+        match check(rom, &reference, frames) {
+            Ok(()) => println!("{}  PASS", rom.display()),
+            Err(error) => {
+                println!("{}  FAIL  {}", rom.display(), error);
+                failed.push(rom.display().to_string());
+            }
+        }
+    }
+    assert!(failed.is_empty(), "screens didn't match for {:?}", failed);
+}
+
+fn run_screenshot_tests(required: bool) {
+    let dir = common::suite_dir(
+        "SCREENSHOTS",
+        DEFAULT_SCREENSHOTS_DIR,
+        "screenshot tests",
+        required,
+    );
+    if let Some(dir) = dir {
+        run_screenshot_dir(&dir);
+    }
+}
+
+#[test]
+fn fixture_screens_match_references() {
+    run_screenshot_dir(&common::fixtures_dir("screenshots"));
+}
+
+#[test]
+fn screens_match_references() {
+    run_screenshot_tests(false);
+}
+
+#[test]
+#[ignore]
+fn screens_match_references_required() {
+    run_screenshot_tests(true);
+}
@@ -0,0 +1,232 @@
+// Runs the SM83 single step tests (https://github.com/SingleStepTests/sm83),
+// one JSON file per opcode with the registers and RAM before and after each
+// case, and the bus activity of every M-cycle in between.
+//
+// A few cases are checked in under tests/fixtures/sm83, for the whole suite
+// point SM83_TESTS at the folder with the .json files (defaults to
+// tests/sm83/v1). Without it that test says it skipped and passes, `cargo
+// test -- --ignored` runs a copy that fails instead.
+
+mod common;
+
+use gbrustemu::cpu::{BusAccess, Reg16, Reg8, CPU};
+use gbrustemu::mmu::MMU;
+use gbrustemu::ppu::PPU;
+use serde_json::Value;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const DEFAULT_TESTS_DIR: &str = "tests/sm83/v1";
+// failures printed per file, the rest are only counted
+const MAX_REPORTED: usize = 5;
+
+const REGISTERS: [(&str, Reg8); 8] = [
+    ("a", Reg8::A),
+    ("b", Reg8::B),
+    ("c", Reg8::C),
+    ("d", Reg8::D),
+    ("e", Reg8::E),
+    ("f", Reg8::F),
+    ("h", Reg8::H),
+    ("l", Reg8::L),
+];
+
+fn number(state: &Value, key: &str) -> u16 {
+    state[key]
+        .as_u64()
+        .unwrap_or_else(|| panic!("missing {}", key)) as u16
+}
+
+fn ram(state: &Value) -> Vec<(u16, u8)> {
+    state["ram"]
+        .as_array()
+        .expect("missing ram")
+        .iter()
+        .map(|entry| {
+            (
+                entry[0].as_u64().unwrap() as u16,
+                entry[1].as_u64().unwrap() as u8,
+            )
+        })
+        .collect()
+}
+
+// The reads and writes of the "cycles" list, leaving out the M-cycles where
+// the bus is idle
+fn bus_accesses(cycles: &Value) -> Vec<BusAccess> {
+    let mut accesses = Vec::new();
+    for cycle in cycles.as_array().expect("missing cycles") {
+        let (address, value, pins) = match (cycle[0].as_u64(), cycle[1].as_u64(), cycle[2].as_str())
+        {
+            (Some(address), Some(value), Some(pins)) => (address as u16, value as u8, pins),
+            _ => continue,
+        };
+        if pins.contains('r') {
+            accesses.push(BusAccess::Read(address, value));
+        } else if pins.contains('w') {
+            accesses.push(BusAccess::Write(address, value));
+        }
+    }
+    accesses
+}
+
+// The tests expect the opcode to be fetched already, at PC - 1, and end with
+// the fetch of the next one at PC. We fetch at PC and stop before the next
+// fetch, so PC is one behind and the first and last accesses are swapped.
+fn run_case(case: &Value) -> Result<(), String> {
+    let initial = &case["initial"];
+    let expected = &case["final"];
+
+    let mut mmu = MMU::new_flat();
+    let mut ppu = PPU::new();
+    let mut cpu = CPU::new();
+    for &(address, value) in ram(initial).iter() {
+        mmu.write_byte(address, value);
+    }
+    for &(key, register) in REGISTERS.iter() {
+        cpu.set_register(register, number(initial, key) as u8);
+    }
+    cpu.set_register_pair(Reg16::Sp, number(initial, "sp"));
+    cpu.set_register_pair(Reg16::Pc, number(initial, "pc").wrapping_sub(1));
+    cpu.set_ime(number(initial, "ime") != 0);
+    if initial.get("ie").is_some() {
+        mmu.write_byte(0xFFFF, number(initial, "ie") as u8);
+    }
+    let opcode = mmu.read_byte(cpu.get_register_pair(Reg16::Pc));
+
+    cpu.record_bus_accesses();
+    let clocks = cpu
+        .run_instruction(&mut mmu, &mut ppu)
+        .map_err(|error| error.to_string())?;
+
+    let mut errors = Vec::new();
+    for &(key, register) in REGISTERS.iter() {
+        let value = cpu.get_register(register);
+        if value != number(expected, key) as u8 {
+            errors.push(format!(
+                "{} is {:#04X}, expected {:#04X}",
+                key,
+                value,
+                number(expected, key)
+            ));
+        }
+    }
+    let sp = cpu.get_register_pair(Reg16::Sp);
+    if sp != number(expected, "sp") {
+        errors.push(format!(
+            "sp is {:#06X}, expected {:#06X}",
+            sp,
+            number(expected, "sp")
+        ));
+    }
+    let pc = cpu.get_register_pair(Reg16::Pc).wrapping_add(1);
+    if pc != number(expected, "pc") {
+        errors.push(format!(
+            "pc is {:#06X}, expected {:#06X}",
+            pc,
+            number(expected, "pc")
+        ));
+    }
+    // our EI only turns IME on once the next instruction starts
+    if opcode != 0xFB && cpu.get_ime() != (number(expected, "ime") != 0) {
+        errors.push(format!(
+            "ime is {}, expected {}",
+            cpu.get_ime(),
+            number(expected, "ime")
+        ));
+    }
+    for &(address, value) in ram(expected).iter() {
+        if mmu.read_byte(address) != value {
+            errors.push(format!(
+                "({:#06X}) is {:#04X}, expected {:#04X}",
+                address,
+                mmu.read_byte(address),
+                value
+            ));
+        }
+    }
+
+    let cycles = case["cycles"].as_array().map_or(0, |cycles| cycles.len());
+    if clocks != cycles * 4 {
+        errors.push(format!("took {} clocks, expected {}", clocks, cycles * 4));
+    }
+    let mut accesses = cpu.take_bus_accesses();
+    accesses.remove(0);
+    let mut expected_accesses = bus_accesses(&case["cycles"]);
+    expected_accesses.pop();
+    if accesses != expected_accesses {
+        errors.push(format!(
+            "bus {:?}, expected {:?}",
+            accesses, expected_accesses
+        ));
+    }
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors.join(", "))
+    }
+}
+
+fn run_file(path: &Path) -> (usize, usize) {
+    let text =
+        fs::read_to_string(path).unwrap_or_else(|error| panic!("{}: {}", path.display(), error));
+    let cases: Value =
+        serde_json::from_str(&text).unwrap_or_else(|error| panic!("{}: {}", path.display(), error));
+    let cases = cases.as_array().expect("a file is a list of cases");
+
+    let mut failed = 0;
+    for case in cases.iter() {
+        if let Err(error) = run_case(case) {
+            if failed < MAX_REPORTED {
+                println!("{} {}: {}", path.display(), case["name"], error);
+            }
+            failed += 1;
+        }
+    }
+    (cases.len(), failed)
+}
+
+fn run_sm83_dir(dir: &Path) {
+    let mut files: Vec<PathBuf> = fs::read_dir(dir)
+        .unwrap_or_else(|error| panic!("{}: {}", dir.display(), error))
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            path.extension()
+                .is_some_and(|extension| extension == "json")
+        })
+        .collect();
+    files.sort();
+
+    let mut total = 0;
+    let mut failed = 0;
+    for path in files.iter() {
+        let (cases, failures) = run_file(path);
+        total += cases;
+        failed += failures;
+    }
+    println!("{} of {} SM83 cases passed", total - failed, total);
+    assert_eq!(failed, 0, "{} of {} SM83 cases failed", failed, total);
+}
+
+fn run_sm83_tests(required: bool) {
+    if let Some(dir) = common::suite_dir("SM83_TESTS", DEFAULT_TESTS_DIR, "SM83 tests", required) {
+        run_sm83_dir(&dir);
+    }
+}
+
+#[test]
+fn sm83_fixtures() {
+    run_sm83_dir(&common::fixtures_dir("sm83"));
+}
+
+#[test]
+fn sm83_single_step_tests() {
+    run_sm83_tests(false);
+}
+
+#[test]
+#[ignore]
+fn sm83_single_step_tests_required() {
+    run_sm83_tests(true);
+}
@@ -0,0 +1,53 @@
+// Runs the blargg and mooneye test ROMs found under TEST_ROMS (defaults to
+// tests/roms) and prints how each one did. The ROMs aren't shipped with the
+// crate, without them this says it skipped and passes, `cargo test --
+// --ignored` runs a copy that fails instead. Two tiny ROMs under
+// tests/fixtures/roms pass the way each of them does, one printing "Passed"
+// and one with the Fibonacci registers at LD B,B.
+
+mod common;
+
+use gbrustemu::testrom::{format_matrix, run_test_rom_dir, Outcome, DEFAULT_TIMEOUT_CYCLES};
+use std::path::Path;
+
+const DEFAULT_ROMS_DIR: &str = "tests/roms";
+
+fn run_test_rom_dir_passes(dir: &Path) {
+    let results = run_test_rom_dir(dir, DEFAULT_TIMEOUT_CYCLES).unwrap();
+    assert!(!results.is_empty(), "no ROMs in {}", dir.display());
+    println!("{}", format_matrix(&results));
+
+    let failed: Vec<&String> = results
+        .iter()
+        .filter(|(_, outcome)| *outcome != Outcome::Passed)
+        .map(|(name, _)| name)
+        .collect();
+    assert!(
+        failed.is_empty(),
+        "{} test ROMs didn't pass: {:?}",
+        failed.len(),
+        failed
+    );
+}
+
+fn run_test_roms(required: bool) {
+    if let Some(dir) = common::suite_dir("TEST_ROMS", DEFAULT_ROMS_DIR, "test ROMs", required) {
+        run_test_rom_dir_passes(&dir);
+    }
+}
+
+#[test]
+fn fixture_roms_pass() {
+    run_test_rom_dir_passes(&common::fixtures_dir("roms"));
+}
+
+#[test]
+fn test_roms_pass() {
+    run_test_roms(false);
+}
+
+#[test]
+#[ignore]
+fn test_roms_pass_required() {
+    run_test_roms(true);
+}